@@ -0,0 +1,49 @@
+//! Sanity checks for email addresses.
+
+use anyhow::bail;
+
+/// Check that a bare email address (no display name or angle brackets) is plausible: exactly one
+/// '@', with something on either side of it, and nothing that would break the headers or command
+/// lines it gets put into. The mail server has the final say on whether it's actually valid.
+pub fn validate(addr: &str) -> anyhow::Result<()> {
+    let (local, domain) = match addr.split_once('@') {
+        Some(parts) => parts,
+        None => bail!("{:?} is not an email address: it has no '@'", addr),
+    };
+    if local.is_empty() {
+        bail!("{:?} is not an email address: nothing before the '@'", addr);
+    }
+    if domain.is_empty() {
+        bail!("{:?} is not an email address: nothing after the '@'", addr);
+    }
+    if domain.contains('@') {
+        bail!("{:?} is not an email address: it has more than one '@'", addr);
+    }
+    let bad_char = |c: &char| c.is_whitespace() || c.is_control() || "<>,".contains(*c);
+    if let Some(c) = addr.chars().find(bad_char) {
+        bail!("{:?} is not an email address: it contains {:?}", addr, c);
+    }
+    if addr.starts_with('-') {
+        // It gets passed as an argument to sendmail.
+        bail!("{:?} is not an email address: it starts with '-'", addr);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        for good in ["daylog@example.com", "daylog+replies@example.com", "a@b", "ü@exämple.com"] {
+            assert!(validate(good).is_ok(), "{:?}", good);
+        }
+        for bad in ["", "daylog", "@example.com", "daylog@", "a@b@example.com",
+            "day log@example.com", "<daylog@example.com>", "a@example.com,b@example.com",
+            "daylog@example.com\n", "-oQ/tmp@example.com"]
+        {
+            assert!(validate(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
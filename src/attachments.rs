@@ -0,0 +1,121 @@
+use anyhow::Context;
+use crate::mail::Attachment;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Longest file name, in bytes, that attachments are saved under.
+const MAX_FILENAME_BYTES: usize = 200;
+
+/// Save attachments to `<dir>/<username>/<date>/`, and return their paths relative to `dir`. If a
+/// file with the same name already exists there, a number is added to the name instead of
+/// overwriting it.
+pub fn save(dir: &Path, username: &str, date: &str, attachments: &[Attachment])
+    -> anyhow::Result<Vec<PathBuf>>
+{
+    let relative_dir = Path::new(&sanitize_filename(username)).join(sanitize_filename(date));
+    let full_dir = dir.join(&relative_dir);
+    std::fs::create_dir_all(&full_dir)
+        .with_context(|| format!("failed to create attachments directory {:?}", full_dir))?;
+
+    let mut saved = vec![];
+    for attachment in attachments {
+        let filename = create_unique(&full_dir, &sanitize_filename(&attachment.filename),
+            &attachment.data)
+            .with_context(|| format!("failed to save attachment {:?} in {:?}",
+                attachment.filename, full_dir))?;
+        saved.push(relative_dir.join(filename));
+    }
+    Ok(saved)
+}
+
+/// Write a new file in the directory with the given name, or if that's taken, with "-2", "-3", etc.
+/// added before the extension. Returns the name used.
+fn create_unique(dir: &Path, filename: &str, data: &[u8]) -> io::Result<String> {
+    let (stem, ext) = match filename.rfind('.') {
+        Some(idx) if idx > 0 => filename.split_at(idx),
+        _ => (filename, ""),
+    };
+    let mut n = 1;
+    loop {
+        let candidate = if n == 1 {
+            filename.to_owned()
+        } else {
+            format!("{}-{}{}", stem, n, ext)
+        };
+        match OpenOptions::new().write(true).create_new(true).open(dir.join(&candidate)) {
+            Ok(mut file) => {
+                file.write_all(data)?;
+                return Ok(candidate);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Make a file name from the sender safe to use as a single path component: drop any directories,
+/// control characters, and leading dots (so no hidden files, "." or ".."), and limit its length.
+fn sanitize_filename(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name = name.chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>();
+    let mut name = name.trim().trim_start_matches('.').trim_start().to_owned();
+    if name.len() > MAX_FILENAME_BYTES {
+        let mut end = MAX_FILENAME_BYTES;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    if name.is_empty() {
+        "attachment".to_owned()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!("photo.jpg", sanitize_filename("photo.jpg"));
+        assert_eq!("passwd", sanitize_filename("../../etc/passwd"));
+        assert_eq!("evil.exe", sanitize_filename("C:\\Windows\\evil.exe"));
+        assert_eq!("bashrc", sanitize_filename(".bashrc"));
+        assert_eq!("attachment", sanitize_filename(".."));
+        assert_eq!("attachment", sanitize_filename("dir/"));
+        assert_eq!("attachment", sanitize_filename(""));
+        assert_eq!("ab.txt", sanitize_filename("a\0b\n.txt"));
+        assert_eq!("café.jpg", sanitize_filename("café.jpg"));
+        // not splitting multi-byte characters
+        assert_eq!(MAX_FILENAME_BYTES - 2, sanitize_filename(&"€".repeat(100)).len());
+    }
+
+    #[test]
+    fn test_save() {
+        let dir = TempDir::new("attachments");
+        let attachment = |filename: &str, data: &[u8]| Attachment {
+            filename: filename.to_owned(),
+            data: data.to_vec(),
+        };
+
+        let saved = save(dir.path(), "alice", "2021-07-15", &[
+            attachment("photo.jpg", b"one"),
+            attachment("../photo.jpg", b"two"),
+            attachment("", b"three"),
+        ]).unwrap();
+        assert_eq!(vec![
+                PathBuf::from("alice/2021-07-15/photo.jpg"),
+                PathBuf::from("alice/2021-07-15/photo-2.jpg"),
+                PathBuf::from("alice/2021-07-15/attachment"),
+            ], saved);
+        assert_eq!(b"one", &std::fs::read(dir.path().join(&saved[0])).unwrap()[..]);
+        assert_eq!(b"two", &std::fs::read(dir.path().join(&saved[1])).unwrap()[..]);
+        assert_eq!(b"three", &std::fs::read(dir.path().join(&saved[2])).unwrap()[..]);
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{bail, Context};
+use crate::CheckArgs;
+use crate::config::{Config, IncomingMailConfig, SecretKeySource};
+use crate::db::Database;
+use crate::message_id::read_secret_key;
+use crate::time::DaylogTime;
+use nix::unistd::{access, AccessFlags};
+use std::path::Path;
+
+pub fn check(config: &Config, _args: CheckArgs) -> anyhow::Result<()> {
+    say!("config file {:?} loaded", config.path);
+
+    let results = [
+        ("database", check_database(&config.database_path, config.default_email_time)),
+        ("secret key", check_secret_key(&config.secret_key)),
+        ("incoming mail", check_incoming_mail(&config.incoming_mail)),
+        ("return address", check_email_address(&config.return_addr)),
+        ("PGP keys", check_pgp_keys(config)),
+    ];
+
+    let mut failures = 0;
+    for (name, result) in &results {
+        match result {
+            Ok(detail) => say!("[ ok ] {}: {}", name, detail),
+            Err(e) => {
+                println!("[FAIL] {}: {:#}", name, e);
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} of {} checks failed", failures, results.len());
+    }
+    Ok(())
+}
+
+fn check_database(path: &Path, default_email_time: Option<DaylogTime>) -> anyhow::Result<String> {
+    if !path.exists() {
+        // It gets created on first use, so that's okay as long as it can be.
+        let parent = match path.parent() {
+            Some(p) if p != Path::new("") => p,
+            _ => Path::new("."),
+        };
+        access(parent, AccessFlags::W_OK | AccessFlags::X_OK)
+            .with_context(|| format!("{:?} does not exist, and can't be created in {:?}",
+                path, parent))?;
+        return Ok(format!("{:?} does not exist yet, and will be created", path));
+    }
+    let db = Database::open_read_only(path)?;
+    let users = db.get_all_users(default_email_time)
+        .context("failed to load users")?;
+    Ok(format!("{:?}, {} users", path, users.iter().count()))
+}
+
+fn check_secret_key(source: &SecretKeySource) -> anyhow::Result<String> {
+    read_secret_key(source)
+        .with_context(|| format!("failed to read {}", source))?;
+    Ok(source.to_string())
+}
+
+fn check_incoming_mail(incoming: &IncomingMailConfig) -> anyhow::Result<String> {
+    match incoming {
+        IncomingMailConfig::Maildir { path, .. } => {
+            for subdir in ["new", "cur", "tmp"] {
+                let dir = path.join(subdir);
+                if !dir.is_dir() {
+                    bail!("{:?} is not a maildir: {:?} is missing", path, dir);
+                }
+                access(&dir, AccessFlags::R_OK | AccessFlags::W_OK | AccessFlags::X_OK)
+                    .with_context(|| format!("{:?} is not accessible", dir))?;
+            }
+            Ok(format!("maildir {:?}", path))
+        }
+        IncomingMailConfig::Imap(imap) => {
+            let mut session = crate::imap::connect(imap)?;
+            let unseen = session.unseen(None)?.len();
+            let _ = session.logout();
+            Ok(format!("IMAP mailbox {:?} on {} ({} unseen messages)", imap.mailbox, imap.host,
+                unseen))
+        }
+    }
+}
+
+fn check_email_address(addr: &str) -> anyhow::Result<String> {
+    crate::address::validate(addr)?;
+    Ok(addr.to_owned())
+}
+
+/// Make sure every user's PGP key, if they have one, can be encrypted to.
+fn check_pgp_keys(config: &Config) -> anyhow::Result<String> {
+    if !config.database_path.exists() {
+        return Ok("no users yet".to_owned());
+    }
+    let users = Database::open_read_only(&config.database_path)?
+        .get_all_users(config.default_email_time)
+        .context("failed to load users")?;
+    let mut count = 0;
+    for user in users.iter() {
+        if let Some(key) = &user.pgp_key {
+            crate::pgp::encrypt(config.gpg_path.as_deref(), key, "test")
+                .with_context(|| format!("failed to encrypt to {}'s key", user.username))?;
+            count += 1;
+        }
+    }
+    Ok(format!("{} users have their entries encrypted", count))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_check_incoming_mail() {
+        let dir = TempDir::new("check-maildir");
+        let maildir = IncomingMailConfig::Maildir {
+            path: dir.path().join("maildir"),
+            delete_processed: false,
+            quarantine_dir: None,
+        };
+        assert!(check_incoming_mail(&maildir).is_err());
+
+        for subdir in ["new", "cur"] {
+            std::fs::create_dir_all(dir.path().join("maildir").join(subdir)).unwrap();
+        }
+        assert!(check_incoming_mail(&maildir).is_err());
+
+        std::fs::create_dir_all(dir.path().join("maildir").join("tmp")).unwrap();
+        assert!(check_incoming_mail(&maildir).is_ok());
+    }
+
+    #[test]
+    fn test_check_database() {
+        let dir = TempDir::new("check-database");
+        let path = dir.path().join("daylog.db");
+        assert!(check_database(&path, None).is_ok());
+        assert!(!path.exists(), "checking shouldn't create the database");
+        assert!(check_database(&dir.path().join("nonexistent").join("daylog.db"), None).is_err());
+
+        Database::open(&path).unwrap()
+            .add_user("alice", "alice@example.com", "Not/A_Timezone", "18:00").unwrap();
+        assert!(check_database(&path, None).is_err());
+    }
+}
@@ -1,38 +1,217 @@
+use chrono::NaiveDate;
+use crate::time::DaylogTime;
 use serde::{Deserialize, Serialize};
 use std::ffi::OsStr;
 use std::fs::File;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Config {
+    /// Path the config was loaded from.
+    #[serde(skip)]
+    pub path: PathBuf,
+
+    /// Settings given on the command line instead, kept so they still apply after a reload.
+    #[serde(skip)]
+    pub overrides: Overrides,
+
     #[serde(rename = "database")]
     pub database_path: PathBuf,
 
-    #[serde(rename = "secret_key")]
-    pub secret_key_path: PathBuf,
+    pub secret_key: SecretKeySource,
 
     pub return_addr: String,
 
+    /// Display name in the 'From' header of emails sent. Defaults to "Daylog".
+    #[serde(default)]
+    pub from_name: Option<String>,
+
+    /// If set, daily emails get a 'Reply-To' header with this address.
+    #[serde(default)]
+    pub reply_to: Option<String>,
+
     #[serde(with = "serde_yaml::with::singleton_map")] // instead of YAML '!tag' syntax
     pub incoming_mail: IncomingMailConfig,
+
+    /// Subject line of the daily email. See `send::fill_template` for available placeholders.
+    #[serde(default)]
+    pub subject_template: Option<String>,
+
+    /// First line of the daily email body.
+    #[serde(default)]
+    pub prompt_template: Option<String>,
+
+    /// If not empty, the first line of the daily email body is picked from these instead, rotating
+    /// from one day to the next.
+    #[serde(default)]
+    pub prompts: Vec<String>,
+
+    /// Text following the signature delimiter at the end of the daily email.
+    #[serde(default)]
+    pub signature: Option<String>,
+
+    /// If set, ingest statistics are written to this file as JSON after each ingest.
+    #[serde(default)]
+    pub stats_file: Option<PathBuf>,
+
+    /// When running as a service, process incoming mail this often, in minutes.
+    #[serde(default)]
+    pub ingest_interval_minutes: Option<u32>,
+
+    /// Send users a confirmation email when their entry is saved.
+    #[serde(default)]
+    pub send_ack: bool,
+
+    /// Command used to send mail. Defaults to "sendmail", looked up in $PATH.
+    #[serde(default)]
+    pub sendmail_path: Option<PathBuf>,
+
+    /// Command used to encrypt entries for users with a PGP key. Defaults to "gpg", looked up in
+    /// $PATH.
+    #[serde(default)]
+    pub gpg_path: Option<PathBuf>,
+
+    /// Longest entry body, in bytes, that will be stored from one email. Longer ones are truncated.
+    #[serde(default)]
+    pub max_body_bytes: Option<usize>,
+
+    /// Most replies whose text will be added to one day's entry. Further ones are dropped.
+    #[serde(default)]
+    pub max_appends_per_day: Option<u32>,
+
+    /// When running as a service, how many times to retry sending an email that failed.
+    #[serde(default)]
+    pub send_retries: u32,
+
+    /// How long to wait before the first retry, in seconds. This doubles on each retry.
+    #[serde(default = "default_send_retry_delay_seconds")]
+    pub send_retry_delay_seconds: u64,
+
+    /// When running as a service, how many emails due at the same time to send at once.
+    #[serde(default = "default_send_concurrency")]
+    pub send_concurrency: u32,
+
+    /// Mention in the daily email how many days in a row the user has written entries.
+    #[serde(default)]
+    pub show_streak: bool,
+
+    /// Replies for entries dated before this are not stored.
+    #[serde(default)]
+    pub min_entry_date: Option<NaiveDate>,
+
+    /// Local time ("HH:MM") to send the daily email to users who don't have their own time set.
+    #[serde(default)]
+    pub default_email_time: Option<DaylogTime>,
+
+    /// Which occurrence to use when a user's email time happens twice in one day, because daylight
+    /// savings time ended.
+    #[serde(default)]
+    pub ambiguous_time: AmbiguousTime,
+
+    /// If set, files attached to replies are saved under this directory, in
+    /// `<username>/<date>/<filename>`. Otherwise attachments are ignored.
+    #[serde(default)]
+    pub attachments_dir: Option<PathBuf>,
+
+    /// Incoming messages dated more than this many days ago are skipped instead of processed.
+    #[serde(default)]
+    pub ingest_max_age_days: Option<u32>,
+
+    /// Domain used in the Message-ID of emails sent, instead of the system hostname.
+    #[serde(default)]
+    pub message_id_host: Option<String>,
+
+    /// Let replies hold entries for several days, each starting with a line like "@2024-01-02:".
+    #[serde(default)]
+    pub multi_day_entries: bool,
+
+    /// Write the 'Date' header of daily emails in the user's timezone, instead of UTC.
+    #[serde(default)]
+    pub local_date_header: bool,
+
+    /// When running as a service, spread out sending emails due at the same time over this many
+    /// seconds, instead of sending them all at once.
+    #[serde(default)]
+    pub send_splay_seconds: u64,
+
+    /// When running as a service, serve Prometheus metrics over HTTP at `/metrics` on this address.
+    /// Only read at startup.
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// What to do with quoted text in replies.
+    #[serde(default)]
+    pub quote_handling: QuoteHandling,
+
+    /// Accept replies with no plain text, converting their HTML to text instead. Otherwise they're
+    /// skipped, and logged as errors.
+    #[serde(default = "default_accept_html_only")]
+    pub accept_html_only: bool,
+
+    /// Also write log messages to this file, in addition to stderr. Only read at startup.
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+
+    /// When the log file would grow past this size, it's renamed with a ".1" suffix (shifting older
+    /// ones to ".2" and so on) and a new one is started.
+    #[serde(default = "default_log_file_max_bytes")]
+    pub log_file_max_bytes: u64,
+
+    /// How many renamed log files to keep. Older ones are deleted.
+    #[serde(default = "default_log_file_keep")]
+    pub log_file_keep: u32,
+
+    /// Add a 'Precedence: bulk' header to daily emails.
+    #[serde(default)]
+    pub precedence_bulk: bool,
+
+    /// When running as a service, skip sending emails that are more than this many minutes overdue
+    /// (say, because the machine was suspended), instead of sending them late.
+    #[serde(default)]
+    pub max_late_send_minutes: Option<u32>,
 }
 
-#[derive(Clone)]
-pub struct ConfigParser;
+fn default_send_retry_delay_seconds() -> u64 {
+    60
+}
 
-impl clap::builder::TypedValueParser for ConfigParser {
-    type Value = Config;
+fn default_send_concurrency() -> u32 {
+    1
+}
 
-    fn parse_ref(&self, cmd: &clap::Command, _arg: Option<&clap::Arg>, value: &OsStr)
-        -> Result<Self::Value, clap::Error>
-    {
-        use clap::error::*;
-        Config::try_from_path(value)
-            .map_err(|msg| Error::raw(ErrorKind::InvalidValue, msg).with_cmd(cmd))
-    }
+fn default_accept_html_only() -> bool {
+    true
+}
+
+fn default_log_file_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_log_file_keep() -> u32 {
+    5
+}
+
+/// Config settings that can be overridden on the command line.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Overrides {
+    pub database_path: Option<PathBuf>,
+    pub secret_key: Option<SecretKeySource>,
 }
 
 impl Config {
+    /// Replace settings from the config file with the given ones.
+    pub fn with_overrides(mut self, overrides: Overrides) -> Self {
+        if let Some(ref path) = overrides.database_path {
+            self.database_path = path.clone();
+        }
+        if let Some(ref source) = overrides.secret_key {
+            self.secret_key = source.clone();
+        }
+        self.overrides = overrides;
+        self
+    }
+
     pub fn try_from_path(os_str: &OsStr) -> Result<Self, String> {
         let config_path = std::fs::canonicalize(Path::new(os_str))
             .map_err(|e| format!("Unable to canonicalize path {:?}: {}", os_str, e))?;
@@ -40,16 +219,60 @@ impl Config {
             .map_err(|e| format!("Error opening config file {:?}: {}", config_path, e))?;
         let mut config: Self = serde_yaml::from_reader(file)
             .map_err(|e| format!("Error parsing config file {:?}: {}", config_path, e))?;
+        config.validate()
+            .map_err(|e| format!("Error in config file {:?}: {:#}", config_path, e))?;
         config.resolve_paths(config_path.parent().unwrap());
+        config.path = config_path;
         Ok(config)
     }
 
+    fn validate(&self) -> anyhow::Result<()> {
+        use anyhow::Context;
+        crate::address::validate(&self.return_addr)
+            .context("invalid return_addr")?;
+        if let Some(ref reply_to) = self.reply_to {
+            crate::address::validate(reply_to)
+                .context("invalid reply_to")?;
+        }
+        if let Some(ref host) = self.message_id_host {
+            if host.is_empty() || host.contains(|c: char| c.is_whitespace() || "<>@".contains(c)) {
+                anyhow::bail!("invalid message_id_host {:?}", host);
+            }
+        }
+        if self.send_concurrency == 0 {
+            anyhow::bail!("send_concurrency must be at least 1");
+        }
+        Ok(())
+    }
+
     pub fn resolve_paths(&mut self, base_path: &Path) {
-        for path_mut in &mut [&mut self.database_path, &mut self.secret_key_path] {
-            Self::resolve_path(path_mut, base_path);
+        Self::resolve_path(&mut self.database_path, base_path);
+        if let SecretKeySource::File(ref mut path) = self.secret_key {
+            Self::resolve_path(path, base_path);
         }
-        let IncomingMailConfig::Maildir { path: ref mut incoming_path } = &mut self.incoming_mail;
-        Self::resolve_path(incoming_path, base_path);
+        for path in [&mut self.stats_file, &mut self.attachments_dir, &mut self.log_file]
+            .into_iter().flatten()
+        {
+            Self::resolve_path(path, base_path);
+        }
+        if let IncomingMailConfig::Maildir { path: ref mut incoming_path, ref mut quarantine_dir, .. }
+            = &mut self.incoming_mail
+        {
+            Self::resolve_path(incoming_path, base_path);
+            if let Some(dir) = quarantine_dir {
+                Self::resolve_path(dir, base_path);
+            }
+        }
+    }
+
+    pub fn ingest_interval(&self) -> Option<std::time::Duration> {
+        self.ingest_interval_minutes
+            .map(|minutes| std::time::Duration::from_secs(u64::from(minutes) * 60))
+    }
+
+    pub fn max_late_send(&self) -> Option<chrono::Duration> {
+        self.max_late_send_minutes
+            .map(|minutes| chrono::Duration::minutes(i64::from(minutes)))
     }
 
     fn resolve_path(path: &mut PathBuf, base_path: &Path) {
@@ -59,15 +282,167 @@ impl Config {
     }
 }
 
+/// Where the secret key comes from. In the config file, this is a path to a file, `env:NAME` for
+/// the environment variable NAME (holding the key in base64), or `-` for standard input.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
+pub enum SecretKeySource {
+    File(PathBuf),
+    Env(String),
+    Stdin,
+}
+
+impl From<String> for SecretKeySource {
+    fn from(s: String) -> Self {
+        if s == "-" {
+            SecretKeySource::Stdin
+        } else if let Some(name) = s.strip_prefix("env:") {
+            SecretKeySource::Env(name.to_owned())
+        } else {
+            SecretKeySource::File(PathBuf::from(s))
+        }
+    }
+}
+
+impl std::str::FromStr for SecretKeySource {
+    type Err = std::convert::Infallible;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from(s.to_owned()))
+    }
+}
+
+impl From<SecretKeySource> for String {
+    fn from(source: SecretKeySource) -> Self {
+        match source {
+            SecretKeySource::File(path) => path.to_string_lossy().into_owned(),
+            SecretKeySource::Env(name) => format!("env:{}", name),
+            SecretKeySource::Stdin => "-".to_owned(),
+        }
+    }
+}
+
+impl std::fmt::Display for SecretKeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretKeySource::File(path) => write!(f, "{:?}", path),
+            SecretKeySource::Env(name) => write!(f, "environment variable {}", name),
+            SecretKeySource::Stdin => f.write_str("standard input"),
+        }
+    }
+}
+
+/// How quoted text in replies (lines starting with '>') is treated when extracting the entry.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum QuoteHandling {
+    /// Remove all quoted text, and the attribution lines above it.
+    #[default]
+    Strip,
+    /// Keep quoted text.
+    Keep,
+    /// Keep quoted text that comes before some of the user's own text, as in an inline reply,
+    /// but remove quoted text after it, and attribution lines.
+    Smart,
+}
+
+/// How to pick between the two instants a local time refers to when clocks are set back, such as
+/// 1:30 AM in America/Los_Angeles on 2020-11-01, which is both 08:30 and 09:30 UTC.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AmbiguousTime {
+    /// The first occurrence, before the clocks are set back.
+    Earliest,
+    /// The second occurrence, after the clocks are set back.
+    #[default]
+    Latest,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub enum IncomingMailConfig {
     /// Maildir path
     #[serde(rename = "maildir")]
     Maildir {
         path: PathBuf,
+
+        /// Delete messages after they're successfully processed, instead of marking them as seen.
+        #[serde(default)]
+        delete_processed: bool,
+
+        /// Move messages that can't be parsed to this directory, instead of marking them as seen.
+        #[serde(default)]
+        quarantine_dir: Option<PathBuf>,
     },
 
-    // and maybe other sources in the future?
+    #[serde(rename = "imap")]
+    Imap(ImapConfig),
+}
+
+/// Settings for reading incoming mail from an IMAP mailbox.
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ImapConfig {
+    pub host: String,
+
+    /// Defaults to 993 with TLS, or 143 without.
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    pub username: String,
+
+    pub password: String,
+
+    /// Mailbox to read from.
+    #[serde(default = "default_imap_mailbox")]
+    pub mailbox: String,
+
+    /// Connect using TLS. Turning this off is only a good idea for servers on the same host.
+    #[serde(default = "default_imap_tls")]
+    pub tls: bool,
+
+    /// Move processed messages to this mailbox, instead of just marking them as seen.
+    #[serde(default)]
+    pub archive_mailbox: Option<String>,
+
+    /// How long to wait to connect to the server.
+    #[serde(default = "default_imap_connect_timeout")]
+    pub connect_timeout_seconds: u64,
+
+    /// How long to wait for the server to respond to anything, after which the connection is
+    /// abandoned (and retried).
+    #[serde(default = "default_imap_read_timeout")]
+    pub read_timeout_seconds: u64,
+}
+
+fn default_imap_mailbox() -> String {
+    "INBOX".to_owned()
+}
+
+fn default_imap_tls() -> bool {
+    true
+}
+
+fn default_imap_connect_timeout() -> u64 {
+    30
+}
+
+fn default_imap_read_timeout() -> u64 {
+    60
+}
+
+/// Leaves out the password, since the config gets logged.
+impl std::fmt::Debug for ImapConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImapConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .field("mailbox", &self.mailbox)
+            .field("tls", &self.tls)
+            .field("archive_mailbox", &self.archive_mailbox)
+            .field("connect_timeout_seconds", &self.connect_timeout_seconds)
+            .field("read_timeout_seconds", &self.read_timeout_seconds)
+            .finish()
+    }
 }
 
 #[cfg(test)]
@@ -86,13 +461,136 @@ incoming_mail:
 ";
         let deserialized: Config = serde_yaml::from_str(yaml).expect("failed to deserialize");
         let expected = Config {
+            path: PathBuf::new(),
+            overrides: Overrides::default(),
             database_path: PathBuf::from("/some/db.sqlite"),
-            secret_key_path: PathBuf::from("/some/secret/file"),
+            secret_key: SecretKeySource::File(PathBuf::from("/some/secret/file")),
             return_addr: "daylog@example.com".to_owned(),
+            from_name: None,
+            reply_to: None,
             incoming_mail: IncomingMailConfig::Maildir {
                 path: PathBuf::from("/var/spool/mail/daylog"),
+                delete_processed: false,
+                quarantine_dir: None,
             },
+            subject_template: None,
+            prompt_template: None,
+            prompts: vec![],
+            signature: None,
+            stats_file: None,
+            ingest_interval_minutes: None,
+            send_ack: false,
+            sendmail_path: None,
+            gpg_path: None,
+            max_body_bytes: None,
+            max_appends_per_day: None,
+            send_retries: 0,
+            send_retry_delay_seconds: 60,
+            send_concurrency: 1,
+            show_streak: false,
+            min_entry_date: None,
+            default_email_time: None,
+            ambiguous_time: AmbiguousTime::Latest,
+            attachments_dir: None,
+            ingest_max_age_days: None,
+            message_id_host: None,
+            multi_day_entries: false,
+            local_date_header: false,
+            send_splay_seconds: 0,
+            metrics_addr: None,
+            quote_handling: QuoteHandling::Strip,
+            accept_html_only: true,
+            log_file: None,
+            log_file_max_bytes: 10 * 1024 * 1024,
+            log_file_keep: 5,
+            precedence_bulk: false,
+            max_late_send_minutes: None,
         };
         assert_eq!(deserialized, expected);
     }
+
+    #[test]
+    fn test_imap() {
+        let yaml = r"
+host: imap.example.com
+username: daylog
+password: hunter2
+";
+        let imap: ImapConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(ImapConfig {
+                host: "imap.example.com".to_owned(),
+                port: None,
+                username: "daylog".to_owned(),
+                password: "hunter2".to_owned(),
+                mailbox: "INBOX".to_owned(),
+                tls: true,
+                archive_mailbox: None,
+                connect_timeout_seconds: 30,
+                read_timeout_seconds: 60,
+            }, imap);
+        assert!(!format!("{:?}", imap).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_invalid_addresses() {
+        let dir = crate::testutil::TempDir::new("config-addresses");
+        let path = dir.path().join("config.yaml");
+        let load = |extra: &str| {
+            std::fs::write(&path, format!("\
+database: daylog.db
+secret_key: key
+incoming_mail:
+    maildir:
+        path: maildir
+{}", extra)).unwrap();
+            Config::try_from_path(path.as_os_str())
+        };
+
+        assert!(load("return_addr: daylog@example.com\n").is_ok());
+        let err = load("return_addr: daylog.example.com\n").unwrap_err();
+        assert!(err.contains("invalid return_addr: \"daylog.example.com\" is not an email address"),
+            "{}", err);
+        let err = load("return_addr: daylog@example.com\nreply_to: replies@\n").unwrap_err();
+        assert!(err.contains("invalid reply_to"), "{}", err);
+        let err = load("return_addr: daylog@example.com\nmessage_id_host: a b\n").unwrap_err();
+        assert!(err.contains("invalid message_id_host \"a b\""), "{}", err);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let dir = crate::testutil::TempDir::new("config-overrides");
+        let config = crate::testutil::test_config(&dir, "");
+        assert_eq!(dir.path().join("daylog.db"), config.database_path);
+
+        let overrides = Overrides {
+            database_path: Some(PathBuf::from("/other/daylog.db")),
+            secret_key: None,
+        };
+        let config = config.with_overrides(overrides.clone());
+        assert_eq!(PathBuf::from("/other/daylog.db"), config.database_path);
+        assert_eq!(SecretKeySource::File(dir.path().join("key")), config.secret_key);
+        assert_eq!(overrides, config.overrides);
+
+        let config = config.with_overrides(Overrides {
+            database_path: None,
+            secret_key: Some(SecretKeySource::Env("DAYLOG_KEY".to_owned())),
+        });
+        assert_eq!(SecretKeySource::Env("DAYLOG_KEY".to_owned()), config.secret_key);
+    }
+
+    #[test]
+    fn test_default_email_time() {
+        let yaml = "\
+database: /some/db.sqlite
+secret_key: /some/secret/file
+return_addr: daylog@example.com
+incoming_mail:
+    maildir:
+        path: /var/spool/mail/daylog
+default_email_time: ";
+        let config: Config = serde_yaml::from_str(&format!("{}\"19:30\"\n", yaml)).unwrap();
+        assert_eq!(Some(DaylogTime::parse("19:30").unwrap()), config.default_email_time);
+        let err = serde_yaml::from_str::<Config>(&format!("{}\"25:00\"\n", yaml)).unwrap_err();
+        assert!(err.to_string().contains("invalid time \"25:00\": hour is out of range"), "{}", err);
+    }
 }
@@ -1,10 +1,34 @@
 use anyhow::Context;
+use chrono::NaiveDate;
+use crate::time::DaylogTime;
 use crate::user::{User, Users};
 use rusqlite::{named_params, OptionalExtension};
 use serde::{Deserialize, Serialize};
-use std::convert::TryFrom;
+use std::collections::BTreeSet;
 use std::path::Path;
 
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Columns of the 'users' table. `email_time_local` is null or empty for users who get the
+/// configured default time.
+const USERS_COLUMNS: &str = "\
+    id INTEGER PRIMARY KEY NOT NULL,\
+    username STRING UNIQUE NOT NULL,\
+    email STRING NOT NULL,\
+    timezone STRING NOT NULL,\
+    email_time_local STRING,\
+    cc STRING NOT NULL DEFAULT '',\
+    enabled INTEGER NOT NULL DEFAULT 1,\
+    days STRING NOT NULL DEFAULT '',\
+    frequency STRING NOT NULL DEFAULT '',\
+    pgp_key STRING NOT NULL DEFAULT ''";
+
+/// The user's current timezone, for recording with their entries.
+const USER_TIMEZONE: &str = "(SELECT timezone FROM users WHERE username = :username)";
+
+/// The current UTC time, as an RFC 3339 timestamp.
+const NOW: &str = "strftime('%Y-%m-%dT%H:%M:%SZ', 'now')";
+
 pub struct Database {
     db: rusqlite::Connection,
 }
@@ -14,13 +38,34 @@ impl Database {
         let db = rusqlite::Connection::open(path)
             .with_context(|| format!("failed to open SQLite database {:?}", path))?;
 
+        // The 'run' service and an 'ingest' cron job can both be writing at once; WAL mode and a busy
+        // timeout make them wait for each other instead of failing with SQLITE_BUSY.
+        db.busy_timeout(BUSY_TIMEOUT)
+            .context("failed to set database busy timeout")?;
+        let journal_mode = db.query_row("PRAGMA journal_mode = WAL", [],
+                |row| row.get::<_, String>(0))
+            .context("failed to set database journal mode")?;
+        if journal_mode != "wal" {
+            warn!("database journal mode is {:?}, not WAL", journal_mode);
+        }
+
         // TODO: schema upgrades
 
+        // 'date' is the user's local date when they wrote the entry, and 'tz' is the timezone that
+        // was in (as in the 'users' table). 'created_at' is when the entry was first added (in UTC,
+        // RFC 3339), and 'message_ids' the space-separated Message-IDs of the emails it came from.
+        // 'raw_bodies' is a JSON list of those emails' text before it was cleaned up, so the entry
+        // can be made again; it's null if some of the entry didn't come from an email.
+        // They're all null for entries from before they were recorded.
         db.execute("CREATE TABLE IF NOT EXISTS entries (\
             id INTEGER PRIMARY KEY NOT NULL,\
             username STRING NOT NULL,\
             date STRING NOT NULL,\
-            body STRING NOT NULL\
+            body STRING NOT NULL,\
+            tz STRING,\
+            created_at STRING,\
+            message_ids STRING,\
+            raw_bodies STRING\
         )", [])
             .context("failed to create 'entries' database table")?;
 
@@ -29,44 +74,140 @@ impl Database {
         )", [])
             .context("failed to create index on 'entries' database table")?;
 
-        db.execute("CREATE TABLE IF NOT EXISTS users (\
-            id INTEGER PRIMARY KEY NOT NULL,\
-            username STRING UNIQUE NOT NULL,\
-            email STRING NOT NULL,\
-            timezone STRING NOT NULL,\
-            email_time_local STRING NOT NULL\
-        )", [])
+        db.execute(&format!("CREATE TABLE IF NOT EXISTS users ({})", USERS_COLUMNS), [])
             .context("failed to create 'users' database table")?;
 
+        // Columns added later; databases created before then need them added.
+        add_column_if_missing(&db, "users", "cc", "STRING NOT NULL DEFAULT ''")?;
+        add_column_if_missing(&db, "users", "enabled", "INTEGER NOT NULL DEFAULT 1")?;
+        add_column_if_missing(&db, "users", "days", "STRING NOT NULL DEFAULT ''")?;
+        add_column_if_missing(&db, "users", "frequency", "STRING NOT NULL DEFAULT ''")?;
+        add_column_if_missing(&db, "users", "pgp_key", "STRING NOT NULL DEFAULT ''")?;
+        add_column_if_missing(&db, "entries", "tz", "STRING")?;
+        add_column_if_missing(&db, "entries", "created_at", "STRING")?;
+        add_column_if_missing(&db, "entries", "message_ids", "STRING")?;
+        add_column_if_missing(&db, "entries", "raw_bodies", "STRING")?;
+
+        // email_time_local used to be NOT NULL, before there was a default_email_time.
+        make_users_email_time_nullable(&db)?;
+
+        Ok(Self {
+            db,
+        })
+    }
+
+    /// Open an existing database without creating or changing anything, for commands that only
+    /// report on it, so they can run alongside the service without taking any write locks.
+    pub fn open_read_only(path: &Path) -> anyhow::Result<Self> {
+        let db = rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("failed to open SQLite database {:?}", path))?;
+        // Readers can still have to wait briefly, like while the service checkpoints the WAL.
+        db.busy_timeout(BUSY_TIMEOUT)
+            .context("failed to set database busy timeout")?;
         Ok(Self {
             db,
         })
     }
 
-    pub fn add_entry(&mut self, username: &str, date: &str, body: &str) -> anyhow::Result<()> {
+    /// Write a consistent copy of the database to the given path, using SQLite's online backup,
+    /// so it's safe to do while other connections are writing to it. The copy is written next to
+    /// the destination first and then renamed into place, replacing any file already there.
+    pub fn backup(&self, dest: &Path) -> anyhow::Result<()> {
+        let mut tmp_name = dest.file_name()
+            .ok_or_else(|| anyhow::anyhow!("invalid backup path {:?}", dest))?
+            .to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = dest.with_file_name(tmp_name);
+
+        // A leftover from an earlier failed backup would otherwise get pages written over it.
+        match std::fs::remove_file(&tmp_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("failed to remove {:?}", tmp_path));
+            }
+            _ => (),
+        }
+        self.db.backup(rusqlite::DatabaseName::Main, &tmp_path, None)
+            .with_context(|| format!("failed to back up database to {:?}", tmp_path))?;
+        std::fs::rename(&tmp_path, dest)
+            .with_context(|| format!("failed to rename {:?} to {:?}", tmp_path, dest))?;
+        Ok(())
+    }
+
+    /// Run SQLite's integrity check, returning the problems it found, if any.
+    pub fn integrity_check(&self) -> anyhow::Result<Vec<String>> {
+        let mut stmt = self.db.prepare("PRAGMA integrity_check")?;
+        let messages = stmt.query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .context("failed to run database integrity check")?;
+        if messages == ["ok"] {
+            Ok(vec![])
+        } else {
+            Ok(messages)
+        }
+    }
+
+    /// Rebuild the database file to reclaim free space. This needs exclusive access to the
+    /// database, so it waits for (or fails because of) anything else using it.
+    pub fn vacuum(&self) -> anyhow::Result<()> {
+        self.db.execute_batch("VACUUM")
+            .context("failed to vacuum database")?;
+        // Get the WAL file back down to size too.
+        self.db.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+            .context("failed to checkpoint database")?;
+        Ok(())
+    }
+
+    /// Add an entry, or append to the existing one for that day. `source` is the email it came
+    /// from, if any; if the entry already has that message's text, nothing is added.
+    pub fn add_entry(&mut self, username: &str, date: &str, body: &str,
+        source: Option<&EntrySource>) -> anyhow::Result<()>
+    {
+        let msgid = source.map(|source| source.msgid.as_str());
         let tx = self.db.transaction()?;
 
         let insert_result = tx.execute(
-            "INSERT INTO entries (username, date, body) \
-                VALUES (:username, :date, :body)",
+            &format!("INSERT INTO entries \
+                    (username, date, body, tz, created_at, message_ids, raw_bodies) \
+                VALUES (:username, :date, :body, {}, {}, :msgid, :raw)", USER_TIMEZONE, NOW),
             named_params!{
                 ":username": username,
                 ":date": date,
                 ":body": body,
+                ":msgid": msgid,
+                ":raw": raw_bodies_json(None, source)?,
             });
 
         if insert_result.is_unique_constraint_error() {
-            let (id, mut update_body): (i64, String) = tx.query_row(
-                "SELECT id, body FROM entries WHERE username = :username AND date = :date",
-                named_params!{ ":username": username, ":date": date },
-                |row| Ok((row.get(0)?, row.get(1)?)),
-                )?;
+            let (id, mut update_body, msgids, raw): (i64, String, Option<String>, Option<String>)
+                = tx.query_row(
+                    "SELECT id, body, message_ids, raw_bodies FROM entries \
+                        WHERE username = :username AND date = :date",
+                    named_params!{ ":username": username, ":date": date },
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                    )?;
+            let mut msgids = msgids.unwrap_or_default();
+            if let Some(msgid) = msgid {
+                if msgids.split_whitespace().any(|id| id == msgid) {
+                    info!("row {} ({}/{}) already has message {:?}", id, username, date, msgid);
+                    return Ok(());
+                }
+                if !msgids.is_empty() {
+                    msgids.push(' ');
+                }
+                msgids += msgid;
+            }
             info!("updating existing row {}: {}/{}", id, username, date);
             update_body.push('\n');
             update_body +=  body;
+            // Once any part of the entry isn't from a known source, it can't be made again.
+            let raw = match raw {
+                Some(raw) => raw_bodies_json(Some(&raw), source)?,
+                None => None,
+            };
             tx.execute(
-                "UPDATE entries SET body = :body WHERE id = :id",
-                named_params!{ ":body": update_body, ":id": id },
+                "UPDATE entries SET body = :body, message_ids = NULLIF(:msgids, ''), \
+                    raw_bodies = :raw WHERE id = :id",
+                named_params!{ ":body": update_body, ":msgids": msgids, ":raw": raw, ":id": id },
                 )
                 .context("failed to update existing entry")?;
         } else {
@@ -77,19 +218,89 @@ impl Database {
         Ok(())
     }
 
-    pub fn get_all_users(&self) -> anyhow::Result<Users> {
+    /// Set the body of an entry, replacing any existing one instead of appending to it.
+    pub fn set_entry(&mut self, username: &str, date: &str, body: &str,
+        source: Option<&EntrySource>) -> anyhow::Result<()>
+    {
+        self.db.execute(
+                &format!("INSERT INTO entries \
+                        (username, date, body, tz, created_at, message_ids, raw_bodies) \
+                    VALUES (:username, :date, :body, {}, {}, :msgid, :raw) \
+                    ON CONFLICT (username, date) DO UPDATE SET body = excluded.body, \
+                        tz = excluded.tz, created_at = excluded.created_at, \
+                        message_ids = excluded.message_ids, raw_bodies = excluded.raw_bodies",
+                    USER_TIMEZONE, NOW),
+                named_params!{
+                    ":username": username,
+                    ":date": date,
+                    ":body": body,
+                    ":msgid": source.map(|source| source.msgid.as_str()),
+                    ":raw": raw_bodies_json(None, source)?,
+                })
+            .context("failed to set entry")?;
+        Ok(())
+    }
+
+    /// Change the body of an entry, leaving everything else about it alone.
+    pub fn update_entry_body(&mut self, username: &str, date: &str, body: &str)
+        -> anyhow::Result<()>
+    {
+        self.db.execute(
+                "UPDATE entries SET body = :body WHERE username = :username AND date = :date",
+                named_params!{ ":username": username, ":date": date, ":body": body })
+            .context("failed to update entry")?;
+        Ok(())
+    }
+
+    /// Delete an entry. Returns whether there was one to delete.
+    pub fn delete_entry(&mut self, username: &str, date: &str) -> anyhow::Result<bool> {
+        let rows = self.db.execute(
+                "DELETE FROM entries WHERE username = :username AND date = :date",
+                named_params!{ ":username": username, ":date": date },
+            )
+            .context("failed to delete entry")?;
+        Ok(rows > 0)
+    }
+
+    #[cfg(test)]
+    pub fn add_user(&mut self, username: &str, email: &str, timezone: &str, email_time_local: &str)
+        -> anyhow::Result<()>
+    {
+        self.db.execute(
+            "INSERT INTO users (username, email, timezone, email_time_local) \
+                VALUES (:username, :email, :timezone, :email_time_local)",
+            named_params!{
+                ":username": username,
+                ":email": email,
+                ":timezone": timezone,
+                ":email_time_local": email_time_local,
+            })?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn set_pgp_key(&mut self, username: &str, key: &str) -> anyhow::Result<()> {
+        self.db.execute("UPDATE users SET pgp_key = :key WHERE username = :username",
+            named_params!{ ":username": username, ":key": key })?;
+        Ok(())
+    }
+
+    /// Load every user. Users without their own email time get `default_email_time`.
+    pub fn get_all_users(&self, default_email_time: Option<DaylogTime>) -> anyhow::Result<Users> {
         serde_rusqlite::from_rows::<UserRaw>(
             self.db.prepare("SELECT * FROM users")?
                 .query([])?
         )
         .try_fold(vec![], |mut vec, u| {
-            vec.push(User::try_from(u?)?);
+            vec.push(User::from_raw(u?, default_email_time)?);
             Ok(vec)
         })
         .map(Users::new)
     }
 
-    pub fn get_user(&self, username: &str) -> anyhow::Result<User> {
+    pub fn get_user(&self, username: &str, default_email_time: Option<DaylogTime>)
+        -> anyhow::Result<User>
+    {
         serde_rusqlite::from_rows::<UserRaw>(
             self.db.prepare("SELECT * FROM users WHERE username = :username")?
                 .query(named_params!{ ":username": username })?
@@ -97,7 +308,32 @@ impl Database {
         .next()
         .transpose()?
         .ok_or_else(|| anyhow::anyhow!("no such user {}", username))
-        .and_then(User::try_from)
+        .and_then(|raw| User::from_raw(raw, default_email_time))
+    }
+
+    /// The user's PGP public key, if they have one and their entries are to be encrypted.
+    pub fn get_pgp_key(&self, username: &str) -> anyhow::Result<Option<String>> {
+        let key = self.db.query_row(
+                "SELECT pgp_key FROM users WHERE username = :username",
+                named_params!{ ":username": username },
+                |row| row.get::<_, String>(0),
+            )
+            .optional()
+            .context("failed to query user's PGP key")?;
+        Ok(key.filter(|key| !key.trim().is_empty()))
+    }
+
+    /// Turn a user's daily email on or off.
+    pub fn set_user_enabled(&self, username: &str, enabled: bool) -> anyhow::Result<()> {
+        let rows = self.db.execute(
+                "UPDATE users SET enabled = :enabled WHERE username = :username",
+                named_params!{ ":enabled": enabled, ":username": username },
+            )
+            .context("failed to update user")?;
+        if rows == 0 {
+            anyhow::bail!("no such user {}", username);
+        }
+        Ok(())
     }
 
     pub fn get_entry(&self, username: &str, date: &str) -> anyhow::Result<Option<String>> {
@@ -111,8 +347,293 @@ impl Database {
             )
             .optional()
             .context("failed to query entry")
-            .map_err(Into::into)
     }
+
+    /// Like `get_entry`, but also returns the timezone the entry was written in, if it's known and
+    /// different from the user's timezone now, meaning its date is a day in that timezone.
+    pub fn get_entry_with_old_timezone(&self, username: &str, date: &str)
+        -> anyhow::Result<Option<(String, Option<String>)>>
+    {
+        self.db.prepare(&format!("SELECT body, \
+                    CASE WHEN tz IS NOT {} THEN tz END \
+                FROM entries \
+                WHERE username = :username \
+                AND date = :date", USER_TIMEZONE))
+            .context("failed to prepare entry query")?
+            .query_row(
+                named_params!{ ":username": username, ":date": date },
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+            )
+            .optional()
+            .context("failed to query entry")
+    }
+
+    /// The Message-IDs of the emails the user's entry for the day was added from.
+    pub fn get_entry_message_ids(&self, username: &str, date: &str)
+        -> anyhow::Result<Vec<String>>
+    {
+        let msgids = self.db.query_row(
+                "SELECT message_ids FROM entries WHERE username = :username AND date = :date",
+                named_params!{ ":username": username, ":date": date },
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()
+            .context("failed to query entry")?;
+        Ok(msgids.flatten()
+            .map(|ids| ids.split_whitespace().map(str::to_owned).collect())
+            .unwrap_or_default())
+    }
+
+    pub fn count_entries(&self, username: &str) -> anyhow::Result<u64> {
+        self.db.query_row(
+                "SELECT COUNT(*) FROM entries WHERE username = :username",
+                named_params!{ ":username": username },
+                |row| row.get(0),
+            )
+            .context("failed to count entries")
+    }
+
+    pub fn first_entry_date(&self, username: &str) -> anyhow::Result<Option<NaiveDate>> {
+        self.entry_date("MIN", username)
+    }
+
+    pub fn last_entry_date(&self, username: &str) -> anyhow::Result<Option<NaiveDate>> {
+        self.entry_date("MAX", username)
+    }
+
+    fn entry_date(&self, aggregate: &str, username: &str) -> anyhow::Result<Option<NaiveDate>> {
+        // Dates are stored as YYYY-MM-DD, so they sort correctly as strings.
+        self.db.query_row(
+                &format!("SELECT {}(date) FROM entries WHERE username = :username", aggregate),
+                named_params!{ ":username": username },
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .context("failed to query entry date")?
+            .map(|date| parse_date(&date))
+            .transpose()
+    }
+
+    /// Dates from `from` to `to`, inclusive, that the user has no entry for. This fetches the dates
+    /// there are entries for in one query, using the (username, date) index, and diffs them against
+    /// the range.
+    pub fn missing_dates(&self, username: &str, from: NaiveDate, to: NaiveDate)
+        -> anyhow::Result<Vec<NaiveDate>>
+    {
+        let mut stmt = self.db.prepare("SELECT date FROM entries \
+                WHERE username = :username \
+                AND date >= :from AND date <= :to")
+            .context("failed to prepare entry dates query")?;
+        let present = stmt.query_map(named_params!{
+                ":username": username,
+                ":from": from.format("%Y-%m-%d").to_string(),
+                ":to": to.format("%Y-%m-%d").to_string(),
+            }, |row| row.get::<_, String>(0))
+            .context("failed to query entry dates")?
+            .map(|date| parse_date(&date.context("failed to query entry dates")?))
+            .collect::<anyhow::Result<BTreeSet<_>>>()?;
+
+        Ok(from.iter_days()
+            .take_while(|date| *date <= to)
+            .filter(|date| !present.contains(date))
+            .collect())
+    }
+
+    /// The user's entries from `from` to `to`, inclusive, in date order.
+    pub fn get_entries(&self, username: &str, from: NaiveDate, to: NaiveDate)
+        -> anyhow::Result<Vec<(NaiveDate, String)>>
+    {
+        let mut stmt = self.db.prepare("SELECT date, body FROM entries \
+                WHERE username = :username \
+                AND date >= :from AND date <= :to \
+                ORDER BY date")
+            .context("failed to prepare entries query")?;
+        let rows = stmt.query_map(named_params!{
+                ":username": username,
+                ":from": from.format("%Y-%m-%d").to_string(),
+                ":to": to.format("%Y-%m-%d").to_string(),
+            }, |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .context("failed to query entries")?;
+        rows.map(|row| {
+                let (date, body) = row.context("failed to query entries")?;
+                Ok((parse_date(&date)?, body))
+            })
+            .collect()
+    }
+
+    /// The user's entries from `from` to `to`, inclusive, in date order, with the emails they were
+    /// made from. Entries that weren't entirely made from emails with known sources are left out.
+    pub fn get_entry_sources(&self, username: &str, from: NaiveDate, to: NaiveDate)
+        -> anyhow::Result<Vec<(NaiveDate, String, Vec<EntrySource>)>>
+    {
+        let mut stmt = self.db.prepare("SELECT date, body, raw_bodies FROM entries \
+                WHERE username = :username \
+                AND date >= :from AND date <= :to \
+                AND raw_bodies IS NOT NULL \
+                ORDER BY date")
+            .context("failed to prepare entry sources query")?;
+        let rows = stmt.query_map(named_params!{
+                ":username": username,
+                ":from": from.format("%Y-%m-%d").to_string(),
+                ":to": to.format("%Y-%m-%d").to_string(),
+            }, |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?)))
+            .context("failed to query entry sources")?;
+        rows.map(|row| {
+                let (date, body, raw) = row.context("failed to query entry sources")?;
+                let sources = serde_json::from_str(&raw)
+                    .with_context(|| format!("invalid raw_bodies for {}/{}", username, date))?;
+                Ok((parse_date(&date)?, body, sources))
+            })
+            .collect()
+    }
+
+    /// Find the user's entries containing every word in the query, ignoring (ASCII) case, in date
+    /// order. This scans the user's entries with LIKE rather than using a full-text index, so
+    /// there's no separate index to keep in sync with the entries table. Entries with any
+    /// encrypted replies are left out, since the ciphertext could match by chance.
+    pub fn search(&self, username: &str, query: &str) -> anyhow::Result<Vec<(NaiveDate, String)>> {
+        let words = query.split_whitespace().collect::<Vec<_>>();
+        if words.is_empty() {
+            anyhow::bail!("empty search query");
+        }
+
+        let mut sql = format!("SELECT date, body FROM entries WHERE username = ? \
+            AND body NOT LIKE '%{}%'", crate::pgp::MESSAGE_HEADER);
+        for _ in &words {
+            sql += " AND body LIKE ? ESCAPE '\\'";
+        }
+        sql += " ORDER BY date";
+
+        let patterns = words.iter().map(|word| like_pattern(word)).collect::<Vec<_>>();
+        let params = std::iter::once(username)
+            .chain(patterns.iter().map(String::as_str));
+
+        let mut stmt = self.db.prepare(&sql)
+            .context("failed to prepare search query")?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(params),
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .context("failed to search entries")?;
+        rows.map(|row| {
+                let (date, body) = row.context("failed to search entries")?;
+                Ok((parse_date(&date)?, body))
+            })
+            .collect()
+    }
+
+    /// Count the days with entries going backwards from the given date, stopping at the first day
+    /// without one.
+    pub fn streak(&self, username: &str, end: NaiveDate) -> anyhow::Result<u64> {
+        let mut stmt = self.db.prepare("SELECT date FROM entries \
+                WHERE username = :username \
+                AND date <= :date \
+                ORDER BY date DESC")
+            .context("failed to prepare streak query")?;
+        let mut rows = stmt.query(named_params!{
+                ":username": username,
+                ":date": end.format("%Y-%m-%d").to_string(),
+            })
+            .context("failed to query entry dates")?;
+
+        let mut count = 0;
+        let mut expected = Some(end);
+        while let Some(row) = rows.next().context("failed to query entry dates")? {
+            let date = parse_date(&row.get::<_, String>(0)?)?;
+            if Some(date) != expected {
+                break;
+            }
+            count += 1;
+            expected = date.pred_opt();
+        }
+        Ok(count)
+    }
+}
+
+fn add_column_if_missing(db: &rusqlite::Connection, table: &str, column: &str, definition: &str)
+    -> anyhow::Result<()>
+{
+    let exists = db.prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = :name",
+            table))?
+        .exists(named_params!{ ":name": column })
+        .with_context(|| format!("failed to query {:?} table columns", table))?;
+    if !exists {
+        info!("adding {:?} column to {:?} table", column, table);
+        db.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])
+            .with_context(|| format!("failed to add {:?} column to {:?} database table",
+                column, table))?;
+    }
+    Ok(())
+}
+
+/// SQLite can't drop a NOT NULL constraint from a column, so copy the table into a new one without
+/// it.
+fn make_users_email_time_nullable(db: &rusqlite::Connection) -> anyhow::Result<()> {
+    let not_null = db.query_row(
+            "SELECT \"notnull\" FROM pragma_table_info('users') WHERE name = 'email_time_local'",
+            [],
+            |row| row.get::<_, bool>(0),
+        )
+        .context("failed to query 'users' table columns")?;
+    if not_null {
+        info!("making 'email_time_local' column of 'users' table nullable");
+        db.execute_batch(&format!("BEGIN;\
+                CREATE TABLE users_new ({});\
+                INSERT INTO users_new \
+                    (id, username, email, timezone, email_time_local, cc, enabled, days, \
+                        frequency, pgp_key) \
+                    SELECT id, username, email, timezone, email_time_local, cc, enabled, days, \
+                        frequency, pgp_key \
+                    FROM users;\
+                DROP TABLE users;\
+                ALTER TABLE users_new RENAME TO users;\
+                COMMIT;", USERS_COLUMNS))
+            .context("failed to rebuild 'users' database table")?;
+    }
+    Ok(())
+}
+
+/// The JSON list of an entry's sources, with `source` added to the existing list, if any. None if
+/// there's no source.
+fn raw_bodies_json(existing: Option<&str>, source: Option<&EntrySource>)
+    -> anyhow::Result<Option<String>>
+{
+    let Some(source) = source.filter(|source| source.raw.is_some()) else {
+        return Ok(None);
+    };
+    let mut sources: Vec<EntrySource> = match existing {
+        Some(json) => serde_json::from_str(json).context("invalid raw_bodies in database")?,
+        None => vec![],
+    };
+    sources.push(source.clone());
+    Ok(Some(serde_json::to_string(&sources)?))
+}
+
+/// A LIKE pattern (with '\\' as the escape character) matching text containing the word.
+fn like_pattern(word: &str) -> String {
+    let escaped = word.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+fn parse_date(date: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .with_context(|| format!("invalid date {:?} in database", date))
+}
+
+/// Where part of an entry came from: the reply, with its text from before it was cleaned up, so the
+/// entry can be made again from it when the cleanup changes. Stored as JSON.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct EntrySource {
+    /// Message-ID of the reply.
+    pub msgid: String,
+    /// Date the reply was to. With `multi_day_entries`, that may not be the entry's date.
+    pub date: String,
+    /// Plain-text body of the reply, as it was received. None if it isn't to be kept, as when the
+    /// entry is stored encrypted; then the entry can't be made again.
+    pub raw: Option<String>,
+    /// Text added after the cleaned-up body, like references to saved attachments.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub suffix: String,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -121,7 +642,23 @@ pub struct UserRaw {
     pub username: String,
     pub email: String,
     pub timezone: String,
-    pub email_time_local: String,
+    pub email_time_local: Option<String>,
+    #[serde(default)]
+    pub cc: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub days: String,
+    #[serde(default)]
+    pub frequency: String,
+    #[serde(default)]
+    pub pgp_key: String,
+}
+
+/// Users are enabled unless the column says otherwise, including in databases from before it was
+/// added, which read-only connections see without it.
+fn default_enabled() -> bool {
+    true
 }
 
 trait RusqliteResultExt {
@@ -139,3 +676,383 @@ impl<T> RusqliteResultExt for Result<T, rusqlite::Error> {
         )))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frequency::Frequency;
+    use crate::testutil::TempDir;
+    use crate::weekdays::Weekdays;
+
+    fn date(s: &str) -> NaiveDate {
+        parse_date(s).unwrap()
+    }
+
+    #[test]
+    fn test_add_columns() {
+        let dir = TempDir::new("add-columns");
+        let path = dir.path().join("daylog.db");
+        rusqlite::Connection::open(&path).unwrap()
+            .execute_batch("CREATE TABLE users (\
+                id INTEGER PRIMARY KEY NOT NULL,\
+                username STRING UNIQUE NOT NULL,\
+                email STRING NOT NULL,\
+                timezone STRING NOT NULL,\
+                email_time_local STRING NOT NULL\
+            );
+            INSERT INTO users (username, email, timezone, email_time_local)
+                VALUES ('alice', 'alice@example.com', 'UTC', '18:00');")
+            .unwrap();
+
+        let db = Database::open(&path).unwrap();
+        assert!(db.get_user("alice", None).unwrap().cc.is_empty());
+        db.db.execute("UPDATE users SET cc = 'a@example.com, b@example.com'", []).unwrap();
+        assert_eq!(vec!["a@example.com", "b@example.com"], db.get_user("alice", None).unwrap().cc);
+        assert!(db.get_user("alice", None).unwrap().enabled);
+        db.set_user_enabled("alice", false).unwrap();
+        assert!(!db.get_user("alice", None).unwrap().enabled);
+        assert!(db.set_user_enabled("nobody", false).is_err());
+
+        // The old NOT NULL constraint on email_time_local is gone, and the data survived.
+        db.db.execute("UPDATE users SET email_time_local = NULL", []).unwrap();
+        assert!(db.get_user("alice", None).is_err());
+        let alice = db.get_user("alice", Some(DaylogTime::new(7, 15))).unwrap();
+        assert_eq!(DaylogTime::new(7, 15), alice.email_time_local);
+        assert_eq!(vec!["a@example.com", "b@example.com"], alice.cc);
+        assert!(!alice.enabled);
+        assert_eq!(Weekdays::all(), alice.days);
+        db.db.execute("UPDATE users SET days = 'Mon-Fri'", []).unwrap();
+        assert_eq!(Weekdays::parse("MTWTF").unwrap(),
+            db.get_user("alice", Some(DaylogTime::new(7, 15))).unwrap().days);
+        assert_eq!(Frequency::Daily, alice.frequency);
+        db.db.execute("UPDATE users SET frequency = 'weekly:Fri'", []).unwrap();
+        assert_eq!(Frequency::Weekly(chrono::Weekday::Fri),
+            db.get_user("alice", Some(DaylogTime::new(7, 15))).unwrap().frequency);
+
+        // Opening it again doesn't try to add them twice.
+        Database::open(&path).unwrap();
+    }
+
+    #[test]
+    fn test_entry_timezone() {
+        let dir = TempDir::new("entry-timezone");
+        let path = dir.path().join("daylog.db");
+        rusqlite::Connection::open(&path).unwrap()
+            .execute_batch("CREATE TABLE entries (\
+                id INTEGER PRIMARY KEY NOT NULL,\
+                username STRING NOT NULL,\
+                date STRING NOT NULL,\
+                body STRING NOT NULL\
+            );
+            INSERT INTO entries (username, date, body) VALUES ('alice', '2020-01-01', 'old');")
+            .unwrap();
+
+        let mut db = Database::open(&path).unwrap();
+        db.add_user("alice", "alice@example.com", "Asia/Tokyo", "18:00").unwrap();
+        db.add_entry("alice", "2021-01-01", "in Tokyo", None).unwrap();
+        let get = |db: &Database, date: &str|
+            db.get_entry_with_old_timezone("alice", date).unwrap().unwrap();
+        assert_eq!(("old".to_owned(), None), get(&db, "2020-01-01"));
+        assert_eq!(("in Tokyo".to_owned(), None), get(&db, "2021-01-01"));
+
+        // Alice moves.
+        db.db.execute("UPDATE users SET timezone = 'Europe/London'", []).unwrap();
+        assert_eq!(("old".to_owned(), None), get(&db, "2020-01-01"));
+        assert_eq!(("in Tokyo".to_owned(), Some("Asia/Tokyo".to_owned())), get(&db, "2021-01-01"));
+
+        // Adding to an entry keeps the timezone it was started in, but replacing it doesn't.
+        db.add_entry("alice", "2021-01-01", "more", None).unwrap();
+        assert_eq!(Some("Asia/Tokyo".to_owned()), get(&db, "2021-01-01").1);
+        db.set_entry("alice", "2021-01-01", "rewritten in London", None).unwrap();
+        assert_eq!(("rewritten in London".to_owned(), None), get(&db, "2021-01-01"));
+    }
+
+    #[test]
+    fn test_entry_sources() {
+        let dir = TempDir::new("entry-sources");
+        let mut db = Database::open(&dir.path().join("daylog.db")).unwrap();
+        let sources = |db: &Database| db.db.query_row(
+                "SELECT created_at, message_ids FROM entries WHERE date = '2021-07-15'", [],
+                |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, Option<String>>(1)?)))
+            .unwrap();
+        let raw = |db: &Database| db.get_entry_sources("alice", date("2021-07-15"),
+                date("2021-07-15"))
+            .unwrap()
+            .into_iter()
+            .flat_map(|(_, _, sources)| sources.into_iter().filter_map(|source| source.raw))
+            .collect::<Vec<_>>();
+        let src = |msgid: &str, raw: &str| EntrySource {
+            msgid: msgid.to_owned(),
+            date: "2021-07-15".to_owned(),
+            raw: Some(raw.to_owned()),
+            suffix: String::new(),
+        };
+
+        db.add_entry("alice", "2021-07-15", "one", Some(&src("1@example.com", "one
+> quoted")))
+            .unwrap();
+        let (created_at, msgids) = sources(&db);
+        let created_at = chrono::DateTime::parse_from_rfc3339(&created_at.unwrap()).unwrap();
+        assert!((chrono::Utc::now() - created_at.with_timezone(&chrono::Utc)).num_minutes() < 1);
+        assert_eq!(Some("1@example.com".to_owned()), msgids);
+
+        assert_eq!(vec!["one\n> quoted"], raw(&db));
+
+        db.add_entry("alice", "2021-07-15", "two", Some(&src("2@example.com", "two"))).unwrap();
+        assert_eq!(vec!["one\n> quoted", "two"], raw(&db));
+        // Text not from an email can't be made again, so the raw text is dropped.
+        db.add_entry("alice", "2021-07-15", "not from an email", None).unwrap();
+        assert_eq!(Some("1@example.com 2@example.com".to_owned()), sources(&db).1);
+        assert!(raw(&db).is_empty());
+
+        // Either message again is a duplicate.
+        db.add_entry("alice", "2021-07-15", "one", Some(&src("1@example.com", "one"))).unwrap();
+        db.add_entry("alice", "2021-07-15", "two", Some(&src("2@example.com", "two"))).unwrap();
+        assert_eq!(Some("one\ntwo\nnot from an email".to_owned()),
+            db.get_entry("alice", "2021-07-15").unwrap());
+
+        db.set_entry("alice", "2021-07-15", "replaced", Some(&src("3@example.com", "replaced")))
+            .unwrap();
+        assert_eq!(Some("3@example.com".to_owned()), sources(&db).1);
+        assert_eq!(vec!["replaced"], raw(&db));
+        db.set_entry("alice", "2021-07-15", "by hand", None).unwrap();
+        assert_eq!(None, sources(&db).1);
+        assert!(raw(&db).is_empty());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = TempDir::new("read-only");
+        let path = dir.path().join("daylog.db");
+        assert!(Database::open_read_only(&path).is_err(), "shouldn't create the database");
+        assert!(!path.exists());
+
+        let mut db = Database::open(&path).unwrap();
+        db.add_user("alice", "alice@example.com", "UTC", "18:00").unwrap();
+        db.add_entry("alice", "2021-07-15", "one", None).unwrap();
+
+        let mut read_only = Database::open_read_only(&path).unwrap();
+        assert_eq!(Some("one".to_owned()), read_only.get_entry("alice", "2021-07-15").unwrap());
+        assert_eq!(1, read_only.get_all_users(None).unwrap().iter().count());
+        assert!(read_only.add_entry("alice", "2021-07-16", "two", None).is_err());
+        assert!(read_only.set_user_enabled("alice", false).is_err());
+
+        // The read-write connection is unaffected.
+        db.add_entry("alice", "2021-07-16", "two", None).unwrap();
+        assert_eq!(Some("two".to_owned()), read_only.get_entry("alice", "2021-07-16").unwrap());
+    }
+
+    #[test]
+    fn test_read_only_old_schema() {
+        // A database from before any columns were added, which opening read-only doesn't upgrade.
+        let dir = TempDir::new("read-only-old-schema");
+        let path = dir.path().join("daylog.db");
+        rusqlite::Connection::open(&path).unwrap()
+            .execute_batch("CREATE TABLE entries (\
+                id INTEGER PRIMARY KEY NOT NULL,\
+                username STRING NOT NULL,\
+                date STRING NOT NULL,\
+                body STRING NOT NULL\
+            );
+            CREATE TABLE users (\
+                id INTEGER PRIMARY KEY NOT NULL,\
+                username STRING UNIQUE NOT NULL,\
+                email STRING NOT NULL,\
+                timezone STRING NOT NULL,\
+                email_time_local STRING NOT NULL\
+            );
+            INSERT INTO users (username, email, timezone, email_time_local)
+                VALUES ('alice', 'alice@example.com', 'UTC', '18:00');
+            INSERT INTO entries (username, date, body)
+                VALUES ('alice', '2021-07-14', 'one'), ('alice', '2021-07-15', 'two');")
+            .unwrap();
+
+        let db = Database::open_read_only(&path).unwrap();
+        let users = db.get_all_users(None).unwrap();
+        let alice = users.iter().next().unwrap();
+        assert!(alice.enabled);
+        assert!(alice.cc.is_empty());
+        assert_eq!(None, alice.pgp_key);
+        assert!(db.get_user("alice", None).unwrap().enabled);
+        assert_eq!(2, db.count_entries("alice").unwrap());
+        assert_eq!(Some(date("2021-07-14")), db.first_entry_date("alice").unwrap());
+        assert_eq!(Some(date("2021-07-15")), db.last_entry_date("alice").unwrap());
+        assert_eq!(2, db.streak("alice", date("2021-07-15")).unwrap());
+        assert_eq!(vec![date("2021-07-16")],
+            db.missing_dates("alice", date("2021-07-14"), date("2021-07-16")).unwrap());
+        assert_eq!(vec![(date("2021-07-15"), "two".to_owned())], db.search("alice", "TWO").unwrap());
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let dir = TempDir::new("concurrent-writes");
+        let path = dir.path().join("daylog.db");
+        let first = Database::open(&path).unwrap();
+
+        // Hold the write lock for a bit while another connection tries to write.
+        first.db.execute_batch("BEGIN IMMEDIATE").unwrap();
+        first.db.execute("INSERT INTO entries (username, date, body) \
+            VALUES ('alice', '2021-07-15', 'first')", []).unwrap();
+
+        let second = std::thread::spawn({
+            let path = path.clone();
+            move || {
+                let mut second = Database::open(&path).unwrap();
+                second.add_entry("bob", "2021-07-15", "second", None).unwrap();
+            }
+        });
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        first.db.execute_batch("COMMIT").unwrap();
+        second.join().unwrap();
+
+        assert_eq!(Some("first".to_owned()), first.get_entry("alice", "2021-07-15").unwrap());
+        assert_eq!(Some("second".to_owned()), first.get_entry("bob", "2021-07-15").unwrap());
+    }
+
+    #[test]
+    fn test_backup() {
+        let dir = TempDir::new("backup");
+        let mut db = Database::open(&dir.path().join("daylog.db")).unwrap();
+        db.add_user("alice", "alice@example.com", "UTC", "18:00").unwrap();
+        db.add_entry("alice", "2021-07-15", "first", None).unwrap();
+        db.add_entry("alice", "2021-07-16", "second", None).unwrap();
+
+        // Replaces whatever was there before.
+        let dest = dir.path().join("backup.db");
+        std::fs::write(&dest, "not a database").unwrap();
+
+        // The entries are still only in the WAL, since the connection is open.
+        db.backup(&dest).unwrap();
+        db.add_entry("alice", "2021-07-17", "after the backup", None).unwrap();
+
+        let copy = Database::open_read_only(&dest).unwrap();
+        assert_eq!(2, copy.count_entries("alice").unwrap());
+        assert_eq!(Some("first".to_owned()), copy.get_entry("alice", "2021-07-15").unwrap());
+        assert_eq!(Some("second".to_owned()), copy.get_entry("alice", "2021-07-16").unwrap());
+        assert_eq!(None, copy.get_entry("alice", "2021-07-17").unwrap());
+        assert_eq!("alice@example.com", copy.get_user("alice", None).unwrap().email);
+        assert!(!dir.path().join("backup.db.tmp").exists());
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let dir = TempDir::new("maintenance");
+        let mut db = Database::open(&dir.path().join("daylog.db")).unwrap();
+        assert!(db.integrity_check().unwrap().is_empty());
+
+        for day in 1 ..= 28 {
+            db.add_entry("alice", &format!("2021-02-{:02}", day), &"x".repeat(10_000), None)
+                .unwrap();
+        }
+        for day in 1 ..= 28 {
+            db.delete_entry("alice", &format!("2021-02-{:02}", day)).unwrap();
+        }
+        db.add_entry("alice", "2021-03-01", "kept", None).unwrap();
+        let free_pages = || db.db.query_row("PRAGMA freelist_count", [], |row| row.get::<_, i64>(0))
+            .unwrap();
+        assert_ne!(0, free_pages());
+
+        db.vacuum().unwrap();
+        assert_eq!(0, free_pages());
+        assert!(db.integrity_check().unwrap().is_empty());
+        assert_eq!(Some("kept".to_owned()), db.get_entry("alice", "2021-03-01").unwrap());
+    }
+
+    #[test]
+    fn test_entry_stats() {
+        let dir = TempDir::new("entry-stats");
+        let mut db = Database::open(&dir.path().join("daylog.db")).unwrap();
+
+        assert_eq!(0, db.count_entries("alice").unwrap());
+        assert_eq!(None, db.first_entry_date("alice").unwrap());
+        assert_eq!(None, db.last_entry_date("alice").unwrap());
+        assert_eq!(0, db.streak("alice", date("2020-03-02")).unwrap());
+
+        for day in ["2020-02-20", "2020-02-28", "2020-02-29", "2020-03-01", "2020-03-02"] {
+            db.add_entry("alice", day, "stuff", None).unwrap();
+        }
+        db.add_entry("alice", "2020-03-02", "more stuff", None).unwrap();
+        db.add_entry("bob", "2020-02-27", "other stuff", None).unwrap();
+
+        assert_eq!(5, db.count_entries("alice").unwrap());
+        assert_eq!(Some(date("2020-02-20")), db.first_entry_date("alice").unwrap());
+        assert_eq!(Some(date("2020-03-02")), db.last_entry_date("alice").unwrap());
+
+        assert_eq!(4, db.streak("alice", date("2020-03-02")).unwrap());
+        assert_eq!(2, db.streak("alice", date("2020-02-29")).unwrap());
+        assert_eq!(0, db.streak("alice", date("2020-03-03")).unwrap());
+        assert_eq!(0, db.streak("alice", date("2020-02-27")).unwrap());
+    }
+
+    #[test]
+    fn test_append_and_replace() {
+        let dir = TempDir::new("append-replace");
+        let mut db = Database::open(&dir.path().join("daylog.db")).unwrap();
+
+        db.add_entry("alice", "2020-02-27", "one", None).unwrap();
+        db.add_entry("alice", "2020-02-27", "two", None).unwrap();
+        assert_eq!(Some("one\ntwo".to_owned()), db.get_entry("alice", "2020-02-27").unwrap());
+
+        db.set_entry("alice", "2020-02-27", "fixed", None).unwrap();
+        assert_eq!(Some("fixed".to_owned()), db.get_entry("alice", "2020-02-27").unwrap());
+
+        db.set_entry("alice", "2020-02-28", "new", None).unwrap();
+        assert_eq!(Some("new".to_owned()), db.get_entry("alice", "2020-02-28").unwrap());
+        assert_eq!(2, db.count_entries("alice").unwrap());
+    }
+
+    #[test]
+    fn test_delete_entry() {
+        let dir = TempDir::new("delete-entry");
+        let mut db = Database::open(&dir.path().join("daylog.db")).unwrap();
+        db.add_entry("alice", "2020-02-27", "one", None).unwrap();
+        db.add_entry("alice", "2020-02-28", "two", None).unwrap();
+
+        assert!(db.delete_entry("alice", "2020-02-27").unwrap());
+        assert_eq!(None, db.get_entry("alice", "2020-02-27").unwrap());
+        assert_eq!(Some("two".to_owned()), db.get_entry("alice", "2020-02-28").unwrap());
+
+        assert!(!db.delete_entry("alice", "2020-02-27").unwrap());
+        assert!(!db.delete_entry("bob", "2020-02-28").unwrap());
+        assert_eq!(1, db.count_entries("alice").unwrap());
+    }
+
+    #[test]
+    fn test_search() {
+        let dir = TempDir::new("search");
+        let mut db = Database::open(&dir.path().join("daylog.db")).unwrap();
+        db.add_entry("alice", "2020-02-28", "Flew to Portugal!", None).unwrap();
+        db.add_entry("alice", "2020-02-27", "packed for the trip to portugal", None).unwrap();
+        db.add_entry("alice", "2020-03-01", "back home; 100% tired", None).unwrap();
+        db.add_entry("bob", "2020-02-28", "portugal, eh?", None).unwrap();
+        db.add_entry("alice", "2020-03-02", "-----BEGIN PGP MESSAGE-----\n\nhQEMA+Portugal\n\
+            -----END PGP MESSAGE-----", None).unwrap();
+
+        let dates = |query: &str| db.search("alice", query).unwrap().into_iter()
+            .map(|(date, _)| date.format("%Y-%m-%d").to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["2020-02-27", "2020-02-28"], dates("PORTUGAL"));
+        assert_eq!(vec!["2020-02-27"], dates("trip portugal"));
+        assert_eq!(vec!["2020-03-01"], dates("100%"));
+        assert!(dates("0%t").is_empty());
+        assert!(dates("back_home").is_empty());
+        assert!(db.search("alice", "  ").is_err());
+    }
+
+    #[test]
+    fn test_missing_dates() {
+        let dir = TempDir::new("missing-dates");
+        let mut db = Database::open(&dir.path().join("daylog.db")).unwrap();
+        for day in ["2020-02-27", "2020-02-29", "2020-03-02"] {
+            db.add_entry("alice", day, "stuff", None).unwrap();
+        }
+        db.add_entry("bob", "2020-02-28", "other stuff", None).unwrap();
+
+        assert_eq!(vec![date("2020-02-28"), date("2020-03-01")],
+            db.missing_dates("alice", date("2020-02-27"), date("2020-03-02")).unwrap());
+        assert_eq!(vec![date("2020-02-26"), date("2020-02-28"), date("2020-03-01"), date("2020-03-03")],
+            db.missing_dates("alice", date("2020-02-26"), date("2020-03-03")).unwrap());
+        assert!(db.missing_dates("alice", date("2020-02-29"), date("2020-02-29")).unwrap().is_empty());
+        assert!(db.missing_dates("alice", date("2020-03-02"), date("2020-02-27")).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,51 @@
+use crate::DeleteArgs;
+use crate::config::Config;
+use crate::db::Database;
+use std::io::{BufRead, Write};
+
+pub fn delete(config: &Config, args: DeleteArgs) -> anyhow::Result<()> {
+    let mut db = Database::open(&config.database_path)?;
+    let date = args.date.format("%Y-%m-%d").to_string();
+
+    if db.get_entry(&args.username, &date)?.is_none() {
+        anyhow::bail!("there is no entry for {} on {}", args.username, date);
+    }
+
+    if !args.yes {
+        print!("Delete the entry for {} on {}? This can't be undone. [y/N] ", args.username, date);
+        std::io::stdout().flush()?;
+        if !confirmed(std::io::stdin().lock())? {
+            println!("not deleted");
+            return Ok(());
+        }
+    }
+
+    if db.delete_entry(&args.username, &date)? {
+        say!("deleted the entry for {} on {}", args.username, date);
+    } else {
+        // someone else got to it first
+        say!("the entry for {} on {} was already gone", args.username, date);
+    }
+    Ok(())
+}
+
+/// Read a line of input, and return whether it's a "yes".
+fn confirmed(mut input: impl BufRead) -> anyhow::Result<bool> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    Ok(matches!(line.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_confirmed() {
+        assert!(confirmed(&b"y\n"[..]).unwrap());
+        assert!(confirmed(&b" Yes \n"[..]).unwrap());
+        assert!(!confirmed(&b"\n"[..]).unwrap());
+        assert!(!confirmed(&b"no\n"[..]).unwrap());
+        assert!(!confirmed(&b""[..]).unwrap());
+    }
+}
@@ -0,0 +1,54 @@
+/// Produce a unified diff (with the whole text as context) between two texts, line by line.
+pub fn unified_diff(old_name: &str, old: &str, new_name: &str, new: &str) -> String {
+    let a = old.lines().collect::<Vec<_>>();
+    let b = new.lines().collect::<Vec<_>>();
+
+    // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0 .. a.len()).rev() {
+        for j in (0 .. b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n@@ -1,{} +1,{} @@\n", old_name, new_name, a.len(), b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out += &format!(" {}\n", a[i]);
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out += &format!("-{}\n", a[i]);
+            i += 1;
+        } else {
+            out += &format!("+{}\n", b[j]);
+            j += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        let old = "hello\n\nworld\n> quoted\n-- \nsig\n";
+        let new = "hello\n\nworld!";
+        assert_eq!("--- a\n+++ b\n@@ -1,6 +1,3 @@\n \
+             hello\n \
+            \n\
+            -world\n\
+            -> quoted\n\
+            --- \n\
+            -sig\n\
+            +world!\n",
+            unified_diff("a", old, "b", new));
+    }
+}
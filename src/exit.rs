@@ -0,0 +1,60 @@
+//! Exit codes, so cron wrappers and monitoring can tell kinds of failures apart.
+
+use crate::send::SendmailFailed;
+
+/// Anything not covered by a more specific code.
+pub const FAILURE: u8 = 1;
+
+// 2 is used by the argument parser for bad command lines.
+
+/// The config file couldn't be loaded, or `check` found a problem.
+pub const CONFIG: u8 = 3;
+
+/// The database couldn't be opened, read, written, or backed up, or it failed its integrity check.
+pub const DATABASE: u8 = 4;
+
+/// Sending an email failed.
+pub const SEND: u8 = 5;
+
+/// Reading or processing incoming mail failed, or with `ingest --stdin`, the message wasn't added.
+pub const INCOMING_MAIL: u8 = 6;
+
+/// The exit code for an error from an operation whose failures are of the given kind by default.
+/// Database and sendmail errors get their own codes wherever they happen.
+pub fn code(e: &anyhow::Error, default: u8) -> u8 {
+    // The innermost cause is the most specific.
+    e.chain()
+        .filter_map(|cause| {
+            if cause.is::<rusqlite::Error>() || cause.is::<serde_rusqlite::Error>() {
+                Some(DATABASE)
+            } else if cause.is::<SendmailFailed>() {
+                Some(SEND)
+            } else {
+                None
+            }
+        })
+        .last()
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_code() {
+        assert_eq!(INCOMING_MAIL, code(&anyhow!("bad mail"), INCOMING_MAIL));
+
+        let db = rusqlite::Connection::open_in_memory().unwrap()
+            .execute("not sql", [])
+            .context("failed to add entry")
+            .unwrap_err();
+        assert_eq!(DATABASE, code(&db, INCOMING_MAIL));
+
+        let sendmail = anyhow::Error::new(SendmailFailed { code: Some(75) })
+            .context("failed to send ack");
+        assert_eq!(SEND, code(&sendmail, INCOMING_MAIL));
+        assert_eq!(FAILURE, code(&anyhow!("other"), FAILURE));
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{anyhow, bail};
+use chrono::{Datelike, Duration, Months, NaiveDate, Weekday};
+
+/// How often a user gets an email.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+
+    /// Once a week, on the given day.
+    Weekly(Weekday),
+
+    /// Once a month, on the given day of the month, or the last day of months too short to have
+    /// it.
+    Monthly(u32),
+}
+
+impl Frequency {
+    /// Parse "daily", "weekly:<day of the week>", or "monthly:<day of the month>". Weekly and
+    /// monthly default to Sunday and the 1st. An empty string means daily.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let (kind, day) = match s.split_once(':') {
+            Some((kind, day)) => (kind.trim(), Some(day.trim())),
+            None => (s, None),
+        };
+        match (kind.to_ascii_lowercase().as_str(), day) {
+            ("" | "daily", None) => Ok(Frequency::Daily),
+            ("weekly", None) => Ok(Frequency::Weekly(Weekday::Sun)),
+            ("weekly", Some(day)) => day.parse::<Weekday>()
+                .map(Frequency::Weekly)
+                .map_err(|_| anyhow!("{:?} is not a day of the week", day)),
+            ("monthly", None) => Ok(Frequency::Monthly(1)),
+            ("monthly", Some(day)) => match day.parse::<u32>() {
+                Ok(n @ 1 ..= 31) => Ok(Frequency::Monthly(n)),
+                _ => bail!("{:?} is not a day of the month", day),
+            },
+            _ => bail!("unknown frequency {:?}; expected daily, weekly, or monthly", s),
+        }
+    }
+
+    /// Whether an email is due on the given (local) date.
+    pub fn includes(self, date: NaiveDate) -> bool {
+        match self {
+            Frequency::Daily => true,
+            Frequency::Weekly(day) => date.weekday() == day,
+            Frequency::Monthly(day) => date.day() == day.min(days_in_month(date)),
+        }
+    }
+
+    /// The date of the email before the one on the given date, for recapping the entries since
+    /// then. None for daily emails, which don't need one.
+    pub fn previous(self, date: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Frequency::Daily => None,
+            Frequency::Weekly(_) => Some(date - Duration::weeks(1)),
+            Frequency::Monthly(day) => {
+                let month = date.with_day(1)?.checked_sub_months(Months::new(1))?;
+                month.with_day(day.min(days_in_month(month)))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Frequency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Frequency::Daily => f.write_str("daily"),
+            Frequency::Weekly(day) => write!(f, "weekly:{}", day),
+            Frequency::Monthly(day) => write!(f, "monthly:{}", day),
+        }
+    }
+}
+
+fn days_in_month(date: NaiveDate) -> u32 {
+    let first = date.with_day(1).unwrap();
+    let next = first.checked_add_months(Months::new(1)).unwrap();
+    (next - first).num_days() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Frequency::Daily, Frequency::parse("").unwrap());
+        assert_eq!(Frequency::Daily, Frequency::parse("Daily").unwrap());
+        assert_eq!(Frequency::Weekly(Weekday::Sun), Frequency::parse("weekly").unwrap());
+        assert_eq!(Frequency::Weekly(Weekday::Fri), Frequency::parse("weekly: friday").unwrap());
+        assert_eq!(Frequency::Monthly(1), Frequency::parse("monthly").unwrap());
+        assert_eq!(Frequency::Monthly(31), Frequency::parse("monthly:31").unwrap());
+        assert!(Frequency::parse("hourly").is_err());
+        assert!(Frequency::parse("daily:1").is_err());
+        assert!(Frequency::parse("weekly:funday").is_err());
+        assert!(Frequency::parse("monthly:0").is_err());
+        assert!(Frequency::parse("monthly:32").is_err());
+        assert_eq!("weekly:Fri", Frequency::Weekly(Weekday::Fri).to_string());
+    }
+
+    #[test]
+    fn test_daily() {
+        assert!(Frequency::Daily.includes(date(2021, 7, 15)));
+        assert_eq!(None, Frequency::Daily.previous(date(2021, 7, 15)));
+    }
+
+    #[test]
+    fn test_weekly() {
+        let weekly = Frequency::Weekly(Weekday::Sun);
+        let days = date(2021, 7, 12).iter_days().take(14)
+            .filter(|&d| weekly.includes(d))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![date(2021, 7, 18), date(2021, 7, 25)], days);
+        assert_eq!(Some(date(2021, 7, 11)), weekly.previous(date(2021, 7, 18)));
+    }
+
+    #[test]
+    fn test_monthly() {
+        let monthly = Frequency::Monthly(15);
+        assert!(monthly.includes(date(2021, 7, 15)));
+        assert!(!monthly.includes(date(2021, 7, 16)));
+        assert_eq!(Some(date(2021, 6, 15)), monthly.previous(date(2021, 7, 15)));
+
+        // The 31st falls back to the last day of shorter months.
+        let monthly = Frequency::Monthly(31);
+        assert!(monthly.includes(date(2021, 1, 31)));
+        assert!(monthly.includes(date(2021, 2, 28)));
+        assert!(monthly.includes(date(2024, 2, 29)));
+        assert!(!monthly.includes(date(2024, 2, 28)));
+        assert!(monthly.includes(date(2021, 4, 30)));
+        assert_eq!(Some(date(2021, 2, 28)), monthly.previous(date(2021, 3, 31)));
+        assert_eq!(Some(date(2021, 3, 31)), monthly.previous(date(2021, 4, 30)));
+    }
+}
@@ -0,0 +1,33 @@
+use crate::{GapsArgs, todays_date};
+use crate::config::Config;
+use crate::db::Database;
+
+pub fn gaps(config: &Config, args: GapsArgs) -> anyhow::Result<()> {
+    let db = Database::open_read_only(&config.database_path)?;
+    let user = db.get_user(&args.username, config.default_email_time)?;
+
+    let from = match args.from {
+        Some(date) => date,
+        None => match db.first_entry_date(&user.username)? {
+            Some(date) => date,
+            None => {
+                println!("{} has no entries", user.username);
+                return Ok(());
+            }
+        },
+    };
+    // Today's entry probably hasn't been written yet, so don't count it as missing.
+    let to = match args.to {
+        Some(date) => date,
+        None => todays_date(&user.timezone).pred_opt().unwrap_or(from).max(from),
+    };
+
+    let missing = db.missing_dates(&user.username, from, to)?;
+    for date in &missing {
+        println!("{}", date.format("%Y-%m-%d"));
+    }
+    let days = if to >= from { (to - from).num_days() + 1 } else { 0 };
+    println!("{} of {} days from {} to {} have no entry", missing.len(), days,
+        from.format("%Y-%m-%d"), to.format("%Y-%m-%d"));
+    Ok(())
+}
@@ -0,0 +1,302 @@
+//! A small HTML-to-text conversion, for replies from mail clients that don't send a plain text
+//! version. It only needs to get the user's words out, in roughly the right lines, with quoted
+//! parts marked the way plain text replies do, so the usual quote stripping works on them.
+
+/// Elements whose contents aren't shown.
+const HIDDEN: &[&str] = &["head", "script", "style", "title"];
+
+/// Elements that go on their own line.
+const LINE: &[&str] = &["div", "li", "tr", "hr", "section", "article", "header", "footer",
+    "center"];
+
+/// Elements that go on their own line with a blank line around them.
+const PARAGRAPH: &[&str] = &["p", "h1", "h2", "h3", "h4", "h5", "h6", "ul", "ol", "table", "pre",
+    "blockquote"];
+
+/// Convert HTML to plain text. Block elements become lines, `<blockquote>` contents are prefixed
+/// with "> ", and everything inside tags is dropped.
+pub fn html_to_text(html: &str) -> String {
+    let mut w = Writer::new();
+    let mut hidden: Option<String> = None;
+    let mut pre = 0usize;
+    let mut rest = html;
+    while !rest.is_empty() {
+        let Some(lt) = rest.find('<') else {
+            if hidden.is_none() {
+                w.text(&decode_entities(rest), pre > 0);
+            }
+            break;
+        };
+        if hidden.is_none() {
+            w.text(&decode_entities(&rest[.. lt]), pre > 0);
+        }
+        rest = &rest[lt ..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map(|end| &comment[end + 3 ..]).unwrap_or("");
+            continue;
+        }
+        let Some(tag) = Tag::parse(rest) else {
+            // Not a tag after all, just a stray '<'.
+            if hidden.is_none() {
+                w.text("<", pre > 0);
+            }
+            rest = &rest[1 ..];
+            continue;
+        };
+        rest = &rest[tag.len ..];
+
+        if let Some(name) = &hidden {
+            if tag.closing && tag.name == *name {
+                hidden = None;
+            }
+            continue;
+        }
+        let name = tag.name.as_str();
+        if HIDDEN.contains(&name) && !tag.closing {
+            hidden = Some(tag.name);
+        } else if name == "br" {
+            w.newline();
+        } else if LINE.contains(&name) {
+            w.end_line();
+            if name == "li" && !tag.closing {
+                w.text("- ", true);
+            }
+        } else if PARAGRAPH.contains(&name) {
+            // The blank lines around a quote are outside of it.
+            if name == "blockquote" && tag.closing {
+                w.quote_depth = w.quote_depth.saturating_sub(1);
+            }
+            w.blank_line();
+            match (name, tag.closing) {
+                ("blockquote", false) => w.quote_depth += 1,
+                ("pre", false) => pre += 1,
+                ("pre", true) => pre = pre.saturating_sub(1),
+                _ => (),
+            }
+        }
+    }
+
+    let text = w.out.trim_start_matches('\n').trim_end();
+    if text.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", text)
+    }
+}
+
+struct Writer {
+    out: String,
+    quote_depth: usize,
+    /// Nothing, not even the quote prefix, has been written on the current line yet.
+    at_line_start: bool,
+    /// Line breaks since the last text.
+    newlines: usize,
+    /// Whitespace was skipped, so a space goes before the next text on this line.
+    space: bool,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Self {
+            out: String::new(),
+            quote_depth: 0,
+            at_line_start: true,
+            newlines: 0,
+            space: false,
+        }
+    }
+
+    /// Add text. Outside of `<pre>`, runs of whitespace become single spaces, and whitespace at
+    /// the start of a line is dropped.
+    fn text(&mut self, text: &str, pre: bool) {
+        for c in text.chars() {
+            if pre && c == '\n' {
+                self.newline();
+                continue;
+            }
+            if !pre && c.is_whitespace() {
+                self.space = !self.at_line_start;
+                continue;
+            }
+            if self.at_line_start {
+                self.prefix();
+            } else if self.space {
+                self.out.push(' ');
+            }
+            self.space = false;
+            self.out.push(c);
+            self.newlines = 0;
+        }
+    }
+
+    fn prefix(&mut self) {
+        if self.quote_depth > 0 {
+            self.out.push_str(&">".repeat(self.quote_depth));
+            self.out.push(' ');
+        }
+        self.at_line_start = false;
+    }
+
+    fn newline(&mut self) {
+        if self.at_line_start && self.quote_depth > 0 {
+            self.out.push_str(&">".repeat(self.quote_depth));
+        }
+        self.out.push('\n');
+        self.at_line_start = true;
+        self.space = false;
+        self.newlines += 1;
+    }
+
+    /// Start a new line, unless already at the start of one.
+    fn end_line(&mut self) {
+        if !self.at_line_start {
+            self.newline();
+        }
+    }
+
+    /// Make sure there's a blank line before whatever comes next.
+    fn blank_line(&mut self) {
+        self.end_line();
+        if self.newlines < 2 && !self.out.is_empty() {
+            self.newline();
+        }
+    }
+}
+
+/// An HTML start or end tag.
+struct Tag {
+    /// Element name, in lowercase.
+    name: String,
+    closing: bool,
+    /// Length of the tag's text, including the angle brackets.
+    len: usize,
+}
+
+impl Tag {
+    /// Parse the tag at the start of the text, which starts with '<'. None if it isn't one.
+    fn parse(text: &str) -> Option<Self> {
+        let inner = &text[1 ..];
+        let (closing, inner) = match inner.strip_prefix('/') {
+            Some(inner) => (true, inner),
+            None => (false, inner),
+        };
+        let first = inner.chars().next()?;
+        if !(first.is_ascii_alphabetic() || first == '!' || first == '?') {
+            return None;
+        }
+        let name = inner.chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+
+        // Find the end of the tag, skipping over any '>' in quoted attribute values.
+        let mut quote = None;
+        for (i, c) in text.char_indices() {
+            match (quote, c) {
+                (None, '"' | '\'') => quote = Some(c),
+                (Some(q), _) if c == q => quote = None,
+                (None, '>') => return Some(Self { name, closing, len: i + 1 }),
+                _ => (),
+            }
+        }
+        // Unterminated: treat the rest as part of the tag.
+        Some(Self { name, closing, len: text.len() })
+    }
+}
+
+/// Replace character references like "&amp;" and "&#8217;" with the characters they stand for.
+/// Unrecognized ones are left alone.
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out += &rest[.. amp];
+        rest = &rest[amp ..];
+        let decoded = rest[1 ..].find(';')
+            .filter(|&semi| semi <= 10)
+            .and_then(|semi| entity(&rest[1 .. semi + 1]).map(|c| (c, semi + 2)));
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len ..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1 ..];
+            }
+        }
+    }
+    out + rest
+}
+
+fn entity(name: &str) -> Option<char> {
+    if let Some(num) = name.strip_prefix('#') {
+        let code = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => num.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    Some(match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        "ndash" => '\u{2013}',
+        "mdash" => '\u{2014}',
+        "lsquo" => '\u{2018}',
+        "rsquo" => '\u{2019}',
+        "ldquo" => '\u{201c}',
+        "rdquo" => '\u{201d}',
+        "hellip" => '\u{2026}',
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        assert_eq!("one\ntwo\n\nthree\n",
+            html_to_text("<div dir=\"ltr\">one<div>two</div><div><br></div><div>three</div></div>"));
+        assert_eq!("first paragraph\n\nsecond\nline\n",
+            html_to_text("<p>first\n   paragraph</p><p>second<br>\nline</p>"));
+        assert_eq!("- eggs\n- milk\n", html_to_text("<ul>\n<li>eggs</li>\n<li>milk</li>\n</ul>"));
+        assert_eq!("", html_to_text("<div><br></div>"));
+    }
+
+    #[test]
+    fn test_hidden() {
+        assert_eq!("hi\n", html_to_text("<html><head><title>x</title><style>p { color: red }\
+            </style></head><body><!-- a <b>comment</b> --><p>hi</p></body></html>"));
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!("mine\n\nOn Thu, Daylog <d@example.com> wrote:\n\n> What did you do?\n>\n\
+            >> older\n>\n> --\n\nafter\n",
+            html_to_text("mine<br><br>On Thu, Daylog &lt;<a href=\"mailto:d@example.com\">\
+                d@example.com</a>&gt; wrote:<br><blockquote type=\"cite\">What did you do?\
+                <blockquote>older</blockquote>-- <br></blockquote>after"));
+    }
+
+    #[test]
+    fn test_pre() {
+        assert_eq!("a\n\n  x = 1\n  y = 2\n\nb\n",
+            html_to_text("a<pre>  x = 1\n  y = 2</pre>b"));
+    }
+
+    #[test]
+    fn test_entities() {
+        assert_eq!("Fish & chips \u{2014} \u{2019}twas <great> \u{e9} &bogus; & done\n",
+            html_to_text("Fish &amp; chips &mdash; &#8217;twas &lt;great&gt; &#xE9; &bogus; \
+                & done"));
+        assert_eq!("a < b, c > d\n", html_to_text("a < b, c > d"));
+        assert_eq!("link\n", html_to_text("<a title=\"x > y\" href='z'>link</a>"));
+    }
+}
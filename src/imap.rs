@@ -0,0 +1,503 @@
+use anyhow::{anyhow, bail, Context};
+use chrono::NaiveDate;
+use crate::config::ImapConfig;
+use crate::mail::{Mail, MailProcessAction, MailSource, RunStats};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Give up on a read after this many messages in a row couldn't be fetched or updated, even after
+/// reconnecting; the server is probably down.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// The IMAP operations daylog needs, on a connection with the mailbox selected, so they can be
+/// faked in tests.
+pub trait ImapSession {
+    /// UIDs of unseen messages, optionally only those received since the given date, in order.
+    fn unseen(&mut self, since: Option<NaiveDate>) -> anyhow::Result<Vec<u32>>;
+
+    /// The whole raw message, without marking it as seen.
+    fn fetch(&mut self, uid: u32) -> anyhow::Result<Vec<u8>>;
+
+    fn mark_seen(&mut self, uid: u32) -> anyhow::Result<()>;
+
+    fn move_to(&mut self, uid: u32, mailbox: &str) -> anyhow::Result<()>;
+
+    fn logout(&mut self) -> anyhow::Result<()>;
+}
+
+type Connector = Box<dyn FnMut() -> anyhow::Result<Box<dyn ImapSession>>>;
+
+pub struct DaylogImap {
+    connect: Connector,
+    archive_mailbox: Option<String>,
+    since: Option<NaiveDate>,
+    limit: Option<u64>,
+}
+
+impl DaylogImap {
+    /// Messages received before `since` are left alone. Each read stops after processing `limit`
+    /// messages, leaving the rest for the next one.
+    pub fn open(config: &ImapConfig, since: Option<NaiveDate>, limit: Option<u64>) -> Self {
+        let connect_config = config.clone();
+        Self::with_connector(Box::new(move || connect(&connect_config)),
+            config.archive_mailbox.clone(), since, limit)
+    }
+
+    fn with_connector(
+        connect: Connector,
+        archive_mailbox: Option<String>,
+        since: Option<NaiveDate>,
+        limit: Option<u64>,
+    ) -> Self {
+        Self {
+            connect,
+            archive_mailbox,
+            since,
+            limit,
+        }
+    }
+
+    /// Run an operation, and if it fails, reconnect and try it once more.
+    fn retry<T>(
+        &mut self,
+        session: &mut Box<dyn ImapSession>,
+        mut op: impl FnMut(&mut dyn ImapSession) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        match op(session.as_mut()) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                warn!("IMAP operation failed, reconnecting: {:#}", e);
+                *session = (self.connect)().context("failed to reconnect to IMAP server")?;
+                op(session.as_mut())
+            }
+        }
+    }
+
+    fn apply(&mut self, session: &mut Box<dyn ImapSession>, uid: u32, action: MailProcessAction)
+        -> anyhow::Result<()>
+    {
+        match action {
+            MailProcessAction::Remove => match self.archive_mailbox.clone() {
+                Some(archive) => self.retry(session, |s| s.move_to(uid, &archive)),
+                None => self.retry(session, |s| s.mark_seen(uid)),
+            },
+            MailProcessAction::Keep => self.retry(session, |s| s.mark_seen(uid)),
+            MailProcessAction::LeaveUnread => Ok(()),
+        }
+    }
+}
+
+impl MailSource for DaylogImap {
+    fn read(&mut self, mut handler: Box<dyn FnMut(Mail) -> MailProcessAction + '_>)
+        -> anyhow::Result<RunStats>
+    {
+        let mut stats = RunStats::default();
+        let mut session = (self.connect)()?;
+        let since = self.since;
+        let uids = self.retry(&mut session, |s| s.unseen(since))
+            .context("failed to search for unseen messages")?;
+
+        let mut failures = 0;
+        for uid in uids {
+            if self.limit.is_some_and(|limit| stats.num_processed >= limit) {
+                info!("stopping after processing {} messages", stats.num_processed);
+                break;
+            }
+            if failures >= MAX_CONSECUTIVE_FAILURES {
+                bail!("giving up after {} failures in a row", failures);
+            }
+
+            let raw = match self.retry(&mut session, |s| s.fetch(uid)) {
+                Ok(raw) => raw,
+                Err(e) => {
+                    error!("failed to fetch IMAP message {}, skipping it: {:#}", uid, e);
+                    failures += 1;
+                    continue;
+                }
+            };
+
+            let (action, parsed) = match mailparse::parse_mail(&raw)
+                .map_err(anyhow::Error::from)
+                .and_then(Mail::parse)
+            {
+                Ok(mail) => {
+                    stats.num_processed += 1;
+                    (handler(mail), true)
+                }
+                Err(e) => {
+                    // Mark it as seen so it isn't fetched again.
+                    error!("failed to parse IMAP message {}: {:#}", uid, e);
+                    (MailProcessAction::Keep, false)
+                }
+            };
+
+            if let Err(e) = self.apply(&mut session, uid, action) {
+                // If it was processed, it'll be processed again next time.
+                error!("failed to update IMAP message {} after processing it: {:#}", uid, e);
+                failures += 1;
+                continue;
+            }
+            failures = 0;
+            match action {
+                _ if !parsed => stats.num_failed += 1,
+                MailProcessAction::Remove => stats.num_removed += 1,
+                MailProcessAction::Keep => stats.num_kept += 1,
+                MailProcessAction::LeaveUnread => stats.num_left_unread += 1,
+            }
+        }
+
+        if let Err(e) = session.logout() {
+            debug!("failed to log out of IMAP server: {:#}", e);
+        }
+        Ok(stats)
+    }
+}
+
+/// Connect and log in to the IMAP server, and select the mailbox.
+pub fn connect(config: &ImapConfig) -> anyhow::Result<Box<dyn ImapSession>> {
+    let port = config.port.unwrap_or(if config.tls { 993 } else { 143 });
+    let context = || format!("failed to connect to IMAP server {}:{}", config.host, port);
+    let stream = connect_tcp(config, port).with_context(context)?;
+    if config.tls {
+        let tls = native_tls::TlsConnector::new().context("failed to set up TLS")?;
+        let stream = tls.connect(&config.host, stream)
+            .map_err(|e| anyhow!("TLS handshake failed: {}", e))
+            .with_context(context)?;
+        let mut client = imap::Client::new(stream);
+        checked(client.read_greeting()).with_context(context)?;
+        login(client, config)
+    } else {
+        let mut client = imap::Client::new(stream);
+        checked(client.read_greeting()).with_context(context)?;
+        login(client, config)
+    }
+}
+
+/// Open a TCP connection with the configured timeouts, trying each of the host's addresses in turn.
+fn connect_tcp(config: &ImapConfig, port: u16) -> io::Result<TcpStream> {
+    let connect_timeout = Duration::from_secs(config.connect_timeout_seconds);
+    let read_timeout = Duration::from_secs(config.read_timeout_seconds);
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "host has no addresses");
+    for addr in (config.host.as_str(), port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, connect_timeout) {
+            Ok(stream) => {
+                // These also bound the TLS handshake.
+                stream.set_read_timeout(Some(read_timeout))?;
+                stream.set_write_timeout(Some(read_timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => {
+                debug!("failed to connect to {}: {}", addr, e);
+                last_err = e;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Convert an IMAP error, making a read or write that hit the timeout say so, instead of "resource
+/// temporarily unavailable".
+fn checked<T>(result: imap::error::Result<T>) -> anyhow::Result<T> {
+    result.map_err(|e| match e {
+        imap::error::Error::Io(ref io)
+            if matches!(io.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) =>
+        {
+            anyhow!("timed out waiting for the IMAP server")
+        }
+        e => e.into(),
+    })
+}
+
+fn login<T: Read + Write + 'static>(client: imap::Client<T>, config: &ImapConfig)
+    -> anyhow::Result<Box<dyn ImapSession>>
+{
+    let mut session = checked(client.login(&config.username, &config.password)
+        .map_err(|(e, _client)| e))
+        .with_context(|| format!("failed to log in to IMAP server {} as {:?}", config.host,
+            config.username))?;
+    checked(session.select(&config.mailbox))
+        .with_context(|| format!("failed to select IMAP mailbox {:?}", config.mailbox))?;
+    let can_move = checked(session.capabilities())
+        .context("failed to get IMAP server capabilities")?
+        .has_str("MOVE");
+    Ok(Box::new(ServerSession { session, can_move }))
+}
+
+struct ServerSession<T: Read + Write> {
+    session: imap::Session<T>,
+    can_move: bool,
+}
+
+impl<T: Read + Write> ImapSession for ServerSession<T> {
+    fn unseen(&mut self, since: Option<NaiveDate>) -> anyhow::Result<Vec<u32>> {
+        let query = match since {
+            Some(date) => format!("UNSEEN SINCE {}", date.format("%-d-%b-%Y")),
+            None => "UNSEEN".to_owned(),
+        };
+        let mut uids = checked(self.session.uid_search(query))?.into_iter().collect::<Vec<_>>();
+        uids.sort_unstable();
+        Ok(uids)
+    }
+
+    fn fetch(&mut self, uid: u32) -> anyhow::Result<Vec<u8>> {
+        let fetches = checked(self.session.uid_fetch(uid.to_string(), "BODY.PEEK[]"))?;
+        fetches.iter()
+            .find_map(|fetch| fetch.body())
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| anyhow!("server returned no message for UID {}", uid))
+    }
+
+    fn mark_seen(&mut self, uid: u32) -> anyhow::Result<()> {
+        checked(self.session.uid_store(uid.to_string(), "+FLAGS.SILENT (\\Seen)"))?;
+        Ok(())
+    }
+
+    fn move_to(&mut self, uid: u32, mailbox: &str) -> anyhow::Result<()> {
+        if self.can_move {
+            checked(self.session.uid_mv(uid.to_string(), mailbox))?;
+        } else {
+            checked(self.session.uid_copy(uid.to_string(), mailbox))?;
+            checked(self.session.uid_store(uid.to_string(), "+FLAGS.SILENT (\\Seen \\Deleted)"))?;
+            checked(self.session.expunge())?;
+        }
+        Ok(())
+    }
+
+    fn logout(&mut self) -> anyhow::Result<()> {
+        checked(self.session.logout())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct FakeServer {
+        /// UID -> (raw message, seen)
+        inbox: BTreeMap<u32, (Vec<u8>, bool)>,
+        archive: Vec<u32>,
+        /// How many of the following operations fail, as if the connection dropped.
+        fail: u32,
+        /// UIDs that can't be fetched.
+        fail_fetch: BTreeSet<u32>,
+        connections: u32,
+    }
+
+    struct FakeSession(Rc<RefCell<FakeServer>>);
+
+    impl FakeSession {
+        fn op<T>(&self, f: impl FnOnce(&mut FakeServer) -> T) -> anyhow::Result<T> {
+            let mut server = self.0.borrow_mut();
+            if server.fail > 0 {
+                server.fail -= 1;
+                bail!("connection reset");
+            }
+            Ok(f(&mut server))
+        }
+    }
+
+    impl ImapSession for FakeSession {
+        fn unseen(&mut self, _since: Option<NaiveDate>) -> anyhow::Result<Vec<u32>> {
+            self.op(|server| server.inbox.iter()
+                .filter(|(_, (_, seen))| !seen)
+                .map(|(uid, _)| *uid)
+                .collect())
+        }
+
+        fn fetch(&mut self, uid: u32) -> anyhow::Result<Vec<u8>> {
+            if self.0.borrow().fail_fetch.contains(&uid) {
+                bail!("no such message");
+            }
+            self.op(|server| server.inbox[&uid].0.clone())
+        }
+
+        fn mark_seen(&mut self, uid: u32) -> anyhow::Result<()> {
+            self.op(|server| server.inbox.get_mut(&uid).unwrap().1 = true)
+        }
+
+        fn move_to(&mut self, uid: u32, mailbox: &str) -> anyhow::Result<()> {
+            assert_eq!("Archive", mailbox);
+            self.op(|server| {
+                server.inbox.remove(&uid);
+                server.archive.push(uid);
+            })
+        }
+
+        fn logout(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn fake_server(messages: &[&str]) -> Rc<RefCell<FakeServer>> {
+        let mut server = FakeServer::default();
+        for (i, msgid) in messages.iter().enumerate() {
+            let raw = format!("Message-ID: <{}>\r\n\r\nhi\r\n", msgid);
+            server.inbox.insert(i as u32 + 1, (raw.into_bytes(), false));
+        }
+        Rc::new(RefCell::new(server))
+    }
+
+    fn source(server: &Rc<RefCell<FakeServer>>, archive: Option<&str>) -> DaylogImap {
+        let server = Rc::clone(server);
+        DaylogImap::with_connector(
+            Box::new(move || {
+                server.borrow_mut().connections += 1;
+                Ok(Box::new(FakeSession(Rc::clone(&server))))
+            }),
+            archive.map(str::to_owned), None, None)
+    }
+
+    /// Process everything, removing messages with "remove" in their message ID, leaving ones with
+    /// "unread" unread, and keeping the rest.
+    fn read(source: &mut DaylogImap) -> anyhow::Result<(Vec<String>, RunStats)> {
+        let mut seen = vec![];
+        let stats = source.read(Box::new(|mail| {
+            let action = if mail.msgid.contains("remove") {
+                MailProcessAction::Remove
+            } else if mail.msgid.contains("unread") {
+                MailProcessAction::LeaveUnread
+            } else {
+                MailProcessAction::Keep
+            };
+            seen.push(mail.msgid);
+            action
+        }))?;
+        Ok((seen, stats))
+    }
+
+    #[test]
+    fn test_actions() {
+        let server = fake_server(&["remove@example.com", "keep@example.com", "unread@example.com"]);
+        let (seen, stats) = read(&mut source(&server, None)).unwrap();
+        assert_eq!(vec!["remove@example.com", "keep@example.com", "unread@example.com"], seen);
+        assert_eq!((3, 1, 1, 1),
+            (stats.num_processed, stats.num_removed, stats.num_kept, stats.num_left_unread));
+        let flags = server.borrow().inbox.values().map(|(_, seen)| *seen).collect::<Vec<_>>();
+        assert_eq!(vec![true, true, false], flags);
+
+        // Next time, only the one left unread is read again.
+        let (seen, _) = read(&mut source(&server, None)).unwrap();
+        assert_eq!(vec!["unread@example.com"], seen);
+    }
+
+    #[test]
+    fn test_archive() {
+        let server = fake_server(&["remove@example.com", "keep@example.com"]);
+        read(&mut source(&server, Some("Archive"))).unwrap();
+        assert_eq!(vec![1], server.borrow().archive);
+        assert_eq!(vec![2], server.borrow().inbox.keys().copied().collect::<Vec<_>>());
+        assert!(server.borrow().inbox[&2].1);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let server = fake_server(&["remove1@example.com", "remove2@example.com"]);
+        let mut source = source(&server, None);
+        let mut seen = vec![];
+        source.read(Box::new(|mail| {
+            // The connection drops while processing the first message, so marking it as seen
+            // fails; it's retried on a new connection.
+            if seen.is_empty() {
+                server.borrow_mut().fail = 1;
+            }
+            seen.push(mail.msgid);
+            MailProcessAction::Remove
+        })).unwrap();
+        assert_eq!(vec!["remove1@example.com", "remove2@example.com"], seen);
+        assert_eq!(2, server.borrow().connections);
+        assert!(server.borrow().inbox.values().all(|(_, seen)| *seen));
+    }
+
+    #[test]
+    fn test_partial_failure() {
+        // Fetching the first message fails even after reconnecting. It's skipped, and left unseen
+        // for next time, but the second one is still processed.
+        let server = fake_server(&["remove1@example.com", "remove2@example.com"]);
+        server.borrow_mut().fail_fetch.insert(1);
+        let (seen, stats) = read(&mut source(&server, None)).unwrap();
+        assert_eq!(vec!["remove2@example.com"], seen);
+        assert_eq!(1, stats.num_processed);
+        assert_eq!(2, server.borrow().connections);
+        assert!(!server.borrow().inbox[&1].1);
+        assert!(server.borrow().inbox[&2].1);
+
+        // If failures keep happening, it gives up.
+        let server = fake_server(&["1@example.com", "2@example.com", "3@example.com", "4@example.com"]);
+        server.borrow_mut().fail_fetch.extend([1, 2, 3, 4]);
+        assert!(read(&mut source(&server, None)).is_err());
+        assert_eq!(4, server.borrow().connections);
+    }
+
+    /// A real IMAP server, on localhost, that stops responding to the client after sending the
+    /// greeting and answering `responses - 1` commands.
+    fn stalling_server(responses: usize) -> (ImapConfig, std::thread::JoinHandle<()>) {
+        use std::io::{BufRead, BufReader};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ImapConfig {
+            host: "127.0.0.1".to_owned(),
+            port: Some(listener.local_addr().unwrap().port()),
+            username: "daylog".to_owned(),
+            password: "hunter2".to_owned(),
+            mailbox: "INBOX".to_owned(),
+            tls: false,
+            archive_mailbox: None,
+            connect_timeout_seconds: 1,
+            read_timeout_seconds: 1,
+        };
+        let handle = std::thread::spawn(move || {
+            // Only one connection is accepted; any more wait in the backlog without a greeting.
+            let (mut stream, _) = listener.accept().unwrap();
+            if responses > 0 {
+                stream.write_all(b"* OK ready\r\n").unwrap();
+            }
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            for _ in 1 .. responses {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let (tag, command) = line.split_once(' ').unwrap();
+                if command.starts_with("CAPABILITY") {
+                    stream.write_all(b"* CAPABILITY IMAP4rev1\r\n").unwrap();
+                } else if command.starts_with("SELECT") {
+                    stream.write_all(b"* 0 EXISTS\r\n* 0 RECENT\r\n").unwrap();
+                }
+                write!(stream, "{} OK done\r\n", tag).unwrap();
+            }
+            // Hold the connection open, saying nothing, until the client gives up on it.
+            let mut rest = vec![];
+            let _ = reader.read_to_end(&mut rest);
+            // Keep the listener open until then too.
+            drop(listener);
+        });
+        (config, handle)
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let (config, _handle) = stalling_server(0);
+        let start = std::time::Instant::now();
+        let err = connect(&config).err().expect("connect should fail");
+        assert!(format!("{:#}", err).contains("timed out waiting for the IMAP server"), "{:#}", err);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        // The greeting, LOGIN, SELECT, and CAPABILITY are sent, but UID SEARCH is never answered,
+        // and the reconnect to retry it doesn't even get a greeting.
+        let (config, handle) = stalling_server(4);
+        let start = std::time::Instant::now();
+        let err = read(&mut DaylogImap::open(&config, None, None)).expect_err("read should fail");
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("failed to search for unseen messages"), "{}", msg);
+        assert!(msg.contains("failed to reconnect"), "{}", msg);
+        assert!(msg.contains("timed out waiting for the IMAP server"), "{}", msg);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        handle.join().unwrap();
+    }
+}
@@ -1,24 +1,120 @@
 use anyhow::Context;
-use crate::config::{Config, IncomingMailConfig};
-use crate::mail::{MailProcessAction, MailSource};
+use crate::config::{Config, IncomingMailConfig, QuoteHandling, SecretKeySource};
+use crate::db::EntrySource;
+use crate::mail::{Attachment, MailProcessAction, MailSource, RunStats};
+use crate::imap::DaylogImap;
 use crate::maildir::DaylogMaildir;
-use crate::message_id::{is_our_message_id, read_secret_key, verify_message_id};
+use crate::stdin_mail::StdinMail;
+use crate::message_id::{is_our_message_id, read_secret_key, verify_message_id, MessageIdError};
 use crate::{IngestArgs, MailTransformArgs};
+use chrono::{NaiveDate, NaiveTime, Utc};
 use regex::Regex;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
 
-pub fn ingest(config: &Config, args: IngestArgs) -> anyhow::Result<()> {
-    let key_bytes = read_secret_key(&config.secret_key_path)
-        .with_context(|| format!("failed to read secret key {:?}", config.secret_key_path))?;
+/// Compile a regex the first time it's used, and reuse it after that.
+macro_rules! regex {
+    ($re:expr) => {{
+        static RE: std::sync::OnceLock<Regex> = std::sync::OnceLock::new();
+        RE.get_or_init(|| Regex::new($re).unwrap())
+    }};
+}
+
+/// Process incoming mail, and return statistics about what was done with it.
+pub fn ingest(config: &Config, args: IngestArgs) -> anyhow::Result<RunStats> {
+    let mut source: Box<dyn MailSource> = if args.stdin {
+        if config.secret_key == SecretKeySource::Stdin {
+            anyhow::bail!("can't read both the secret key and the message from standard input");
+        }
+        Box::new(StdinMail::read_from(std::io::stdin().lock())?)
+    } else {
+        match config.incoming_mail {
+            IncomingMailConfig::Maildir { ref path, delete_processed, ref quarantine_dir } => {
+                let max_age_cutoff = config.ingest_max_age_days
+                    .map(|days| Utc::now() - chrono::Duration::days(i64::from(days)));
+                let since_cutoff = args.since.map(|date| date.and_time(NaiveTime::MIN).and_utc());
+                Box::new(DaylogMaildir::open(path, delete_processed, quarantine_dir.as_deref(),
+                    max_age_cutoff.max(since_cutoff), args.limit, args.reprocess))
+            }
+            IncomingMailConfig::Imap(ref imap) => {
+                if args.reprocess {
+                    anyhow::bail!("--reprocess is only supported with a maildir");
+                }
+                let max_age_date = config.ingest_max_age_days
+                    .map(|days| (Utc::now() - chrono::Duration::days(i64::from(days))).date_naive());
+                Box::new(DaylogImap::open(imap, max_age_date.max(args.since), args.limit))
+            }
+        }
+    };
+
+    let stats = process_mail(config, &args, source.as_mut())?;
+
+    if let Some(path) = args.stats_file.as_ref().or(config.stats_file.as_ref()) {
+        stats.write_to_file(path)
+            .context("failed to write stats file")?;
+    }
+
+    if args.stdin && !args.dry_run && stats.num_removed == 0 {
+        anyhow::bail!("message was not added to the database");
+    }
+
+    Ok(stats)
+}
+
+/// Add replies from the given source to the database, and send any acknowledgements.
+fn process_mail(config: &Config, args: &IngestArgs, source: &mut dyn MailSource)
+    -> anyhow::Result<RunStats>
+{
+    let key_bytes = read_secret_key(&config.secret_key)
+        .with_context(|| format!("failed to read secret key from {}", config.secret_key))?;
 
     let mut db = crate::db::Database::open(&config.database_path)?;
 
-    let mut source: Box<dyn MailSource> = match config.incoming_mail {
-        IncomingMailConfig::Maildir { ref path } => {
-            Box::new(DaylogMaildir::open(path))
+    let max_body_bytes = args.max_body_bytes.or(config.max_body_bytes);
+    let today = chrono::Utc::now().date_naive();
+
+    // (username, date) pairs to send acknowledgements for. Using a set means a batch of replies for
+    // the same day only results in one acknowledgement.
+    let mut acks = BTreeSet::<(String, String)>::new();
+
+    let mut db_errors = 0;
+    let mut seen = 0;
+    let mut stats = source.read(Box::new(|mail| {
+        seen += 1;
+        if args.progress.is_some_and(|every| seen % every == 0) {
+            say!("{} messages so far...", seen);
+        }
+
+        if let Some(token) = unsubscribe_token(&mail.subject) {
+            let username = match verify_message_id(token, key_bytes) {
+                Ok((username, _date)) => username,
+                Err(e) => {
+                    log_verify_error(&format!("message {:?} is an unsubscribe request for {:?}",
+                        mail.msgid, token), &e);
+                    return if args.dry_run {
+                        MailProcessAction::LeaveUnread
+                    } else {
+                        MailProcessAction::Keep
+                    };
+                }
+            };
+            if args.dry_run {
+                println!("Message {:?} unsubscribes {:?}", mail.msgid, username);
+                return MailProcessAction::LeaveUnread;
+            }
+            return match db.set_user_enabled(&username, false) {
+                Ok(()) => {
+                    info!("unsubscribed {:?}", username);
+                    MailProcessAction::Remove
+                }
+                Err(e) => {
+                    error!("failed to unsubscribe {:?}: {:?}", username, e);
+                    db_errors += 1;
+                    MailProcessAction::LeaveUnread
+                }
+            };
         }
-    };
 
-    let stats = source.read(Box::new(move |mail| {
         let mut msgids = vec![];
         for msgid in mail.reply_to {
             if is_our_message_id(&msgid) {
@@ -38,10 +134,27 @@ pub fn ingest(config: &Config, args: IngestArgs) -> anyhow::Result<()> {
             println!("Message {:?} is interesting", mail.msgid);
         }
 
-        let body = process_body(&mail.body);
+        if mail.from_html && !config.accept_html_only {
+            error!("message {:?} has no plain text, only HTML, and accept_html_only is off",
+                mail.msgid);
+            return if args.dry_run {
+                MailProcessAction::LeaveUnread
+            } else {
+                MailProcessAction::Keep
+            };
+        }
+
+        let (replace, body) = clean_body(config, &mail.body, max_body_bytes,
+            &format!("message {:?}", mail.msgid));
 
         if args.dry_run {
+            if replace {
+                println!("replacing existing entries");
+            }
             println!("body:\n{}", body);
+            for attachment in &mail.attachments {
+                println!("attachment: {:?} ({} bytes)", attachment.filename, attachment.data.len());
+            }
         }
 
         for msgid in msgids {
@@ -52,9 +165,12 @@ pub fn ingest(config: &Config, args: IngestArgs) -> anyhow::Result<()> {
                     }
                     (username, date)
                 }
+                Err(MessageIdError::WrongPrefix) => {
+                    debug!("message {:?} replies to {:?}, which isn't ours", mail.msgid, msgid);
+                    continue;
+                }
                 Err(e) => {
-                    println!("Error: message {:?} replies to {:?}, but: {}",
-                             mail.msgid, msgid, e);
+                    log_verify_error(&format!("message {:?} replies to {:?}", mail.msgid, msgid), &e);
                     return if args.dry_run {
                         MailProcessAction::LeaveUnread
                     } else {
@@ -63,11 +179,163 @@ pub fn ingest(config: &Config, args: IngestArgs) -> anyhow::Result<()> {
                 }
             };
 
-            if !args.dry_run {
-                if let Err(e) = db.add_entry(&username, &date, &body) {
-                    eprintln!("Error adding to database: {:?}", e);
+            if let Err(e) = check_entry_date(&date, today, config.min_entry_date) {
+                error!("message {:?} replies to {:?} for {}/{}, but: {}",
+                    mail.msgid, msgid, username, date, e);
+                return if args.dry_run {
+                    MailProcessAction::LeaveUnread
+                } else {
+                    MailProcessAction::Keep
+                };
+            }
+
+            let result = entry_sections(config, &body, &date)
+                .and_then(|entries| {
+                    for (entry_date, _) in &entries {
+                        check_entry_date(entry_date, today, config.min_entry_date)
+                            .with_context(|| format!("bad date line for {}", entry_date))?;
+                    }
+                    Ok(entries)
+                });
+            let entries = match result {
+                Ok(entries) => entries,
+                Err(e) => {
+                    error!("message {:?} for {}/{}: {:#}", mail.msgid, username, date, e);
+                    return if args.dry_run {
+                        MailProcessAction::LeaveUnread
+                    } else {
+                        MailProcessAction::Keep
+                    };
+                }
+            };
+
+            if args.dry_run {
+                if entries.iter().any(|(entry_date, _)| *entry_date != date) {
+                    for (entry_date, text) in &entries {
+                        println!("entry for {}:\n{}", entry_date, text);
+                    }
+                }
+                continue;
+            }
+
+            // Leave out entries this message was already added to (as with --reprocess), and
+            // ones that already have as many replies as they're allowed, before saving any
+            // attachments for them.
+            let mut dropped = vec![];
+            let mut kept = vec![];
+            for (entry_date, text) in entries {
+                let msgids = match db.get_entry_message_ids(&username, &entry_date) {
+                    Ok(msgids) => msgids,
+                    Err(e) => {
+                        error!("failed to look up replies for {}/{}: {:#}", username,
+                            entry_date, e);
+                        db_errors += 1;
+                        return MailProcessAction::LeaveUnread;
+                    }
+                };
+                if msgids.contains(&mail.msgid) {
+                    info!("message {:?} was already added to {}/{}", mail.msgid, username,
+                        entry_date);
+                    dropped.push(entry_date);
+                } else if let (false, Some(max)) = (replace, config.max_appends_per_day) {
+                    if msgids.len() >= max as usize {
+                        warn!("message {:?}: {}/{} already has {} replies added; dropping \
+                            this one", mail.msgid, username, entry_date, msgids.len());
+                        dropped.push(entry_date);
+                    } else {
+                        kept.push((entry_date, text));
+                    }
+                } else {
+                    kept.push((entry_date, text));
+                }
+            }
+            let mut entries = kept;
+
+            let pgp_key = match db.get_pgp_key(&username) {
+                Ok(key) => key,
+                Err(e) => {
+                    error!("failed to look up PGP key for {}: {:#}", username, e);
+                    db_errors += 1;
+                    return MailProcessAction::LeaveUnread;
+                }
+            };
+
+            let attachments_dir = config.attachments_dir.as_ref()
+                .filter(|_| !mail.attachments.is_empty() && !dropped.contains(&date));
+            let mut suffix = None;
+            if let Some(dir) = attachments_dir {
+                // Like the entry, attachments are only saved encrypted for users with a key.
+                let attachments = match &pgp_key {
+                    Some(key) => match encrypt_attachments(config, key, &mail.attachments) {
+                        Ok(encrypted) => Cow::Owned(encrypted),
+                        Err(e) => {
+                            error!("failed to encrypt attachments from message {:?} for {}: {:#}",
+                                mail.msgid, username, e);
+                            return MailProcessAction::LeaveUnread;
+                        }
+                    },
+                    None => Cow::Borrowed(&mail.attachments),
+                };
+                match crate::attachments::save(dir, &username, &date, &attachments) {
+                    Ok(paths) => {
+                        // The references go with the entry for the day the reply is to.
+                        let idx = match entries.iter().position(|(entry_date, _)| *entry_date == date) {
+                            Some(idx) => idx,
+                            None => {
+                                entries.push((date.clone(), String::new()));
+                                entries.len() - 1
+                            }
+                        };
+                        let refs = paths.iter()
+                            .map(|path| format!("[attachment: {}]", path.display()))
+                            .collect::<Vec<_>>()
+                            .join("\n");
+                        append_suffix(&mut entries[idx].1, &refs);
+                        suffix = Some((idx, refs));
+                    }
+                    Err(e) => {
+                        error!("failed to save attachments from message {:?}: {:?}",
+                            mail.msgid, e);
+                        return MailProcessAction::LeaveUnread;
+                    }
+                }
+            }
+
+            for (i, (entry_date, text)) in entries.into_iter().enumerate() {
+                // The reply's raw text would give away what was encrypted, so it isn't kept.
+                let (text, raw) = match &pgp_key {
+                    Some(key) => match crate::pgp::encrypt(config.gpg_path.as_deref(), key, &text) {
+                        Ok(armored) => (armored, None),
+                        Err(e) => {
+                            error!("failed to encrypt entry from message {:?} for {}/{}: {:#}",
+                                mail.msgid, username, entry_date, e);
+                            return MailProcessAction::LeaveUnread;
+                        }
+                    },
+                    None => (text, Some(mail.body.clone())),
+                };
+                let source = EntrySource {
+                    msgid: mail.msgid.clone(),
+                    date: date.clone(),
+                    raw,
+                    suffix: match suffix {
+                        Some((idx, ref refs)) if idx == i => refs.clone(),
+                        _ => String::new(),
+                    },
+                };
+                let result = if replace {
+                    db.set_entry(&username, &entry_date, &text, Some(&source))
+                } else {
+                    db.add_entry(&username, &entry_date, &text, Some(&source))
+                };
+                if let Err(e) = result {
+                    error!("failed to save entry for {}/{}: {:#}", username, entry_date, e);
+                    db_errors += 1;
                     return MailProcessAction::LeaveUnread;
                 }
+                if config.send_ack {
+                    acks.insert((username.clone(), entry_date));
+                }
             }
         }
 
@@ -77,39 +345,931 @@ pub fn ingest(config: &Config, args: IngestArgs) -> anyhow::Result<()> {
             MailProcessAction::Remove
         }
     }))?;
+    stats.num_db_errors = db_errors;
 
     info!("{:#?}", stats);
 
+    for (username, date) in acks {
+        let result = db.get_user(&username, config.default_email_time)
+            .and_then(|user| crate::send::send_ack(config, &user, &date));
+        if let Err(e) = result {
+            error!("failed to send acknowledgement to {:?} for {}: {:?}", username, date, e);
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Clean up a reply's text to store it as an entry, truncating it to `max_body_bytes`, if given.
+/// Returns whether the reply is to replace the day's entry, and the text. `what` describes the
+/// reply, for logging.
+fn clean_body(config: &Config, raw: &str, max_body_bytes: Option<usize>, what: &str)
+    -> (bool, String)
+{
+    let (replace, mut body) = take_replace_token(process_body(raw, config.quote_handling));
+    if let Some(max) = max_body_bytes {
+        if body.len() > max {
+            warn!("{} body is {} bytes; truncating to {}", what, body.len(), max);
+            body = truncate_body(body, max);
+        }
+    }
+    (replace, body)
+}
+
+/// The days a cleaned-up reply to the given date has text for, and the text for each.
+fn entry_sections(config: &Config, body: &str, date: &str) -> anyhow::Result<Vec<(String, String)>> {
+    if config.multi_day_entries {
+        split_dated_sections(body, date)
+    } else {
+        Ok(vec![(date.to_owned(), body.to_owned())])
+    }
+}
+
+/// Add text, like attachment references, after an entry's text.
+fn append_suffix(entry: &mut String, suffix: &str) {
+    if !entry.is_empty() {
+        entry.push_str("\n\n");
+    }
+    *entry += suffix;
+}
+
+/// Make an entry again from the replies it came from, with the current config.
+pub fn regenerate_entry(config: &Config, date: &str, sources: &[EntrySource])
+    -> anyhow::Result<String>
+{
+    let mut parts = vec![];
+    for source in sources {
+        let raw = source.raw.as_deref()
+            .ok_or_else(|| anyhow::anyhow!("message {:?}'s text wasn't kept", source.msgid))?;
+        let (_replace, body) = clean_body(config, raw, config.max_body_bytes,
+            &format!("message {:?}", source.msgid));
+        let mut sections = entry_sections(config, &body, &source.date)?
+            .into_iter()
+            .filter(|(entry_date, _)| entry_date == date)
+            .map(|(_, text)| text)
+            .collect::<Vec<_>>();
+        if !source.suffix.is_empty() {
+            if sections.is_empty() {
+                sections.push(String::new());
+            }
+            append_suffix(&mut sections[0], &source.suffix);
+        }
+        parts.extend(sections);
+    }
+    Ok(parts.join("\n"))
+}
+
+/// Line a reply can start with to replace the day's entry, instead of adding to it.
+const REPLACE_TOKEN: &str = "!replace";
+
+/// If the body starts with `REPLACE_TOKEN` on a line of its own, remove it, and return true.
+fn take_replace_token(body: String) -> (bool, String) {
+    let (first, rest) = body.split_once('\n').unwrap_or((&body, ""));
+    if first.trim() == REPLACE_TOKEN {
+        (true, rest.trim_start().to_owned())
+    } else {
+        (false, body)
+    }
+}
+
+/// With `multi_day_entries`, one reply can hold entries for several days, each one starting with a
+/// line like "@2024-01-02:". Text before the first of those lines is for the day the reply is to.
+/// Returns (date, text) pairs in the order they appear.
+fn split_dated_sections(body: &str, default_date: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let re = regex!(r"^@([0-9][0-9-]*):\s*$");
+    let mut sections = vec![];
+    let mut current = (default_date.to_owned(), vec![]);
+    let mut found = false;
+    for line in body.lines() {
+        if let Some(caps) = re.captures(line) {
+            let token = &caps[1];
+            let date = NaiveDate::parse_from_str(token, "%Y-%m-%d")
+                .ok()
+                .filter(|date| date.format("%Y-%m-%d").to_string() == token)
+                .ok_or_else(|| anyhow::anyhow!("invalid date line {:?}", line))?;
+            sections.push(current);
+            current = (date.format("%Y-%m-%d").to_string(), vec![]);
+            found = true;
+        } else {
+            current.1.push(line);
+        }
+    }
+    if !found {
+        return Ok(vec![(default_date.to_owned(), body.to_owned())]);
+    }
+    sections.push(current);
+    let sections = sections.into_iter()
+        .map(|(date, lines)| (date, lines.join("\n").trim().to_owned()))
+        .filter(|(_, text)| !text.is_empty())
+        .collect::<Vec<_>>();
+    if sections.is_empty() {
+        anyhow::bail!("there are date lines, but no text for any of them");
+    }
+    Ok(sections)
+}
+
+/// Log why a message ID that looked like ours couldn't be verified. One that fails to decrypt has
+/// been tampered with or was made with a different secret key, so that's worse than a merely
+/// malformed one.
+fn log_verify_error(what: &str, e: &MessageIdError) {
+    match e {
+        MessageIdError::DecryptFailed => error!("{}, but it failed to decrypt; it may have been \
+            tampered with, or the secret key has changed", what),
+        _ => warn!("{}, but: {}", what, e),
+    }
+}
+
+/// If the subject is an unsubscribe request, as set up by the daily email's 'List-Unsubscribe'
+/// header, return the message ID it carries.
+fn unsubscribe_token(subject: &str) -> Option<&str> {
+    let re = regex!(r"(?i)\bunsubscribe\s+(\S+)");
+    re.captures(subject)
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str())
+        .filter(|token| is_our_message_id(token))
+}
+
+/// Entries can be dated at most this many days after the current UTC date. Users' dates can be
+/// ahead of UTC by up to a day, so anything much further out means something is broken.
+const MAX_DAYS_IN_FUTURE: i64 = 2;
+
+/// Check that a date from a verified message ID is plausible. The message ID is authenticated, so
+/// this isn't protecting against spoofing, but against bugs or clock problems on our end.
+fn check_entry_date(date: &str, today: NaiveDate, min_date: Option<NaiveDate>)
+    -> anyhow::Result<()>
+{
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .with_context(|| format!("invalid date {:?}", date))?;
+    if date - today > chrono::Duration::days(MAX_DAYS_IN_FUTURE) {
+        anyhow::bail!("date is too far in the future");
+    }
+    if let Some(min_date) = min_date {
+        if date < min_date {
+            anyhow::bail!("date is before the configured minimum of {}", min_date);
+        }
+    }
     Ok(())
 }
 
-pub fn mail_transform(_config: &Config, args: MailTransformArgs, raw: &[u8])
+pub fn mail_transform(config: &Config, args: MailTransformArgs, raw: &[u8])
     -> anyhow::Result<String>
 {
     let parsed = mailparse::parse_mail(raw)
         .context("failed to parse mail")?;
     let pre_processed = crate::mail::Mail::parse(parsed)
         .context("failed to parse mail as Daylog reply")?;
+    if args.json {
+        return Ok(serde_json::to_string_pretty(&pre_processed)?);
+    }
+    if pre_processed.from_html && !config.accept_html_only {
+        anyhow::bail!("message has no plain text, only HTML, and accept_html_only is off");
+    }
     if args.pre_transform {
         Ok(pre_processed.body)
     } else {
-        let processed = process_body(&pre_processed.body);
-        Ok(processed)
+        let quote_handling = args.quote_handling.unwrap_or(config.quote_handling);
+        let processed = process_body(&pre_processed.body, quote_handling);
+        if args.diff {
+            Ok(crate::diff::unified_diff(
+                "pre-transform", &pre_processed.body, "post-transform", &processed))
+        } else {
+            Ok(processed)
+        }
     }
 }
 
-fn process_body(input: &str) -> String {
-    let quote_begin = Regex::new("\nOn (Mon|Tue|Wed|Thu|Fri|Sat|Sun), (Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec) [^>]+([^\n]>)?( |\r?\n)wrote:\r?\n\r?\n?>").unwrap();
-    let signature = Regex::new("(?s)\r?\n-- \r?\n.*$").unwrap();
-
-    signature.replace_all(&quote_begin.replace_all(input, "\n>"), "")
-        .lines()
-        .filter(|line| !line.starts_with('>'))
-        .fold(String::new(), |mut acc, line| {
-            acc.push('\n');
-            acc += line;
-            acc
+/// Encrypt attachments to the user's PGP key, each saved as its ASCII-armored PGP message with
+/// ".asc" added to its name.
+fn encrypt_attachments(config: &Config, key: &str, attachments: &[Attachment])
+    -> anyhow::Result<Vec<Attachment>>
+{
+    attachments.iter()
+        .map(|attachment| {
+            let armored = crate::pgp::encrypt(config.gpg_path.as_deref(), key, &attachment.data)
+                .with_context(|| format!("failed to encrypt {:?}", attachment.filename))?;
+            Ok(Attachment {
+                filename: format!("{}.asc", attachment.filename),
+                data: armored.into_bytes(),
+            })
         })
-        .trim()
-        .to_string()
+        .collect()
+}
+
+/// Cut the body down to at most `max` bytes (not splitting any UTF-8 characters), and add a marker
+/// line indicating it was truncated.
+fn truncate_body(mut body: String, max: usize) -> String {
+    let mut end = max.min(body.len());
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    body.truncate(end);
+    body.push_str("\n[truncated]");
+    body
+}
+
+fn process_body(input: &str, quote_handling: QuoteHandling) -> String {
+    let quote_begin = regex!("\nOn (Mon|Tue|Wed|Thu|Fri|Sat|Sun), (Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec) [^>]+([^\n]>)?( |\r?\n)wrote:\r?\n\r?\n?>");
+    let signature = regex!("(?s)\r?\n-- \r?\n.*$");
+    // Outlook doesn't quote the original message; it puts it after one of these separators.
+    let original_message = regex!("(?ms)^(-{3,} ?(?i:original message) ?-{3,}|_{10,}\r?\n(From|De|Von|Da|Van): ).*$");
+
+    let text = if quote_handling == QuoteHandling::Keep {
+        signature.replace_all(input, "").into_owned()
+    } else {
+        let text = signature.replace_all(&quote_begin.replace_all(input, "\n>"), "").into_owned();
+        original_message.replace(&text, "").into_owned()
+    };
+
+    let lines = text.lines().collect::<Vec<_>>();
+    let mut keep = vec![true; lines.len()];
+    if quote_handling == QuoteHandling::Keep {
+        return join_lines(&lines, &keep);
+    }
+    for (i, line) in lines.iter().enumerate() {
+        if is_quoted(line) {
+            keep[i] = false;
+        } else if is_attribution(&lines[i..]) {
+            keep[i] = false;
+            if i > 0 && is_wrapped_attribution(lines[i - 1], line) {
+                keep[i - 1] = false;
+            }
+        }
+    }
+
+    if quote_handling == QuoteHandling::Smart {
+        // Put back quotes the user replied to inline, which are the ones before their last line.
+        let last_new = (0 .. lines.len())
+            .rev()
+            .find(|&i| keep[i] && !lines[i].trim().is_empty());
+        if let Some(last_new) = last_new {
+            for i in 0 .. last_new {
+                if is_quoted(lines[i]) {
+                    keep[i] = true;
+                }
+            }
+        }
+    }
+
+    join_lines(&lines, &keep)
+}
+
+/// Join the lines that are to be kept, without any blank lines at the start or end. Blank lines the
+/// user wrote are kept as they are, but where lines were removed from between two paragraphs, only
+/// one blank line is left between them, instead of the blank lines from both sides.
+fn join_lines(lines: &[&str], keep: &[bool]) -> String {
+    let mut out: Vec<&str> = vec![];
+    let mut removed = false;
+    for (line, keep) in lines.iter().zip(keep) {
+        let blank = line.trim().is_empty();
+        if !keep {
+            removed = true;
+        } else if blank && (out.is_empty() || (removed && out.last().unwrap().trim().is_empty())) {
+            continue;
+        } else {
+            if !blank {
+                removed = false;
+            }
+            out.push(line);
+        }
+    }
+    out.join("\n").trim_end().to_owned()
+}
+
+fn is_quoted(line: &str) -> bool {
+    line.trim_start().starts_with('>')
+}
+
+/// Whether the first line looks like the attribution line mail clients put above a quoted message
+/// (like "On <date>, <someone> wrote:" in any language): it ends with a colon, and the next
+/// non-blank line is quoted.
+fn is_attribution(lines: &[&str]) -> bool {
+    lines[0].trim_end().ends_with(':')
+        && lines[1..].iter()
+            .find(|line| !line.trim().is_empty())
+            .map(|line| is_quoted(line))
+            .unwrap_or(false)
+}
+
+/// Whether an attribution line is actually the tail end of one that got wrapped onto two lines.
+fn is_wrapped_attribution(prev: &str, line: &str) -> bool {
+    !prev.trim().is_empty()
+        && (prev.trim_end().ends_with('<') || line.split_whitespace().count() <= 3)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::Database;
+    use crate::mail::Mail;
+    use crate::message_id::{gen_message_id, gen_secret_key};
+    use crate::testutil::{reply, secret_key, test_config, TempDir, VecMailSource};
+
+    fn ingest_args(dry_run: bool) -> IngestArgs {
+        IngestArgs {
+            dry_run,
+            stats_file: None,
+            max_body_bytes: None,
+            stdin: false,
+            limit: None,
+            reprocess: false,
+            since: None,
+            progress: None,
+        }
+    }
+
+    #[test]
+    fn test_ingest_end_to_end() {
+        let dir = TempDir::new("ingest-end-to-end");
+        let config = test_config(&dir, "");
+        let key = secret_key(&config);
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let our_msgid = format!("{}@daylog.example.com",
+            gen_message_id("alice", date, key).unwrap());
+        let mut source = VecMailSource::new(vec![
+            Mail {
+                msgid: "reply@example.com".to_owned(),
+                reply_to: vec!["earlier@example.com".to_owned(), our_msgid],
+                subject: "Re: Daylog for 2021-07-15".to_owned(),
+                body: "went for a walk\n\n\
+                    On Thu, Jul 15, 2021 at 6:00 PM Daylog <daylog@example.com> wrote:\n\n\
+                    > What'd you do today?\n".to_owned(),
+                attachments: vec![],
+                from_html: false,
+            },
+            Mail {
+                msgid: "unrelated@example.com".to_owned(),
+                reply_to: vec!["something-else@example.com".to_owned()],
+                subject: "Re: something else".to_owned(),
+                body: "not for us".to_owned(),
+                attachments: vec![],
+                from_html: false,
+            },
+        ]);
+
+        // A dry run doesn't change anything, and leaves everything to be read again.
+        let stats = process_mail(&config, &ingest_args(true), &mut source).unwrap();
+        assert_eq!((2, 0, 0, 2),
+            (stats.num_processed, stats.num_removed, stats.num_kept, stats.num_left_unread));
+        assert_eq!(2, source.mails.len());
+        let db = Database::open(&config.database_path).unwrap();
+        assert_eq!(None, db.get_entry("alice", "2021-07-15").unwrap());
+
+        let stats = process_mail(&config, &ingest_args(false), &mut source).unwrap();
+        assert_eq!((2, 1, 1, 0),
+            (stats.num_processed, stats.num_removed, stats.num_kept, stats.num_left_unread));
+        assert_eq!(&source.actions[2..], &[
+            ("reply@example.com".to_owned(), MailProcessAction::Remove),
+            ("unrelated@example.com".to_owned(), MailProcessAction::Keep),
+        ]);
+        assert!(source.mails.is_empty());
+        assert_eq!(Some("went for a walk".to_owned()), db.get_entry("alice", "2021-07-15").unwrap());
+    }
+
+    #[test]
+    fn test_ingest_errors() {
+        let dir = TempDir::new("ingest-errors");
+        let config = test_config(&dir, "");
+        let key = secret_key(&config);
+
+        // Make adding entries fail.
+        Database::open(&config.database_path).unwrap();
+        rusqlite::Connection::open(&config.database_path).unwrap()
+            .execute_batch("CREATE TRIGGER no_entries BEFORE INSERT ON entries \
+                BEGIN SELECT RAISE(ABORT, 'no entries allowed'); END;")
+            .unwrap();
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let mut source = VecMailSource::new(vec![
+            reply(key, "alice", date, "reply@example.com", "went for a walk\n"),
+        ]);
+        source.unparseable = 2;
+
+        let stats = process_mail(&config, &ingest_args(false), &mut source).unwrap();
+        assert_eq!((1, 0, 0, 1, 2, 1),
+            (stats.num_processed, stats.num_removed, stats.num_kept, stats.num_left_unread,
+                stats.num_failed, stats.num_db_errors));
+        assert_eq!(1, source.mails.len());
+    }
+
+    #[test]
+    fn test_accept_html_only() {
+        let dir = TempDir::new("ingest-html-only");
+        let config = test_config(&dir, "accept_html_only: false\n");
+        let key = secret_key(&config);
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let html = |from_html: bool| Mail {
+            from_html,
+            ..reply(key, "alice", date, &format!("{}@example.com", from_html), "went for a walk\n")
+        };
+        let mut source = VecMailSource::new(vec![html(true), html(false)]);
+        process_mail(&config, &ingest_args(false), &mut source).unwrap();
+        assert_eq!(source.actions, vec![
+            ("true@example.com".to_owned(), MailProcessAction::Keep),
+            ("false@example.com".to_owned(), MailProcessAction::Remove),
+        ]);
+    }
+
+    #[test]
+    fn test_ingest_stats() {
+        let dir = TempDir::new("ingest-stats");
+        let config = test_config(&dir, "");
+        gen_secret_key(&config.secret_key, false).unwrap();
+        let maildir = maildir::Maildir::from(dir.path().join("maildir"));
+        maildir.create_dirs().unwrap();
+        maildir.store_new(b"Message-ID: <unrelated@example.com>\r\n\r\nnot for us\r\n").unwrap();
+
+        let stats = ingest(&config, ingest_args(false)).unwrap();
+        assert_eq!((1, 0, 1, 0),
+            (stats.num_processed, stats.num_removed, stats.num_kept, stats.num_left_unread));
+        assert_eq!(1, maildir.count_cur());
+    }
+
+    #[test]
+    fn test_ingest_limit() {
+        let dir = TempDir::new("ingest-limit");
+        let config = test_config(&dir, "");
+        gen_secret_key(&config.secret_key, false).unwrap();
+        let maildir = maildir::Maildir::from(dir.path().join("maildir"));
+        maildir.create_dirs().unwrap();
+        for i in 0 .. 3 {
+            maildir.store_new(format!("Message-ID: <{}@example.com>\r\n\r\nhi\r\n", i).as_bytes())
+                .unwrap();
+        }
+
+        let args = IngestArgs { limit: Some(1), .. ingest_args(false) };
+        let stats = ingest(&config, args).unwrap();
+        assert_eq!((1, 1), (stats.num_processed, stats.num_kept));
+        assert_eq!((2, 1), (maildir.count_new(), maildir.count_cur()));
+
+        let stats = ingest(&config, ingest_args(false)).unwrap();
+        assert_eq!(2, stats.num_processed);
+        assert_eq!((0, 3), (maildir.count_new(), maildir.count_cur()));
+    }
+
+    #[test]
+    fn test_ingest_stdin() {
+        let dir = TempDir::new("ingest-stdin");
+        let config = test_config(&dir, "");
+        let key = secret_key(&config);
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let raw = format!("\
+From: alice@example.com\r
+To: daylog@example.com\r
+Subject: Re: Daylog for 2021-07-15\r
+Message-ID: <reply@example.com>\r
+References: <{}@daylog.example.com>\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+baked bread\r
+", gen_message_id("alice", date, key).unwrap());
+
+        let mut source = StdinMail::read_from(raw.as_bytes()).unwrap();
+        let stats = process_mail(&config, &ingest_args(false), &mut source).unwrap();
+        assert_eq!((1, 1), (stats.num_processed, stats.num_removed));
+        let db = Database::open(&config.database_path).unwrap();
+        assert_eq!(Some("baked bread".to_owned()), db.get_entry("alice", "2021-07-15").unwrap());
+
+        let mut source = StdinMail::read_from(&b"not an email"[..]).unwrap();
+        assert!(process_mail(&config, &ingest_args(false), &mut source).is_err());
+    }
+
+    #[test]
+    fn test_ingest_attachments() {
+        let dir = TempDir::new("ingest-attachments");
+        let config = test_config(&dir, "attachments_dir: attachments\n");
+        let key = secret_key(&config);
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let raw = format!("\
+From: alice@example.com\r
+To: daylog@example.com\r
+Subject: Re: Daylog for 2021-07-15\r
+Message-ID: <reply@example.com>\r
+References: <{}@daylog.example.com>\r
+Content-Type: multipart/mixed; boundary=XX\r
+\r
+--XX\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+went to the beach\r
+--XX\r
+Content-Type: image/png; name=\"beach.png\"\r
+Content-Disposition: attachment; filename=\"../../beach.png\"\r
+Content-Transfer-Encoding: base64\r
+\r
+iVBORw0KGgo=\r
+--XX--\r
+", gen_message_id("alice", date, key).unwrap());
+
+        let mut source = StdinMail::read_from(raw.as_bytes()).unwrap();
+        let stats = process_mail(&config, &ingest_args(false), &mut source).unwrap();
+        assert_eq!((1, 1), (stats.num_processed, stats.num_removed));
+
+        let saved = dir.path().join("attachments/alice/2021-07-15/beach.png");
+        assert_eq!(b"\x89PNG\r\n\x1a\n", &std::fs::read(saved).unwrap()[..]);
+        let db = Database::open(&config.database_path).unwrap();
+        assert_eq!(Some("went to the beach\n\n[attachment: alice/2021-07-15/beach.png]".to_owned()),
+            db.get_entry("alice", "2021-07-15").unwrap());
+    }
+
+    #[test]
+    fn test_take_replace_token() {
+        assert_eq!((true, "fixed typo".to_owned()), take_replace_token("!replace\n\nfixed typo".to_owned()));
+        assert_eq!((true, String::new()), take_replace_token(" !replace ".to_owned()));
+        assert_eq!((false, "!replace me\nplease".to_owned()),
+            take_replace_token("!replace me\nplease".to_owned()));
+        assert_eq!((false, "hi\n!replace".to_owned()), take_replace_token("hi\n!replace".to_owned()));
+    }
+
+    #[test]
+    fn test_ingest_replace() {
+        let dir = TempDir::new("ingest-replace");
+        let config = test_config(&dir, "");
+        let key = secret_key(&config);
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let mail = |msgid: &str, body: &str| reply(key, "alice", date, msgid, body);
+        let db = Database::open(&config.database_path).unwrap();
+        let ingest_one = |mail: Mail| {
+            process_mail(&config, &ingest_args(false), &mut VecMailSource::new(vec![mail])).unwrap();
+            db.get_entry("alice", "2021-07-15").unwrap().unwrap()
+        };
+
+        assert_eq!("went for a wlak", ingest_one(mail("1@example.com", "went for a wlak")));
+        assert_eq!("went for a wlak\nand a swim", ingest_one(mail("2@example.com", "and a swim")));
+        // The same message again (like with --reprocess) isn't added twice.
+        assert_eq!("went for a wlak\nand a swim", ingest_one(mail("1@example.com", "went for a wlak")));
+        assert_eq!("went for a walk and a swim",
+            ingest_one(mail("3@example.com", "!replace\nwent for a walk and a swim")));
+    }
+
+    #[test]
+    fn test_max_appends_per_day() {
+        let dir = TempDir::new("ingest-max-appends");
+        let config = test_config(&dir, "max_appends_per_day: 2\n");
+        let key = secret_key(&config);
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let mail = |msgid: &str, body: &str| reply(key, "alice", date, msgid, body);
+        let db = Database::open(&config.database_path).unwrap();
+        let ingest_one = |mail: Mail| {
+            process_mail(&config, &ingest_args(false), &mut VecMailSource::new(vec![mail])).unwrap();
+            db.get_entry("alice", "2021-07-15").unwrap().unwrap()
+        };
+
+        assert_eq!("one", ingest_one(mail("1@example.com", "one")));
+        assert_eq!("one\ntwo", ingest_one(mail("2@example.com", "two")));
+        assert_eq!("one\ntwo", ingest_one(mail("3@example.com", "three")));
+        assert_eq!("one\ntwo", ingest_one(mail("4@example.com", "four")));
+
+        // Replacing the entry is still allowed, and starts the count over.
+        assert_eq!("fixed", ingest_one(mail("5@example.com", "!replace\nfixed")));
+        assert_eq!("fixed\nsix", ingest_one(mail("6@example.com", "six")));
+        assert_eq!("fixed\nsix", ingest_one(mail("7@example.com", "seven")));
+    }
+
+    #[test]
+    fn test_dropped_reply_attachments() {
+        let dir = TempDir::new("ingest-dropped-attachments");
+        let config = test_config(&dir, "max_appends_per_day: 1\nattachments_dir: attachments\n");
+        let key = secret_key(&config);
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let mail = |msgid: &str, body: &str| Mail {
+            attachments: vec![crate::mail::Attachment {
+                filename: "photo.jpg".to_owned(),
+                data: b"jpeg".to_vec(),
+            }],
+            ..reply(key, "alice", date, msgid, body)
+        };
+        // The second is the first again, as with --reprocess, and the third is over the limit.
+        process_mail(&config, &ingest_args(false), &mut VecMailSource::new(vec![
+            mail("1@example.com", "one"),
+            mail("1@example.com", "one"),
+            mail("2@example.com", "two"),
+        ])).unwrap();
+
+        let db = Database::open(&config.database_path).unwrap();
+        assert_eq!(Some("one\n\n[attachment: alice/2021-07-15/photo.jpg]".to_owned()),
+            db.get_entry("alice", "2021-07-15").unwrap());
+        let saved = std::fs::read_dir(dir.path().join("attachments/alice/2021-07-15")).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["photo.jpg"], saved);
+    }
+
+    #[test]
+    fn test_regenerate_entry() {
+        let dir = TempDir::new("ingest-regenerate");
+        let config = test_config(&dir, "quote_handling: keep\nmulti_day_entries: true\n");
+        let key = secret_key(&config);
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let mail = |msgid: &str, body: &str| reply(key, "alice", date, msgid, body);
+        process_mail(&config, &ingest_args(false), &mut VecMailSource::new(vec![
+            mail("1@example.com", "went for a walk\n\n> What did you do today?"),
+            mail("2@example.com", "@2021-07-14:\nread a book\n@2021-07-15:\nate lunch\n> hi"),
+        ])).unwrap();
+
+        let db = Database::open(&config.database_path).unwrap();
+        let entries = db.get_entry_sources("alice", date.pred_opt().unwrap(), date).unwrap();
+        assert_eq!(2, entries.len());
+
+        // With the same config, the entries come out the same.
+        for (entry_date, body, sources) in &entries {
+            let entry_date = entry_date.format("%Y-%m-%d").to_string();
+            assert_eq!(*body, regenerate_entry(&config, &entry_date, sources).unwrap());
+        }
+
+        let stripped = test_config(&dir, "quote_handling: strip\nmulti_day_entries: true\n");
+        let (_, _, sources) = &entries[0];
+        assert_eq!("read a book", regenerate_entry(&stripped, "2021-07-14", sources).unwrap());
+        let (_, _, sources) = &entries[1];
+        assert_eq!("went for a walk\nate lunch",
+            regenerate_entry(&stripped, "2021-07-15", sources).unwrap());
+    }
+
+    #[test]
+    fn test_encrypted_entries() {
+        let pgp = crate::testutil::TestKey::new();
+        let dir = TempDir::new("ingest-encrypted");
+        let config = test_config(&dir, "attachments_dir: attachments\n");
+        let key = secret_key(&config);
+        let mut db = Database::open(&config.database_path).unwrap();
+        db.add_user("alice", "alice@example.com", "UTC", "18:00").unwrap();
+        db.set_pgp_key("alice", &pgp.public_key).unwrap();
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let mail = |msgid: &str, body: &str| reply(key, "alice", date, msgid, body);
+        let with_photo = Mail {
+            attachments: vec![crate::mail::Attachment {
+                filename: "lunch.jpg".to_owned(),
+                data: b"jpeg".to_vec(),
+            }],
+            ..mail("2@example.com", "ate lunch")
+        };
+        process_mail(&config, &ingest_args(false), &mut VecMailSource::new(vec![
+            mail("1@example.com", "went for a walk\n\n> What did you do today?"),
+            with_photo,
+        ])).unwrap();
+
+        let body = db.get_entry("alice", "2021-07-15").unwrap().unwrap();
+        assert!(!body.contains("walk") && !body.contains("lunch"), "{}", body);
+        let blocks = body.split_inclusive("-----END PGP MESSAGE-----")
+            .map(|block| pgp.decrypt(block.trim_start()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["went for a walk",
+            "ate lunch\n\n[attachment: alice/2021-07-15/lunch.jpg.asc]"], blocks);
+
+        // Attachments are only saved encrypted too.
+        let attachments = dir.path().join("attachments/alice/2021-07-15");
+        assert!(!attachments.join("lunch.jpg").exists());
+        let saved = std::fs::read_to_string(attachments.join("lunch.jpg.asc")).unwrap();
+        assert_eq!("jpeg", pgp.decrypt(&saved));
+
+        // None of the plaintext is kept for reprocessing.
+        assert!(db.get_entry_sources("alice", date, date).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_split_dated_sections() {
+        let split = |body: &str| split_dated_sections(body, "2024-01-05").unwrap();
+        let pairs = |v: &[(&str, &str)]| v.iter()
+            .map(|(date, text)| (date.to_string(), text.to_string()))
+            .collect::<Vec<_>>();
+
+        // no date lines: the whole body, unchanged
+        assert_eq!(pairs(&[("2024-01-05", "went for a walk\n@alice: hi")]),
+            split("went for a walk\n@alice: hi"));
+
+        // one date line
+        assert_eq!(pairs(&[("2024-01-02", "went for a walk")]),
+            split("@2024-01-02:\nwent for a walk\n"));
+
+        // several, with leading text for the reply's own date
+        assert_eq!(pairs(&[
+                ("2024-01-05", "today"),
+                ("2024-01-02", "tuesday\nmore tuesday"),
+                ("2024-01-03", "wednesday"),
+            ]),
+            split("today\n\n@2024-01-02:\ntuesday\nmore tuesday\n\n@2024-01-03: \nwednesday"));
+
+        assert!(split_dated_sections("@2024-02-30:\nnope", "2024-01-05").is_err());
+        assert!(split_dated_sections("@2024-1-2:\nnope", "2024-01-05").is_err());
+        assert!(split_dated_sections("@2024-01-02:\n\n", "2024-01-05").is_err());
+    }
+
+    #[test]
+    fn test_multi_day_entries() {
+        let dir = TempDir::new("multi-day-entries");
+        let config = test_config(&dir, "multi_day_entries: true\n");
+        let key = secret_key(&config);
+
+        let today = chrono::Utc::now().date_naive();
+        let day = |n: i64| (today - chrono::Duration::days(n)).format("%Y-%m-%d").to_string();
+        let mut source = VecMailSource::new(vec![
+            reply(key, "alice", today, "multi@example.com",
+                &format!("today\n\n@{}:\nyesterday\n\n@{}:\nthe day before", day(1), day(2))),
+            reply(key, "alice", today, "bad@example.com", "@2024-13-01:\nnope"),
+        ]);
+
+        let stats = process_mail(&config, &ingest_args(false), &mut source).unwrap();
+        assert_eq!((2, 1, 1), (stats.num_processed, stats.num_removed, stats.num_kept));
+        let db = Database::open(&config.database_path).unwrap();
+        assert_eq!(Some("today".to_owned()), db.get_entry("alice", &day(0)).unwrap());
+        assert_eq!(Some("yesterday".to_owned()), db.get_entry("alice", &day(1)).unwrap());
+        assert_eq!(Some("the day before".to_owned()), db.get_entry("alice", &day(2)).unwrap());
+    }
+
+    #[test]
+    fn test_implausible_dates() {
+        let dir = TempDir::new("implausible-dates");
+        let config = test_config(&dir, "min_entry_date: 2010-01-01\n");
+        let key = secret_key(&config);
+
+        let today = chrono::Utc::now().date_naive();
+        let mail = |date: NaiveDate| reply(key, "alice", date, &format!("{}@example.com", date),
+            &format!("entry for {}", date));
+        let dates = [
+            today + chrono::Duration::days(10),
+            today + chrono::Duration::days(2),
+            NaiveDate::from_ymd_opt(2010, 1, 1).unwrap(),
+            NaiveDate::from_ymd_opt(2009, 12, 31).unwrap(),
+        ];
+        let mut source = VecMailSource::new(dates.iter().copied().map(mail).collect());
+
+        let stats = process_mail(&config, &ingest_args(false), &mut source).unwrap();
+        assert_eq!((4, 2, 2), (stats.num_processed, stats.num_removed, stats.num_kept));
+        let actions = source.actions.iter().map(|(_, action)| *action).collect::<Vec<_>>();
+        assert_eq!(vec![MailProcessAction::Keep, MailProcessAction::Remove,
+            MailProcessAction::Remove, MailProcessAction::Keep], actions);
+
+        let db = Database::open(&config.database_path).unwrap();
+        for (date, stored) in dates.iter().zip([false, true, true, false]) {
+            let entry = db.get_entry("alice", &date.format("%Y-%m-%d").to_string()).unwrap();
+            assert_eq!(stored, entry.is_some(), "{}", date);
+        }
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let dir = TempDir::new("unsubscribe");
+        let config = test_config(&dir, "");
+        let key = secret_key(&config);
+        let mut db = Database::open(&config.database_path).unwrap();
+        db.add_user("alice", "alice@example.com", "UTC", "18:00").unwrap();
+        db.add_user("bob", "bob@example.com", "UTC", "18:00").unwrap();
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let token = gen_message_id("alice", date, key).unwrap();
+        let request = |subject: String| Mail {
+            msgid: "unsubscribe@example.com".to_owned(),
+            reply_to: vec![],
+            subject,
+            body: String::new(),
+            attachments: vec![],
+            from_html: false,
+        };
+        let mut source = VecMailSource::new(vec![
+            request(format!("unsubscribe {}", token.replace("daylog.1.", "daylog.1.A"))),
+            request(format!("unsubscribe {}", token)),
+        ]);
+
+        process_mail(&config, &ingest_args(true), &mut source).unwrap();
+        assert!(db.get_user("alice", None).unwrap().enabled);
+
+        process_mail(&config, &ingest_args(false), &mut source).unwrap();
+        let actions = source.actions[2..].iter().map(|(_, action)| *action).collect::<Vec<_>>();
+        assert_eq!(vec![MailProcessAction::Keep, MailProcessAction::Remove], actions);
+        assert!(!db.get_user("alice", None).unwrap().enabled);
+        assert!(db.get_user("bob", None).unwrap().enabled);
+    }
+
+    #[test]
+    fn test_unsubscribe_token() {
+        assert_eq!(Some("daylog.1.abc.def"), unsubscribe_token("unsubscribe daylog.1.abc.def"));
+        assert_eq!(Some("daylog.1.abc.def"), unsubscribe_token("Unsubscribe  daylog.1.abc.def "));
+        assert_eq!(None, unsubscribe_token("unsubscribe"));
+        assert_eq!(None, unsubscribe_token("unsubscribe me please"));
+        assert_eq!(None, unsubscribe_token("Re: Daylog for 2021-07-15"));
+    }
+
+    #[test]
+    fn test_check_entry_date() {
+        let today = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        assert!(check_entry_date("2021-07-17", today, None).is_ok());
+        assert!(check_entry_date("2021-07-18", today, None).is_err());
+        assert!(check_entry_date("1970-01-01", today, None).is_ok());
+        let min = NaiveDate::from_ymd_opt(2020, 1, 1);
+        assert!(check_entry_date("2020-01-01", today, min).is_ok());
+        assert!(check_entry_date("2019-12-31", today, min).is_err());
+        assert!(check_entry_date("2021-02-30", today, None).is_err());
+    }
+
+    #[test]
+    fn test_regex_cached() {
+        let compiled = (0 .. 2).map(|_| regex!("a+") as *const Regex).collect::<Vec<_>>();
+        assert_eq!(compiled[0], compiled[1]);
+
+        // Processing the same body again, with the regexes already compiled, gives the same result.
+        let body = "new text\n\nOn Thu, Oct 15, 2026 at 6:00 PM Daylog <daylog@example.com> wrote:\n\
+            > old\n\
+            -- \n\
+            Someone\n";
+        for _ in 0 .. 2 {
+            assert_eq!("new text", process_body(body, QuoteHandling::Strip));
+        }
+    }
+
+    #[test]
+    fn test_process_body_colons() {
+        // lines ending in a colon are only attributions if followed by a quote
+        assert_eq!("Things I did:\n- laundry\n- dishes",
+            process_body("Things I did:\n- laundry\n- dishes\n", QuoteHandling::Strip));
+        assert_eq!("Things I did:\n\n- laundry",
+            process_body("Things I did:\n\n- laundry\n\nSomeone wrote:\n> hello\n",
+                QuoteHandling::Strip));
+    }
+
+    #[test]
+    fn test_process_body_nested_quotes() {
+        assert_eq!("new text", process_body("new text\n\n>> old\n> > older\n >>> oldest\n",
+            QuoteHandling::Strip));
+    }
+
+    #[test]
+    fn test_process_body_paragraphs() {
+        // Blank lines between paragraphs survive, even several, and so does indentation.
+        assert_eq!("First paragraph,\nstill first.\n\nSecond paragraph.",
+            process_body("\nFirst paragraph,\nstill first.\n\nSecond paragraph.\n\n\
+                On Thu, Oct 15, 2026 at 6:00 PM Daylog <daylog@example.com> wrote:\n\n\
+                > What'd you do today?\n", QuoteHandling::Strip));
+        assert_eq!("  indented\n\n\nafter two blank lines",
+            process_body("  indented\n\n\nafter two blank lines\n", QuoteHandling::Strip));
+
+        // A quote removed from between paragraphs doesn't leave two blank lines in its place.
+        assert_eq!("one\n\ntwo", process_body("one\n\n> quote\n\ntwo\n", QuoteHandling::Strip));
+        assert_eq!("one\ntwo", process_body("one\n> quote\ntwo\n", QuoteHandling::Strip));
+    }
+
+    #[test]
+    fn test_process_body_inline_reply() {
+        let body = "On Thu, Oct 15, 2026 at 6:00 PM Daylog <daylog@example.com> wrote:\n\
+            > What'd you do today, Thursday, October 15, 2026?\n\
+            \n\
+            Went hiking.\n\
+            \n\
+            > Here's what you were doing\n\
+            > \tone week ago:\tmade soup\n\
+            \n\
+            Made soup again.\n\
+            \n\
+            > --\n\
+            > sent by daylog\n\
+            \n\
+            -- \n\
+            Someone\n";
+        assert_eq!("Went hiking.\n\nMade soup again.", process_body(body, QuoteHandling::Strip));
+        assert_eq!("> What'd you do today, Thursday, October 15, 2026?\n\
+            \n\
+            Went hiking.\n\
+            \n\
+            > Here's what you were doing\n\
+            > \tone week ago:\tmade soup\n\
+            \n\
+            Made soup again.",
+            process_body(body, QuoteHandling::Smart));
+        assert_eq!("On Thu, Oct 15, 2026 at 6:00 PM Daylog <daylog@example.com> wrote:\n\
+            > What'd you do today, Thursday, October 15, 2026?\n\
+            \n\
+            Went hiking.\n\
+            \n\
+            > Here's what you were doing\n\
+            > \tone week ago:\tmade soup\n\
+            \n\
+            Made soup again.\n\
+            \n\
+            > --\n\
+            > sent by daylog",
+            process_body(body, QuoteHandling::Keep));
+
+        // Without any inline replies, smart is the same as strip.
+        let body = "Went hiking.\n\nOn Thu, Oct 15, 2026 at 6:00 PM Daylog <daylog@example.com> wrote:\n\
+            > What'd you do today?\n";
+        assert_eq!("Went hiking.", process_body(body, QuoteHandling::Smart));
+    }
+
+    #[test]
+    fn test_truncate_body() {
+        let body = "0123456789".repeat(100);
+        assert_eq!("0123456789\n[truncated]", truncate_body(body, 10));
+
+        // 'é' is two bytes; don't cut it in half
+        let body = "abcé".to_owned();
+        assert_eq!("abc\n[truncated]", truncate_body(body.clone(), 4));
+        assert_eq!("abcé\n[truncated]", truncate_body(body, 5));
+    }
 }
@@ -0,0 +1,352 @@
+use crate::config::Config;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::Duration;
+
+/// How many log lines can be waiting to be written to the log file before more are dropped,
+/// instead of making whatever is logging wait on the disk.
+const LOG_FILE_QUEUE: usize = 1024;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines, as written by stderrlog.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Set up the global logger. Only log records from this crate are emitted, and the verbosity
+/// count maps to levels the same way stderrlog does it (0 = errors only, 4+ = everything). If the
+/// config has a `log_file`, they're written there too.
+pub fn init(format: LogFormat, verbosity: u8, config: &Config) -> anyhow::Result<()> {
+    let level = level_for_verbosity(verbosity);
+    let stderr: Box<dyn Log> = match format {
+        LogFormat::Text => {
+            let mut logger = stderrlog::new();
+            logger.module(module_path!().split("::").next().unwrap())
+                .verbosity(verbosity as usize);
+            Box::new(logger)
+        }
+        LogFormat::Json => Box::new(JsonLogger { level }),
+    };
+    let logger = match &config.log_file {
+        Some(path) => {
+            let file = RotatingFile::open(path.clone(), config.log_file_max_bytes,
+                config.log_file_keep)?;
+            Box::new(TeeLogger { stderr, file: FileLogger::start(file, format, level)? })
+        }
+        None => stderr,
+    };
+    log::set_boxed_logger(logger)?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+fn level_for_verbosity(verbosity: u8) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+struct JsonLogger {
+    level: LevelFilter,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'static str,
+    target: &'a str,
+    message: String,
+}
+
+fn json_line(record: &Record) -> Option<String> {
+    let json = JsonRecord {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        level: record.level().as_str(),
+        target: record.target(),
+        message: record.args().to_string(),
+    };
+    serde_json::to_string(&json).ok()
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && is_our_target(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // note: nowhere to report errors from a logger
+        if let Some(line) = json_line(record) {
+            let _ = writeln!(io::stderr().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+fn is_our_target(target: &str) -> bool {
+    let ours = module_path!().split("::").next().unwrap();
+    target == ours || target.starts_with(&format!("{}::", ours))
+}
+
+struct TeeLogger {
+    stderr: Box<dyn Log>,
+    file: FileLogger,
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.enabled(metadata) || self.file.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.stderr.log(record);
+        self.file.log(record);
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        self.file.flush();
+    }
+}
+
+enum FileMessage {
+    Line(String),
+    Flush(SyncSender<()>),
+}
+
+/// Hands log lines off to a thread that writes them to the log file, so a slow disk doesn't hold
+/// up sending or ingesting. If the thread falls too far behind, lines are dropped, and the file
+/// says how many.
+struct FileLogger {
+    format: LogFormat,
+    level: LevelFilter,
+    sender: SyncSender<FileMessage>,
+    dropped: AtomicU64,
+}
+
+impl FileLogger {
+    fn start(file: RotatingFile, format: LogFormat, level: LevelFilter) -> anyhow::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(LOG_FILE_QUEUE);
+        std::thread::Builder::new()
+            .name("log-file".to_owned())
+            .spawn(move || write_log_file(file, receiver))?;
+        Ok(Self { format, level, sender, dropped: AtomicU64::new(0) })
+    }
+}
+
+fn write_log_file(mut file: RotatingFile, receiver: Receiver<FileMessage>) {
+    let mut failed = false;
+    for msg in receiver {
+        match msg {
+            FileMessage::Line(line) => match file.write_line(&line) {
+                Ok(()) => failed = false,
+                Err(e) => {
+                    // Only complain once per run of failures, instead of for every line.
+                    if !failed {
+                        eprintln!("failed to write to log file {:?}: {}", file.path, e);
+                    }
+                    failed = true;
+                }
+            },
+            FileMessage::Flush(done) => {
+                let _ = file.file.flush();
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && is_our_target(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Text => format!("{} {:<5} {}: {}",
+                chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                record.level(), record.target(), record.args()),
+            LogFormat::Json => match json_line(record) {
+                Some(line) => line,
+                None => return,
+            },
+        };
+
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped != 0 {
+            let msg = format!("(dropped {} log messages that couldn't be written fast enough)",
+                dropped);
+            if self.sender.try_send(FileMessage::Line(msg)).is_err() {
+                self.dropped.fetch_add(dropped, Ordering::Relaxed);
+            }
+        }
+        match self.sender.try_send(FileMessage::Line(line)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+
+    /// Wait (briefly) for everything logged so far to be written.
+    fn flush(&self) {
+        let (done, wait) = mpsc::sync_channel(1);
+        if self.sender.send(FileMessage::Flush(done)).is_ok() {
+            let _ = wait.recv_timeout(Duration::from_secs(5));
+        }
+    }
+}
+
+/// A log file that gets rotated once it reaches a maximum size.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, keep: u32) -> anyhow::Result<Self> {
+        use anyhow::Context;
+        let file = open_append(&path)
+            .with_context(|| format!("failed to open log file {:?}", path))?;
+        let size = file.metadata()
+            .with_context(|| format!("failed to get size of log file {:?}", path))?
+            .len();
+        Ok(Self { path, max_bytes, keep, file, size })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size != 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1 .. self.keep).rev() {
+                match std::fs::rename(rotated_path(&self.path, n), rotated_path(&self.path, n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+            std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut s = OsString::from(path);
+    s.push(format!(".{}", n));
+    PathBuf::from(s)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_rotate() {
+        let dir = TempDir::new("log-rotate");
+        let path = dir.path().join("daylog.log");
+        let read = |suffix: &str| {
+            let mut s = OsString::from(&path);
+            s.push(suffix);
+            std::fs::read_to_string(PathBuf::from(s)).ok()
+        };
+
+        // Each line is 10 bytes with the newline, so three fit.
+        let mut file = RotatingFile::open(path.clone(), 30, 2).unwrap();
+        for i in 0 .. 10 {
+            file.write_line(&format!("line {:04}", i)).unwrap();
+        }
+        assert_eq!(Some("line 0009\n".to_owned()), read(""));
+        assert_eq!(Some("line 0006\nline 0007\nline 0008\n".to_owned()), read(".1"));
+        assert_eq!(Some("line 0003\nline 0004\nline 0005\n".to_owned()), read(".2"));
+        assert_eq!(None, read(".3"));
+
+        // Reopening picks up where it left off.
+        drop(file);
+        let mut file = RotatingFile::open(path.clone(), 30, 2).unwrap();
+        file.write_line("line 0010").unwrap();
+        file.write_line("line 0011").unwrap();
+        file.write_line("line 0012").unwrap();
+        assert_eq!(Some("line 0012\n".to_owned()), read(""));
+        assert_eq!(Some("line 0009\nline 0010\nline 0011\n".to_owned()), read(".1"));
+
+        // Lines longer than the limit still get written, on their own.
+        file.write_line("a line longer than the limit by itself").unwrap();
+        assert_eq!(Some("a line longer than the limit by itself\n".to_owned()), read(""));
+
+        // Keeping none just starts over.
+        let mut file = RotatingFile::open(path.clone(), 30, 0).unwrap();
+        file.write_line("line 0013").unwrap();
+        assert_eq!(Some("line 0013\n".to_owned()), read(""));
+    }
+
+    #[test]
+    fn test_file_logger() {
+        let dir = TempDir::new("log-file");
+        let path = dir.path().join("daylog.log");
+        let file = RotatingFile::open(path.clone(), 1024, 1).unwrap();
+        let logger = FileLogger::start(file, LogFormat::Text, LevelFilter::Info).unwrap();
+        let target = module_path!();
+        logger.log(&Record::builder()
+            .args(format_args!("hello"))
+            .level(log::Level::Info)
+            .target(target)
+            .build());
+        logger.log(&Record::builder()
+            .args(format_args!("too verbose"))
+            .level(log::Level::Debug)
+            .target(target)
+            .build());
+        logger.log(&Record::builder()
+            .args(format_args!("not ours"))
+            .level(log::Level::Error)
+            .target("some_dependency")
+            .build());
+        logger.flush();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(1, lines.len(), "{}", contents);
+        assert!(lines[0].ends_with(&format!(" INFO  {}: hello", target)), "{}", contents);
+    }
+}
@@ -1,18 +1,61 @@
 use anyhow::Context;
+use charset::Charset;
 use mailparse::{MailHeaderMap, ParsedMail};
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
 
 pub trait MailSource {
-    fn read(&mut self, handler: Box<dyn FnMut(Mail) -> MailProcessAction>) -> anyhow::Result<RunStats>;
+    fn read(&mut self, handler: Box<dyn FnMut(Mail) -> MailProcessAction + '_>) -> anyhow::Result<RunStats>;
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct RunStats {
     pub num_processed: u64,
     pub num_removed: u64,
     pub num_kept: u64,
     pub num_left_unread: u64,
+    /// Messages that couldn't be parsed.
+    pub num_failed: u64,
+    /// Messages whose entries couldn't be added to the database. They're left unread, to be tried
+    /// again next time, so they're also counted in `num_left_unread`.
+    pub num_db_errors: u64,
 }
 
+impl RunStats {
+    /// One line describing what happened to the messages, for people running ingest by hand.
+    /// Messages left unread because of a database error count as errors, not skipped.
+    pub fn summary(&self) -> String {
+        let total = self.num_removed + self.num_kept + self.num_left_unread + self.num_failed;
+        let skipped = (self.num_kept + self.num_left_unread).saturating_sub(self.num_db_errors);
+        format!("Processed {} messages: {} stored, {} skipped, {} errors", total,
+            self.num_removed, skipped, self.num_failed + self.num_db_errors)
+    }
+
+    /// Write the stats as JSON to the given path. The file is replaced atomically, so readers never
+    /// see a partially-written file.
+    pub fn write_to_file(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp_name = path.file_name()
+            .ok_or_else(|| anyhow::anyhow!("invalid stats file path {:?}", path))?
+            .to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("failed to create {:?}", tmp_path))?;
+        serde_json::to_writer(&mut file, self)
+            .with_context(|| format!("failed to write {:?}", tmp_path))?;
+        file.write_all(b"\n")
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("failed to write {:?}", tmp_path))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to rename {:?} to {:?}", tmp_path, path))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MailProcessAction {
     /// Remove the message.
     Remove,
@@ -25,14 +68,33 @@ pub enum MailProcessAction {
 }
 
 /// An email message plucked from a MailSource.
-/// Only contains two pieces of information: the list of replied-to message IDs, and the message
-/// body text. Things like 'From' are ignored because they can be spoofed. All we care about are
-/// message IDs.
-#[derive(Debug)]
+/// Only contains a few pieces of information: the list of replied-to message IDs, the subject
+/// (which is where unsubscribe requests carry their message ID), the message body text, and any
+/// attached files. Things like 'From' are ignored because they can be spoofed. All we care about
+/// are message IDs.
+#[derive(Debug, Clone, Serialize)]
 pub struct Mail {
     pub msgid: String,
     pub reply_to: Vec<String>, // message IDs in 'References:' header
+    pub subject: String,
     pub body: String,
+    pub attachments: Vec<Attachment>,
+    /// The message had no plain text, so `body` was converted from its HTML.
+    pub from_html: bool,
+}
+
+/// A message part with "attachment" content disposition.
+#[derive(Debug, Clone, Serialize)]
+pub struct Attachment {
+    /// File name as given by the sender, so it can't be trusted to be a safe path.
+    pub filename: String,
+    /// Serialized as just its size; the contents aren't much use in JSON.
+    #[serde(rename = "size", serialize_with = "serialize_len")]
+    pub data: Vec<u8>,
+}
+
+fn serialize_len<S: serde::Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(data.len() as u64)
 }
 
 impl Mail {
@@ -48,25 +110,61 @@ impl Mail {
             .map(trim_msgid)
             .collect::<Vec<_>>();
 
+        let subject = parsed.headers.get_first_value("Subject")
+            .unwrap_or_default();
+
+        let mut attachments = vec![];
+        let mut from_html = false;
         let body = if parsed.subparts.is_empty() {
-            parsed.get_body().context("unable to parse email body text")?
+            let text = part_text(&parsed).context("unable to parse email body text")?;
+            if parsed.ctype.mimetype == "text/html" {
+                from_html = true;
+                crate::html::html_to_text(&text)
+            } else {
+                text
+            }
         } else {
             // Find parts with "inline" content disposition and "text/plain" mimetype and
-            // concatenate them together.
+            // concatenate them together. Empty ones (some clients send one alongside the HTML)
+            // would only add blank lines to the entry, so they don't count.
             let mut body = String::new();
             let mut found_something = false;
+            let mut html = None;
             for part in parsed.subparts {
                 let disposition = part.get_content_disposition()
                     .disposition;
                 let mimetype = &part.ctype.mimetype;
                 if disposition == mailparse::DispositionType::Inline && mimetype == "text/plain" {
-                    let part_body = part.get_body().context("unable to parse email message subpart body")?;
+                    let part_body = part_text(&part).context("unable to parse email message subpart body")?;
+                    if part_body.trim().is_empty() {
+                        continue;
+                    }
                     body += &part_body;
                     body += "\n\n";
                     found_something = true;
+                } else if disposition == mailparse::DispositionType::Inline
+                    && mimetype == "text/html" && html.is_none()
+                {
+                    html = Some(part_text(&part)
+                        .context("unable to parse email message HTML subpart body")?);
+                } else if disposition == mailparse::DispositionType::Attachment {
+                    attachments.push(Attachment {
+                        filename: part_filename(&part),
+                        data: part.get_body_raw()
+                            .context("unable to decode email attachment")?,
+                    });
                 }
             }
-            if !found_something {
+            // Failing plain text, make do with the HTML.
+            if let Some(text) = html.map(|html| crate::html::html_to_text(&html))
+                .filter(|text| !found_something && !text.trim().is_empty())
+            {
+                body = text;
+                found_something = true;
+                from_html = true;
+            }
+            // A reply that's just attachments is fine; they get referenced from the entry.
+            if !found_something && attachments.is_empty() {
                 anyhow::bail!("no suitable email message part with plain text found");
             }
             body
@@ -75,11 +173,100 @@ impl Mail {
         Ok(Mail {
             msgid,
             reply_to,
+            subject,
             body,
+            attachments,
+            from_html,
         })
     }
 }
 
+/// The file name of an attachment part, from its Content-Disposition, or failing that, its
+/// Content-Type.
+fn part_filename(part: &ParsedMail) -> String {
+    part.get_content_disposition().params.get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Get the text of a text/plain message part, undoing any `format=flowed` line wrapping.
+/// `get_body_raw` takes care of decoding the Content-Transfer-Encoding (quoted-printable or base64);
+/// the charset is handled by `decode_text`.
+fn part_text(part: &ParsedMail) -> anyhow::Result<String> {
+    let text = decode_text(&part.get_body_raw()?, part.ctype.params.get("charset"));
+    let param = |name: &str| part.ctype.params.get(name).map(|v| v.to_ascii_lowercase());
+    if param("format").as_deref() == Some("flowed") {
+        Ok(unflow(&text, param("delsp").as_deref() == Some("yes")))
+    } else {
+        Ok(text)
+    }
+}
+
+/// Decode text in the given charset. If there isn't one, or it's not recognized, or it's "us-ascii"
+/// (which mail clients often claim even when sending UTF-8), decode it as UTF-8, replacing any
+/// invalid bytes.
+fn decode_text(bytes: &[u8], charset: Option<&String>) -> String {
+    match charset
+        .filter(|label| !label.eq_ignore_ascii_case("us-ascii"))
+        .and_then(|label| Charset::for_label(label.as_bytes()))
+    {
+        Some(charset) => charset.decode(bytes).0.into_owned(),
+        None => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Join the lines of a `format=flowed` body (RFC 3676) back into paragraphs. A line ending in a
+/// space is "soft" and continues on the next line, as long as the quote depth is the same.
+fn unflow(text: &str, delsp: bool) -> String {
+    let mut out = String::new();
+    let mut continuing: Option<usize> = None; // quote depth of the paragraph being joined
+
+    for line in text.lines() {
+        let depth = line.chars().take_while(|&c| c == '>').count();
+        let mut content = &line[depth..];
+        // undo space-stuffing
+        if let Some(unstuffed) = content.strip_prefix(' ') {
+            content = unstuffed;
+        }
+
+        let soft = content.ends_with(' ') && content != "-- ";
+        if soft && delsp {
+            content = &content[..content.len() - 1];
+        }
+
+        if continuing != Some(depth) {
+            if continuing.is_some() {
+                // a soft line break followed by a different quote depth; treat it as hard
+                end_line(&mut out);
+            }
+            if depth > 0 {
+                out += &">".repeat(depth);
+                out.push(' ');
+            }
+        }
+        out += content;
+
+        if soft {
+            continuing = Some(depth);
+        } else {
+            out.push('\n');
+            continuing = None;
+        }
+    }
+    if continuing.is_some() {
+        end_line(&mut out);
+    }
+    out
+}
+
+fn end_line(out: &mut String) {
+    if out.ends_with(' ') {
+        out.pop();
+    }
+    out.push('\n');
+}
+
 fn trim_msgid(s: impl AsRef<str>) -> String {
     s.as_ref()
         .trim()
@@ -87,3 +274,198 @@ fn trim_msgid(s: impl AsRef<str>) -> String {
         .trim_end_matches('>')
         .to_owned()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let stats = RunStats {
+            num_processed: 6,
+            num_removed: 3,
+            num_kept: 1,
+            num_left_unread: 2,
+            num_failed: 1,
+            num_db_errors: 1,
+        };
+        assert_eq!("Processed 7 messages: 3 stored, 2 skipped, 2 errors", stats.summary());
+        assert_eq!("Processed 0 messages: 0 stored, 0 skipped, 0 errors",
+            RunStats::default().summary());
+    }
+
+    fn parse(raw: &str) -> Mail {
+        parse_bytes(raw.as_bytes())
+    }
+
+    fn parse_bytes(raw: &[u8]) -> Mail {
+        Mail::parse(mailparse::parse_mail(raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_folded_references() {
+        let mail = parse("Message-ID: <a@b>\r\n\
+            References: <CAF00@mail.example.com>\r\n\
+            \t<daylog.1.abc.def@daylog.example.com>\r\n \
+            <other@example.com>\r\n\
+            \r\n\
+            hi\r\n");
+        assert_eq!(vec!["CAF00@mail.example.com", "daylog.1.abc.def@daylog.example.com",
+            "other@example.com"], mail.reply_to);
+        assert!(crate::message_id::is_our_message_id(&mail.reply_to[1]));
+    }
+
+    #[test]
+    fn test_unflow() {
+        assert_eq!("one two three\nfour\n", unflow("one \ntwo \nthree\nfour", false));
+        assert_eq!("onetwo\n", unflow("one \ntwo", true));
+        // space-stuffed lines
+        assert_eq!(">not a quote\n From\n", unflow(" >not a quote\n  From", false));
+        // quote depth changes end the paragraph
+        assert_eq!("mine\n> quoted text\n>> older\n",
+            unflow("mine \n> quoted \n> text\n>> older", false));
+        // signature delimiter isn't a soft break
+        assert_eq!("text\n-- \nsig\n", unflow("text\n-- \nsig", false));
+    }
+
+    #[test]
+    fn test_quoted_printable() {
+        let mail = parse("Message-ID: <a@b>\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\
+            \r\n\
+            Caf=C3=A9 with Zo=C3=AB, 2+2=3D4, and a very long line that got wrapped by the =\r\n\
+            mail client.\r\n");
+        assert_eq!("Café with Zoë, 2+2=4, and a very long line that got wrapped by the \
+            mail client.\r\n", mail.body);
+    }
+
+    #[test]
+    fn test_transfer_encodings_multipart() {
+        let mail = parse("Message-ID: <a@b>\r\n\
+            Content-Type: multipart/alternative; boundary=XX\r\n\
+            \r\n\
+            --XX\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            Q2Fmw6kgd2l0aCBab8OrLg==\r\n\
+            --XX\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\
+            \r\n\
+            =E2=98=95 x=3Dy\r\n\
+            --XX--\r\n");
+        assert_eq!("Café with Zoë.\n\n\u{2615} x=y\r\n\n\n", mail.body);
+    }
+
+    #[test]
+    fn test_latin1() {
+        let mail = parse_bytes(b"Message-ID: <a@b>\r\n\
+            Content-Type: text/plain; charset=ISO-8859-1\r\n\
+            Content-Transfer-Encoding: 8bit\r\n\
+            \r\n\
+            Cr\xe8me br\xfbl\xe9e \xe0 la caf\xe9\r\n");
+        assert_eq!("Crème brûlée à la café\r\n", mail.body);
+    }
+
+    #[test]
+    fn test_charset_fallback() {
+        // UTF-8 without a charset, or claiming to be ASCII
+        for ctype in ["text/plain", "text/plain; charset=us-ascii", "text/plain; charset=bogus"] {
+            let raw = format!("Message-ID: <a@b>\r\nContent-Type: {}\r\n\r\nZo\u{eb}\r\n", ctype);
+            assert_eq!("Zoë\r\n", parse(&raw).body, "{}", ctype);
+        }
+
+        // invalid UTF-8
+        let mail = parse_bytes(b"Message-ID: <a@b>\r\n\r\nZo\xeb\r\n");
+        assert_eq!("Zo\u{fffd}\r\n", mail.body);
+    }
+
+    #[test]
+    fn test_flowed() {
+        let mail = parse("Message-ID: <a@b>\r\n\
+            Content-Type: text/plain; charset=utf-8; format=flowed\r\n\
+            \r\n\
+            Went for a long walk along the river, then had lunch at the new place \r\n\
+            downtown. It was pretty good.\r\n\
+            \r\n\
+            Second paragraph.\r\n");
+        assert_eq!("Went for a long walk along the river, then had lunch at the new place \
+            downtown. It was pretty good.\n\nSecond paragraph.\n", mail.body);
+    }
+
+    #[test]
+    fn test_flowed_multipart() {
+        let mail = parse("Message-ID: <a@b>\r\n\
+            Content-Type: multipart/alternative; boundary=XX\r\n\
+            \r\n\
+            --XX\r\n\
+            Content-Type: text/plain; format=flowed; delsp=yes\r\n\
+            \r\n\
+            Some\x20\r\n\
+            thing\r\n\
+            --XX--\r\n");
+        assert_eq!("Something\n\n\n", mail.body);
+    }
+
+    #[test]
+    fn test_empty_text_part() {
+        let raw = |html: &str| format!("Message-ID: <a@b>\r\n\
+            Content-Type: multipart/mixed; boundary=XX\r\n\
+            \r\n\
+            --XX\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            \r\n\
+            \x20\r\n\
+            --XX\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\
+            \r\n\
+            {}\r\n\
+            --XX--\r\n", html);
+
+        // The HTML is used instead.
+        let mail = parse(&raw("<p>went for a walk</p>"));
+        assert_eq!("went for a walk\n", mail.body);
+        assert!(mail.from_html);
+
+        let err = Mail::parse(mailparse::parse_mail(raw("<div><br></div>").as_bytes()).unwrap())
+            .unwrap_err();
+        assert!(err.to_string().contains("no suitable email message part"), "{:#}", err);
+
+        // With an attachment, there's still something to add to the entry.
+        let mail = parse("Message-ID: <a@b>\r\n\
+            Content-Type: multipart/mixed; boundary=XX\r\n\
+            \r\n\
+            --XX\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            \r\n\
+            \r\n\
+            --XX\r\n\
+            Content-Type: image/jpeg\r\n\
+            Content-Disposition: attachment; filename=walk.jpg\r\n\
+            \r\n\
+            jpeg\r\n\
+            --XX--\r\n");
+        assert_eq!("", mail.body);
+        assert!(!mail.from_html);
+        assert_eq!(1, mail.attachments.len());
+    }
+
+    #[test]
+    fn test_html_only() {
+        let mail = parse("Message-ID: <a@b>\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\
+            \r\n\
+            <html><body><p>Went for a walk &amp; read.</p></body></html>\r\n");
+        assert_eq!("Went for a walk & read.\n", mail.body);
+        assert!(mail.from_html);
+
+        let mail = parse("Message-ID: <a@b>\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            <p>not HTML</p>\r\n");
+        assert_eq!("<p>not HTML</p>\r\n", mail.body);
+        assert!(!mail.from_html);
+    }
+}
@@ -1,56 +1,162 @@
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 use crate::mail::{Mail, MailProcessAction, MailSource, RunStats};
+use mailparse::{MailHeaderMap, ParsedMail};
 use maildir::Maildir;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+/// Maildir flag for messages daylog has processed, as opposed to ones it kept, or that were marked
+/// as seen by something else, so `--reprocess` knows to skip them. Lowercase flags are for local
+/// use, like this.
+const PROCESSED_FLAG: char = 'd';
 
 pub struct DaylogMaildir {
     maildir: Maildir,
+    delete_processed: bool,
+    quarantine_dir: Option<PathBuf>,
+    cutoff: Option<DateTime<Utc>>,
+    limit: Option<u64>,
+    reprocess: bool,
 }
 
 impl DaylogMaildir {
-    pub fn open(path: &Path) -> Self {
+    /// Messages with a 'Date' header before `cutoff` are skipped and kept, instead of being
+    /// processed. Each read stops after processing `limit` messages, leaving the rest for the next
+    /// one. With `reprocess`, messages in 'cur' that daylog didn't process are read too, after the
+    /// new ones. Messages that can't be parsed are moved to `quarantine_dir` if it's given, or else
+    /// kept.
+    pub fn open(
+        path: &Path,
+        delete_processed: bool,
+        quarantine_dir: Option<&Path>,
+        cutoff: Option<DateTime<Utc>>,
+        limit: Option<u64>,
+        reprocess: bool,
+    ) -> Self {
         Self {
             maildir: Maildir::from(path.to_owned()),
+            delete_processed,
+            quarantine_dir: quarantine_dir.map(Path::to_owned),
+            cutoff,
+            limit,
+            reprocess,
         }
     }
 }
 
+enum Entry {
+    Mail(Mail),
+    TooOld(DateTime<Utc>),
+}
+
+/// Move a file into the directory (creating it if need be), keeping its name.
+fn move_into(file: &Path, dir: &Path) -> anyhow::Result<PathBuf> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create directory {:?}", dir))?;
+    let dest = dir.join(file.file_name().context("message has no file name")?);
+    if std::fs::rename(file, &dest).is_err() {
+        // Probably on a different filesystem.
+        std::fs::copy(file, &dest)
+            .with_context(|| format!("failed to copy {:?} to {:?}", file, dest))?;
+        std::fs::remove_file(file)
+            .with_context(|| format!("failed to remove {:?}", file))?;
+    }
+    Ok(dest)
+}
+
+/// If the message's 'Date' header is before the cutoff, return that date. Messages without a
+/// usable date are never too old.
+fn too_old(parsed: &ParsedMail, cutoff: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    parsed.headers.get_first_value("Date")
+        .and_then(|date| mailparse::dateparse(&date).ok())
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .filter(|date| *date < cutoff)
+}
+
 impl MailSource for DaylogMaildir {
-    fn read(&mut self, mut handler: Box<dyn FnMut(Mail) -> MailProcessAction>)
+    fn read(&mut self, mut handler: Box<dyn FnMut(Mail) -> MailProcessAction + '_>)
         -> anyhow::Result<RunStats>
     {
         let mut stats = RunStats::default();
-        for entry_result in self.maildir.list_new() {
+        let processed_flags = format!("S{}", PROCESSED_FLAG);
+
+        // Changing flags renames files in 'cur', so list it up front rather than while doing that.
+        let cur = if self.reprocess {
+            self.maildir.list_cur()
+                .filter(|entry| !matches!(entry, Ok(e) if e.flags().contains(PROCESSED_FLAG)))
+                .collect()
+        } else {
+            vec![]
+        };
+        let entries = self.maildir.list_new().map(|entry| (entry, false))
+            .chain(cur.into_iter().map(|entry| (entry, true)));
+
+        for (entry_result, in_cur) in entries {
+            if self.limit.is_some_and(|limit| stats.num_processed >= limit) {
+                info!("stopping after processing {} messages", stats.num_processed);
+                break;
+            }
             let mut entry = entry_result.context("failed to iterate maildir entries")?;
             let id = entry.id().to_owned();
 
             let action = match entry.parsed()
                 .map_err(|e| format!("failed to parse mail message {}: {}", id, e))
                 .and_then(|unstructured| {
+                    if let Some(date) = self.cutoff.and_then(|cutoff| too_old(&unstructured, cutoff)) {
+                        return Ok(Entry::TooOld(date));
+                    }
                     Mail::parse(unstructured)
+                        .map(Entry::Mail)
                         .map_err(|e| format!("failed to parse mail message {} (inner): {}", id, e))
                 })
             {
-                Ok(mail) => {
+                Ok(Entry::Mail(mail)) => {
                     stats.num_processed += 1;
                     handler(mail)
                 }
+                Ok(Entry::TooOld(date)) => {
+                    info!("skipping message {}: its date {} is before {}",
+                        id, date, self.cutoff.unwrap_or_default());
+                    MailProcessAction::Keep
+                }
                 Err(msg) => {
                     eprintln!("Failed to parse mail message {}: {}", id, msg);
-                    MailProcessAction::Keep
+                    stats.num_failed += 1;
+                    match &self.quarantine_dir {
+                        Some(dir) => {
+                            let dest = move_into(entry.path(), dir)
+                                .with_context(|| format!("failed to quarantine message {}", id))?;
+                            warn!("moved unparseable message {} to {:?}", id, dest);
+                        }
+                        None if !in_cur => {
+                            self.maildir.move_new_to_cur(&id)
+                                .with_context(|| format!("failed to move message {} from new to cur", id))?;
+                        }
+                        None => (),
+                    }
+                    continue;
                 }
             };
             match action {
                 MailProcessAction::Remove => {
-                    //self.maildir.delete(entry.id())
-                    // for now, let's save them as seen instead.
-                    self.maildir.move_new_to_cur_with_flags(entry.id(), "S")
-                        .with_context(|| format!("failed to remove message {:?}", entry.id()))?;
+                    if self.delete_processed {
+                        self.maildir.delete(&id)
+                            .with_context(|| format!("failed to delete message {:?}", id))?;
+                    } else if in_cur {
+                        self.maildir.add_flags(&id, &processed_flags)
+                            .with_context(|| format!("failed to set flags on message {:?}", id))?;
+                    } else {
+                        // Save them as seen instead, in case they need to be re-processed.
+                        self.maildir.move_new_to_cur_with_flags(&id, &processed_flags)
+                            .with_context(|| format!("failed to remove message {:?}", id))?;
+                    }
                     stats.num_removed += 1;
                 }
                 MailProcessAction::Keep => {
-                    self.maildir.move_new_to_cur(&id)
-                        .with_context(|| format!("failed to move message {} from new to cur", id))?;
+                    if !in_cur {
+                        self.maildir.move_new_to_cur(&id)
+                            .with_context(|| format!("failed to move message {} from new to cur", id))?;
+                    }
                     stats.num_kept += 1;
                 }
                 MailProcessAction::LeaveUnread => {
@@ -61,3 +167,125 @@ impl MailSource for DaylogMaildir {
         Ok(stats)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_max_age() {
+        let dir = TempDir::new("maildir-max-age");
+        let maildir = Maildir::from(dir.path().to_owned());
+        maildir.create_dirs().unwrap();
+        let message = |msgid: &str, date: &str| format!("Message-ID: <{}>\r\nDate: {}\r\n\r\nhi\r\n",
+            msgid, date);
+        maildir.store_new(message("old@example.com", "Sun, 8 Jul 2001 18:00:00 +0000").as_bytes())
+            .unwrap();
+        maildir.store_new(message("recent@example.com", &Utc::now().to_rfc2822()).as_bytes())
+            .unwrap();
+
+        let cutoff = Utc::now() - chrono::Duration::days(30);
+        let mut source = DaylogMaildir::open(dir.path(), false, None, Some(cutoff), None, false);
+        let mut seen = vec![];
+        let stats = source.read(Box::new(|mail| {
+            seen.push(mail.msgid);
+            MailProcessAction::Remove
+        })).unwrap();
+
+        assert_eq!(vec!["recent@example.com"], seen);
+        assert_eq!((1, 1, 1, 0),
+            (stats.num_processed, stats.num_removed, stats.num_kept, stats.num_left_unread));
+        assert_eq!(0, maildir.count_new());
+        assert_eq!(2, maildir.count_cur());
+    }
+
+    #[test]
+    fn test_reprocess() {
+        let dir = TempDir::new("maildir-reprocess");
+        let maildir = Maildir::from(dir.path().to_owned());
+        maildir.create_dirs().unwrap();
+        let message = |msgid: &str| format!("Message-ID: <{}>\r\n\r\nhi\r\n", msgid);
+        let flags = |msgid: &str| {
+            maildir.list_cur().map(Result::unwrap)
+                .find(|e| std::fs::read_to_string(e.path()).unwrap().contains(msgid))
+                .unwrap()
+                .flags()
+                .to_owned()
+        };
+        maildir.store_new(message("processed@example.com").as_bytes()).unwrap();
+        maildir.store_new(message("kept@example.com").as_bytes()).unwrap();
+        // Marked as seen by something other than daylog.
+        maildir.store_cur_with_flags(message("seen@example.com").as_bytes(), "S").unwrap();
+
+        let read = |reprocess: bool, remove: &[&str]| {
+            let mut seen = vec![];
+            DaylogMaildir::open(dir.path(), false, None, None, None, reprocess)
+                .read(Box::new(|mail| {
+                    let action = if remove.contains(&mail.msgid.as_str()) {
+                        MailProcessAction::Remove
+                    } else {
+                        MailProcessAction::Keep
+                    };
+                    seen.push(mail.msgid);
+                    action
+                }))
+                .unwrap();
+            seen.sort();
+            seen
+        };
+
+        // New messages are moved to cur, and only processed ones get the flag.
+        assert_eq!(vec!["kept@example.com", "processed@example.com"],
+            read(false, &["processed@example.com"]));
+        assert_eq!(0, maildir.count_new());
+        assert_eq!("Sd", flags("processed@example.com"));
+        assert_eq!("", flags("kept@example.com"));
+        assert_eq!("S", flags("seen@example.com"));
+
+        // Without reprocessing, there's nothing left to read.
+        assert!(read(false, &[]).is_empty());
+
+        // Reprocessing reads the ones without the flag, and flags them if they're processed now.
+        assert_eq!(vec!["kept@example.com", "seen@example.com"],
+            read(true, &["seen@example.com"]));
+        assert_eq!("Sd", flags("seen@example.com"));
+        assert_eq!("", flags("kept@example.com"));
+
+        assert_eq!(vec!["kept@example.com"], read(true, &[]));
+        assert_eq!(3, maildir.count_cur());
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let dir = TempDir::new("maildir-quarantine");
+        let maildir = Maildir::from(dir.path().join("maildir"));
+        maildir.create_dirs().unwrap();
+        let quarantine = dir.path().join("quarantine");
+        let read = |quarantine: Option<&Path>| {
+            maildir.store_new(b"Message-ID: <good@example.com>\r\n\r\nhi\r\n").unwrap();
+            // No Message-ID.
+            maildir.store_new(b"Subject: bad\r\n\r\nhi\r\n").unwrap();
+            DaylogMaildir::open(maildir.path(), false, quarantine, None, None, false)
+                .read(Box::new(|_| MailProcessAction::Remove))
+                .unwrap()
+        };
+
+        let stats = read(Some(&quarantine));
+        assert_eq!((1, 1, 0, 1),
+            (stats.num_processed, stats.num_removed, stats.num_kept, stats.num_failed));
+        assert_eq!(0, maildir.count_new());
+        assert_eq!(1, maildir.count_cur());
+        let quarantined = std::fs::read_dir(&quarantine).unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["Subject: bad\r\n\r\nhi\r\n"], quarantined);
+
+        // Without a quarantine directory, they're marked as seen, like before.
+        let stats = read(None);
+        assert_eq!((1, 1, 0, 1),
+            (stats.num_processed, stats.num_removed, stats.num_kept, stats.num_failed));
+        assert_eq!(0, maildir.count_new());
+        assert_eq!(3, maildir.count_cur());
+    }
+}
@@ -1,31 +1,89 @@
 #[macro_use] extern crate log;
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by --quiet.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Like `println!`, but for messages that only report what was done, which --quiet suppresses.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !$crate::QUIET.load(std::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod address;
+mod attachments;
+mod check;
 mod config;
 mod db;
+mod delete;
+mod diff;
+mod exit;
+mod frequency;
+mod gaps;
+mod html;
+mod imap;
 mod ingest;
+mod logging;
 mod message_id;
+mod metrics;
 mod mail;
 mod maildir;
+mod maintenance;
+mod pgp;
+mod reprocess;
 mod run;
+mod search;
 mod send;
+mod stats;
+mod status;
+mod stdin_mail;
+#[cfg(test)]
+mod testutil;
 mod time;
+mod timezone;
 mod user;
+mod weekdays;
 
 use chrono::NaiveDate;
 use clap::Parser;
-use crate::config::{Config, ConfigParser};
+use crate::config::Config;
+use crate::logging::LogFormat;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
 #[derive(Parser, Debug)]
 #[clap(version, author, about)]
 struct Args {
-    #[clap(value_parser = ConfigParser)]
-    config: Config,
+    /// Path to the config file.
+    config: PathBuf,
 
     #[clap(subcommand)]
     op: Operation,
 
     #[clap(action = clap::ArgAction::Count, short('v'), long)]
     verbose: u8,
+
+    /// Only print errors, and output that was asked for (like from `status`), not messages saying
+    /// what was done. For cron jobs.
+    #[clap(short('q'), long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Format of log messages written to stderr.
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Use this database instead of the one in the config file.
+    #[clap(long, env = "DAYLOG_DATABASE")]
+    database: Option<PathBuf>,
+
+    /// Read the secret key from here instead of where the config file says. Takes the same forms
+    /// as `secret_key` in the config file.
+    #[clap(long)]
+    secret_key: Option<config::SecretKeySource>,
 }
 
 #[derive(Parser, Debug)]
@@ -36,10 +94,46 @@ enum Operation {
     /// Send a user their daily email.
     Send(SendArgs),
 
+    /// Send a short test email to the given address, to check that sending mail works. This doesn't
+    /// need a user in the database.
+    SendTest(SendTestArgs),
+
     /// Run as a service, blocking indefinitely. Send all users their daily mail at the
-    /// pre-configured time, and process incoming mail periodically.
+    /// pre-configured time, and process incoming mail periodically. Send it SIGUSR1 to make it
+    /// process incoming mail immediately.
     Run(RunArgs),
 
+    /// Show when each user will next be sent their daily email.
+    Status(StatusArgs),
+
+    /// Show how many entries each user has written, and their current streak of consecutive days.
+    Stats(StatsArgs),
+
+    /// Delete a user's entry for a day.
+    Delete(DeleteArgs),
+
+    /// List the days a user has no entry for.
+    Gaps(GapsArgs),
+
+    /// Find a user's entries containing all the given words.
+    Search(SearchArgs),
+
+    /// Check that the config file and the files it refers to are usable, without changing anything.
+    Check(CheckArgs),
+
+    /// Write a consistent copy of the database to the given path. This is safe to do while the
+    /// service is running, unlike copying the database file.
+    Backup(BackupArgs),
+
+    /// Check the database for corruption, and optionally compact it.
+    Maintenance(MaintenanceArgs),
+
+    /// Make a user's entries again from the replies they came from, using the current config.
+    Reprocess(ReprocessArgs),
+
+    /// Generate a new secret key and write it to the path given in the config file.
+    GenKey(GenKeyArgs),
+
     /// Read a raw email from standard input, and write to standard output the sanitized version of
     /// it. This does not alter the database.
     MailTransform(MailTransformArgs),
@@ -50,25 +144,80 @@ pub struct IngestArgs {
     /// show what would be done, but do not make any changes
     #[clap(long)]
     dry_run: bool,
+
+    /// Write ingest statistics as JSON to this file. Overrides `stats_file` from the config.
+    #[clap(long)]
+    stats_file: Option<std::path::PathBuf>,
+
+    /// Truncate entries longer than this many bytes. Overrides `max_body_bytes` from the config.
+    #[clap(long)]
+    max_body_bytes: Option<usize>,
+
+    /// Read a single message from standard input instead of the configured incoming mail source,
+    /// for use as a mail delivery hook. Exits unsuccessfully if the message wasn't added.
+    #[clap(long)]
+    stdin: bool,
+
+    /// Stop after processing this many messages, leaving the rest for the next run.
+    #[clap(long, conflicts_with = "stdin")]
+    limit: Option<u64>,
+
+    /// Also process messages that were already read (moved to the maildir's 'cur' directory) but
+    /// not processed. Messages daylog processed are marked with the 'd' flag, and skipped; ones
+    /// processed before daylog started marking them aren't, so this would add them again. Use
+    /// with --since to only look at recent messages.
+    #[clap(long, conflicts_with = "stdin")]
+    reprocess: bool,
+
+    /// Skip messages with a 'Date' header before this date (YYYY-MM-DD, in UTC).
+    #[clap(long, conflicts_with = "stdin", value_parser = send::parse_date_override)]
+    since: Option<NaiveDate>,
+
+    /// Print a line every this many messages, to show how far along a big batch is.
+    #[clap(long, conflicts_with = "stdin", value_parser = clap::value_parser!(u64).range(1..))]
+    progress: Option<u64>,
 }
 
 #[derive(Parser, Debug)]
 pub struct SendArgs {
     /// Username
-    #[clap(long)]
-    username: String,
+    #[clap(long, required_unless_present = "all_users")]
+    username: Option<String>,
+
+    /// Send to every user instead of just one: each enabled user who gets an email on their
+    /// local date, as the service would have.
+    #[clap(long, conflicts_with = "username")]
+    all_users: bool,
 
     /// Different email address to send to.
-    #[clap(long("email"))]
+    #[clap(long("email"), conflicts_with = "all_users")]
     email_override: Option<String>,
 
-    /// Send email for the given date instead of today.
+    /// Send email for the given date instead of today. Past entries in it are looked up by the
+    /// local dates they were written on, whatever timezone the user was in then.
     #[clap(long("date"))]
     date_override: Option<String>,
 
+    /// Work out what today is using this timezone instead of the user's. Accepts the same formats
+    /// as the users table: an IANA name like "Asia/Tokyo", or an offset like "+09:00".
+    #[clap(long("timezone"), value_parser = timezone::UserTimezone::parse, allow_hyphen_values = true,
+        conflicts_with_all = ["date_override", "all_users"])]
+    timezone_override: Option<timezone::UserTimezone>,
+
     /// Print the email to stdout, but do not send it.
     #[clap(long)]
     dry_run: bool,
+
+    /// With --dry-run, write the email to this file instead of stdout.
+    #[clap(long, requires = "dry_run", conflicts_with = "all_users")]
+    output: Option<std::path::PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct SendTestArgs {
+    /// Address to send the test email to.
+    #[clap(long)]
+    email: String,
 }
 
 #[derive(Parser, Debug)]
@@ -76,6 +225,114 @@ pub struct RunArgs {
     /// log what would be done, but do not make any changes
     #[clap(long)]
     dry_run: bool,
+
+    /// Print when each user will next be emailed, and exit instead of running.
+    #[clap(long)]
+    plan: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct StatusArgs {
+    /// Print the status as JSON.
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct StatsArgs {
+    /// Only show stats for this user.
+    #[clap(long)]
+    username: Option<String>,
+
+    /// Print the stats as JSON.
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct DeleteArgs {
+    /// Username
+    #[clap(long)]
+    username: String,
+
+    /// Date of the entry to delete, as YYYY-MM-DD.
+    #[clap(long, value_parser = send::parse_date_override)]
+    date: NaiveDate,
+
+    /// Don't ask for confirmation.
+    #[clap(long)]
+    yes: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct GapsArgs {
+    /// Username
+    #[clap(long)]
+    username: String,
+
+    /// First date to look at. Defaults to the date of the user's first entry.
+    #[clap(long, value_parser = send::parse_date_override)]
+    from: Option<NaiveDate>,
+
+    /// Last date to look at. Defaults to yesterday, in the user's timezone.
+    #[clap(long, value_parser = send::parse_date_override)]
+    to: Option<NaiveDate>,
+}
+
+#[derive(Parser, Debug)]
+pub struct SearchArgs {
+    /// Username
+    #[clap(long)]
+    username: String,
+
+    /// Words to search for. Entries containing all of them (ignoring case) are listed.
+    #[clap(required = true)]
+    query: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct CheckArgs {
+}
+
+#[derive(Parser, Debug)]
+pub struct BackupArgs {
+    /// Where to write the copy. A file already there is replaced.
+    path: std::path::PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct MaintenanceArgs {
+    /// Also rebuild the database to reclaim space left by deleted entries. This needs the database
+    /// to itself, so stop the service first.
+    #[clap(long)]
+    vacuum: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct ReprocessArgs {
+    /// Username
+    #[clap(long)]
+    username: String,
+
+    /// First date to reprocess. Defaults to the date of the user's first entry.
+    #[clap(long, value_parser = send::parse_date_override)]
+    from: Option<NaiveDate>,
+
+    /// Last date to reprocess. Defaults to the date of the user's last entry.
+    #[clap(long, value_parser = send::parse_date_override)]
+    to: Option<NaiveDate>,
+
+    /// Print how the entries would change instead of changing them.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct GenKeyArgs {
+    /// Overwrite the key file if it already exists. Messages sent using the old key will no longer
+    /// be recognized.
+    #[clap(long)]
+    force: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -83,26 +340,110 @@ pub struct MailTransformArgs {
     /// Print the plain-text mail body without applying any transformations on it.
     #[clap(long)]
     pre_transform: bool,
+
+    /// Print a diff between the plain-text mail body and the transformed version of it.
+    #[clap(long, conflicts_with = "pre_transform")]
+    diff: bool,
+
+    /// How to treat quoted text. Overrides `quote_handling` from the config.
+    #[clap(long, value_enum)]
+    quote_handling: Option<config::QuoteHandling>,
+
+    /// Print everything parsed out of the message as JSON: its Message-ID, the message IDs it
+    /// replies to, its subject, and its untransformed body. Useful to see why a reply didn't match
+    /// any daily email.
+    #[clap(long, conflicts_with_all = ["pre_transform", "diff", "quote_handling"])]
+    json: bool,
+}
+
+impl Operation {
+    /// Exit code for failures of this operation, unless there's a more specific one.
+    fn failure_code(&self) -> u8 {
+        match self {
+            Operation::Ingest(_) | Operation::MailTransform(_) => exit::INCOMING_MAIL,
+            Operation::Send(_) | Operation::SendTest(_) => exit::SEND,
+            Operation::Check(_) => exit::CONFIG,
+            Operation::Backup(_) | Operation::Maintenance(_) => exit::DATABASE,
+            _ => exit::FAILURE,
+        }
+    }
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
     let args = Args::parse();
+    QUIET.store(args.quiet, Ordering::Relaxed);
 
-    stderrlog::new()
-        .module(module_path!())
-        .verbosity(args.verbose as usize)
-        .init()?;
+    let overrides = config::Overrides {
+        database_path: args.database.clone(),
+        secret_key: args.secret_key.clone(),
+    };
+    let config = match Config::try_from_path(args.config.as_os_str()) {
+        Ok(config) => config.with_overrides(overrides),
+        Err(msg) => {
+            eprintln!("Error: {}", msg);
+            return ExitCode::from(exit::CONFIG);
+        }
+    };
+
+    if let Err(e) = logging::init(args.log_format, args.verbose, &config) {
+        eprintln!("Error: {:?}", e);
+        return ExitCode::from(exit::CONFIG);
+    }
 
     debug!("{:#?}", args);
+    debug!("{:#?}", config);
+
+    let failure_code = args.op.failure_code();
+    let result = run_op(&config, args.op);
 
-    match args.op {
-        Operation::Ingest(op) => ingest::ingest(&args.config, op),
-        Operation::Send(op) => send::send(&args.config, send::Mode::Args(op)),
-        Operation::Run(op) => run::run(&args.config, op),
+    // Make sure everything got written to the log file, if there is one.
+    log::logger().flush();
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(exit::code(&e, failure_code))
+        }
+    }
+}
+
+fn run_op(config: &Config, op: Operation) -> anyhow::Result<()> {
+    match op {
+        Operation::Ingest(op) => {
+            // With --stdin, the exit code says what happened.
+            let stdin = op.stdin;
+            let stats = ingest::ingest(config, op)?;
+            if !stdin {
+                say!("{}", stats.summary());
+            }
+            Ok(())
+        }
+        Operation::Send(op) => send::send(config, send::Mode::Args(op)),
+        Operation::SendTest(op) => send::send_test(config, op),
+        Operation::Run(op) => run::run(config, op),
+        Operation::Status(op) => status::status(config, op),
+        Operation::Stats(op) => stats::stats(config, op),
+        Operation::Delete(op) => delete::delete(config, op),
+        Operation::Gaps(op) => gaps::gaps(config, op),
+        Operation::Search(op) => search::search(config, op),
+        Operation::Check(op) => check::check(config, op),
+        Operation::Backup(op) => {
+            db::Database::open_read_only(&config.database_path)?.backup(&op.path)?;
+            say!("backed up the database to {:?}", op.path);
+            Ok(())
+        }
+        Operation::Maintenance(op) => maintenance::maintenance(config, op),
+        Operation::Reprocess(op) => reprocess::reprocess(config, op),
+        Operation::GenKey(op) => {
+            message_id::gen_secret_key(&config.secret_key, op.force)?;
+            say!("wrote new secret key to {}", config.secret_key);
+            Ok(())
+        }
         Operation::MailTransform(op) => {
             let mut raw_input = vec![];
             std::io::Read::read_to_end(&mut std::io::stdin(), &mut raw_input).unwrap();
-            let processed = ingest::mail_transform(&args.config, op, &raw_input)?;
+            let processed = ingest::mail_transform(config, op, &raw_input)?;
             println!("{}", processed);
             Ok(())
         }
@@ -113,5 +454,5 @@ pub fn todays_date<Tz>(tz: &Tz) -> NaiveDate
     where Tz: chrono::TimeZone,
           Tz::Offset: std::fmt::Display,
 {
-    chrono::Utc::now().with_timezone(tz).date().naive_local()
+    chrono::Utc::now().with_timezone(tz).date_naive()
 }
@@ -0,0 +1,31 @@
+use crate::MaintenanceArgs;
+use crate::config::Config;
+use crate::db::Database;
+
+pub fn maintenance(config: &Config, args: MaintenanceArgs) -> anyhow::Result<()> {
+    let db = Database::open(&config.database_path)?;
+
+    let problems = db.integrity_check()?;
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        anyhow::bail!("database integrity check found {} problem(s)", problems.len());
+    }
+    say!("database integrity check: ok");
+
+    if args.vacuum {
+        let before = file_size(config)?;
+        db.vacuum()?;
+        let after = file_size(config)?;
+        say!("vacuumed database: {} bytes -> {} bytes", before, after);
+    }
+    Ok(())
+}
+
+fn file_size(config: &Config) -> anyhow::Result<u64> {
+    use anyhow::Context;
+    Ok(std::fs::metadata(&config.database_path)
+        .with_context(|| format!("failed to get size of database {:?}", config.database_path))?
+        .len())
+}
@@ -1,14 +1,54 @@
 use anyhow::{anyhow, bail, Context};
 use base64::Engine;
-use base64::engine::general_purpose::URL_SAFE;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE};
 use chrono::NaiveDate;
+use crate::config::SecretKeySource;
 use ring::aead;
-use std::fs::File;
-use std::io::{self, Read};
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::sync::OnceLock;
 
-const PREFIX: &str = "daylog.1";
-const SECRET_KEY_LEN: usize = 32;
+/// Message IDs look like `daylog.<version>.<nonce>.<encrypted>`. The version determines how the
+/// rest is encrypted, so that if it ever needs to change, IDs in emails already sent can still be
+/// read.
+const IDENT: &str = "daylog";
+
+/// Version used for new message IDs.
+const CURRENT_VERSION: Version = Version::V1;
+
+pub const SECRET_KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    /// ChaCha20-Poly1305.
+    V1,
+}
+
+impl Version {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "1" => Some(Version::V1),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Version::V1 => "1",
+        }
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Version::V1 => &aead::CHACHA20_POLY1305,
+        }
+    }
+
+    /// The ident and version; this is also used as the additional authenticated data.
+    fn prefix(self) -> String {
+        format!("{}.{}", IDENT, self.as_str())
+    }
+}
 
 fn base64_decode(s: &str) -> Result<Vec<u8>, base64::DecodeError> {
     URL_SAFE.decode(s)
@@ -18,116 +58,426 @@ fn base64_encode(bytes: &[u8]) -> String {
     URL_SAFE.encode(bytes)
 }
 
-pub fn read_secret_key(path: &Path) -> io::Result<[u8; SECRET_KEY_LEN]> {
+/// A key read from standard input, kept for when it's read again on reload.
+static STDIN_KEY: OnceLock<[u8; SECRET_KEY_LEN]> = OnceLock::new();
+
+pub fn read_secret_key(source: &SecretKeySource) -> anyhow::Result<[u8; SECRET_KEY_LEN]> {
+    match source {
+        SecretKeySource::File(path) => {
+            let mut bytes = vec![];
+            File::open(path)?.read_to_end(&mut bytes)?;
+            parse_secret_key(&bytes)
+        }
+        SecretKeySource::Env(name) => {
+            let value = std::env::var(name)?;
+            let bytes = STANDARD.decode(value.trim())
+                .context("secret key in the environment must be base64")?;
+            parse_secret_key(&bytes)
+        }
+        SecretKeySource::Stdin => {
+            if let Some(key) = STDIN_KEY.get() {
+                return Ok(*key);
+            }
+            let mut bytes = vec![];
+            io::stdin().lock().read_to_end(&mut bytes)?;
+            let key = parse_secret_key(&bytes)?;
+            Ok(*STDIN_KEY.get_or_init(|| key))
+        }
+    }
+}
+
+/// The key must be exactly `SECRET_KEY_LEN` bytes, except that a single trailing newline (as added
+/// by some text editors) is ignored.
+fn parse_secret_key(bytes: &[u8]) -> anyhow::Result<[u8; SECRET_KEY_LEN]> {
+    let trimmed = match bytes {
+        [key @ .., b'\n'] if key.len() == SECRET_KEY_LEN => key,
+        _ => bytes,
+    };
+    trimmed.try_into()
+        .map_err(|_| anyhow!("secret key must be exactly {} bytes, but it is {} bytes",
+            SECRET_KEY_LEN, bytes.len()))
+}
+
+/// Generate a new random secret key and write it to the given file. Unless `force` is set, this
+/// refuses to overwrite an existing file.
+pub fn gen_secret_key(source: &SecretKeySource, force: bool) -> anyhow::Result<()> {
+    use ring::rand::{SecureRandom, SystemRandom};
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let path = match source {
+        SecretKeySource::File(path) => path,
+        _ => bail!("the secret key is read from {}, not a file, so a new one can't be written",
+            source),
+    };
+
     let mut key = [0u8; SECRET_KEY_LEN];
-    let mut file = File::open(path)?;
-    file.read_exact(&mut key)?;
-    Ok(key)
+    SystemRandom::new().fill(&mut key)
+        .map_err(|_| anyhow!("failed to generate random key"))?;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .create_new(!force)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| {
+            if e.kind() == io::ErrorKind::AlreadyExists {
+                anyhow!("secret key file {:?} already exists; use --force to overwrite it", path)
+            } else {
+                anyhow::Error::new(e).context(format!("failed to open {:?}", path))
+            }
+        })?;
+
+    // in case we're overwriting an existing file that had different permissions
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("failed to set permissions on {:?}", path))?;
+
+    file.write_all(&key)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("failed to write {:?}", path))?;
+
+    Ok(())
 }
 
+/// Whether the message ID looks like one of ours, of any version.
 pub fn is_our_message_id(s: &str) -> bool {
-    s.starts_with(PREFIX)
+    id_part(s).strip_prefix(IDENT).is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// The part of a message ID before the '@hostname', without any surrounding whitespace or angle
+/// brackets, which is what our IDs are made of.
+fn id_part(s: &str) -> &str {
+    let s = s.trim().trim_start_matches('<').trim_end_matches('>').trim();
+    s.split('@').next().unwrap() // can't panic; split always yields something
 }
 
 pub fn gen_message_id(username: &str, date: NaiveDate, key_bytes: [u8; SECRET_KEY_LEN]) -> anyhow::Result<String> {
     let plaintext = format!("{}.{}", username, date.format("%Y-%m-%d"));
 
-    let key = aead_key(key_bytes);
-    let nonce = TimeNonce::new();
+    let prefix = CURRENT_VERSION.prefix();
+    let key = aead_key(CURRENT_VERSION, key_bytes);
+    let nonce = TimeNonce::new()?;
 
     let mut encrypted = plaintext.into_bytes();
-    key.seal_in_place_append_tag(nonce.as_aead(), ring::aead::Aad::from(PREFIX.as_bytes()), &mut encrypted).unwrap();
+    key.seal_in_place_append_tag(nonce.as_aead(), ring::aead::Aad::from(prefix.as_bytes()), &mut encrypted).unwrap();
 
-    Ok(format!("{}.{}.{}", PREFIX, nonce.base64(), base64_encode(&encrypted)))
+    Ok(format!("{}.{}.{}", prefix, nonce.base64(), base64_encode(&encrypted)))
 }
 
-pub fn verify_message_id(message_id: &str, key_bytes: [u8; SECRET_KEY_LEN]) -> anyhow::Result<(String, String)> {
-    let mut parts = message_id.split('@').next().unwrap().split('.');
-    let mut extract = || parts.next().ok_or_else(|| anyhow!("not enough parts"));
+/// Why a message ID couldn't be verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageIdError {
+    /// It doesn't start with our ident, so it's not one of ours at all.
+    WrongPrefix,
+
+    /// It's ours, but isn't made up of the expected parts.
+    MalformedStructure(&'static str),
+
+    /// It's ours, but from a version this program doesn't know how to read.
+    UnsupportedVersion(String),
+
+    /// The nonce part couldn't be decoded.
+    BadNonce,
+
+    /// The encrypted part didn't authenticate: it was tampered with, or made using a different
+    /// secret key.
+    DecryptFailed,
+
+    /// The decrypted content isn't valid UTF-8.
+    BadUtf8,
+}
+
+impl std::fmt::Display for MessageIdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageIdError::WrongPrefix => f.write_str("unrecognized prefix"),
+            MessageIdError::MalformedStructure(msg) => f.write_str(msg),
+            MessageIdError::UnsupportedVersion(ver) => write!(f, "unsupported version {:?}", ver),
+            MessageIdError::BadNonce => f.write_str("invalid nonce"),
+            MessageIdError::DecryptFailed => f.write_str("failed to validate encrypted data"),
+            MessageIdError::BadUtf8 => f.write_str("invalid utf-8 in decrypted content"),
+        }
+    }
+}
+
+impl std::error::Error for MessageIdError {}
+
+pub fn verify_message_id(message_id: &str, key_bytes: [u8; SECRET_KEY_LEN])
+    -> Result<(String, String), MessageIdError>
+{
+    let mut parts = id_part(message_id).split('.');
+    if parts.next() != Some(IDENT) {
+        return Err(MessageIdError::WrongPrefix);
+    }
+    let mut extract = || parts.next().ok_or(MessageIdError::MalformedStructure("not enough parts"));
 
-    let ident = extract()?;
     let ver = extract()?;
     let nonce_base64 = extract()?;
     let encrypted_base64 = extract()?;
     if parts.next().is_some() {
-        bail!("too many parts");
+        return Err(MessageIdError::MalformedStructure("too many parts"));
     }
 
-    let prefix = format!("{}.{}", ident, ver);
-    if prefix != PREFIX {
-        bail!("unrecognized prefix");
-    }
+    let version = Version::parse(ver)
+        .ok_or_else(|| MessageIdError::UnsupportedVersion(ver.to_owned()))?;
+    let prefix = version.prefix();
 
     let nonce = TimeNonce::parse(nonce_base64)
-        .context("invalid nonce base64")?;
+        .map_err(|_| MessageIdError::BadNonce)?;
 
     let mut encrypted = base64_decode(encrypted_base64)
-        .context("invalid encrypted base64")?;
+        .map_err(|_| MessageIdError::MalformedStructure("invalid encrypted base64"))?;
 
-    let key = aead_key(key_bytes);
+    let key = aead_key(version, key_bytes);
     let decrypted = key.open_in_place(nonce.as_aead(), aead::Aad::from(prefix.as_bytes()), &mut encrypted)
-        .map_err(|_| anyhow!("failed to validate encrypted data"))?;
+        .map_err(|_| MessageIdError::DecryptFailed)?;
 
     // get the parts in reverse order and limit to 2, in case username contains a '.'
     let mut result_parts = decrypted.rsplitn(2, |b| *b == b'.');
-    let mut extract_result = || -> anyhow::Result<String> {
+    let mut extract_result = || {
         result_parts.next()
-            .ok_or_else(|| anyhow!("not enough result parts"))
-            .map(Vec::from)
-            .and_then(|vec| {
-                String::from_utf8(vec)
-                    .context("invalid utf-8 in decrypted content")
-            })
+            .ok_or(MessageIdError::MalformedStructure("not enough result parts"))
+            .and_then(|part| String::from_utf8(part.to_vec()).map_err(|_| MessageIdError::BadUtf8))
     };
     let date = extract_result()?;
     let user = extract_result()?;
     Ok((user, date))
 }
 
+/// The nonce for a message ID. It's 8 bytes of timestamp, in nanoseconds, followed by 4 random
+/// bytes. The timestamp is made to always increase within this process, and the random bytes cover
+/// other processes generating IDs at the same time, and the clock having gone backwards since a
+/// previous run. IDs from before the random bytes were added have them all zero.
 struct TimeNonce {
-    nanos: u128,
+    bytes: [u8; NONCE_LEN],
 }
 
+const NONCE_LEN: usize = 12;
+
 impl TimeNonce {
-    pub fn new() -> Self {
+    pub fn new() -> anyhow::Result<Self> {
+        use ring::rand::{SecureRandom, SystemRandom};
+        use std::sync::atomic::{AtomicU64, Ordering};
         use std::time::{SystemTime, UNIX_EPOCH};
-        TimeNonce {
-            nanos: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos(),
-        }
+
+        static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        let prev = LAST_NANOS.fetch_update(Ordering::SeqCst, Ordering::SeqCst,
+                |last| Some(now.max(last + 1)))
+            .unwrap(); // can't fail: the closure always returns Some
+        let nanos = now.max(prev + 1);
+
+        let mut bytes = [0u8; NONCE_LEN];
+        bytes[..8].copy_from_slice(&nanos.to_le_bytes());
+        SystemRandom::new().fill(&mut bytes[8..])
+            .map_err(|_| anyhow!("failed to generate random nonce bytes"))?;
+        Ok(Self { bytes })
     }
 
     pub fn as_aead(&self) -> aead::Nonce {
-        // take nanos as little-endian bytes, and use the low-order 12 bytes for the nonce
-        let array: [u8; 12] = self.nanos.to_le_bytes()[0..12].try_into().unwrap();
-        aead::Nonce::assume_unique_for_key(array)
+        aead::Nonce::assume_unique_for_key(self.bytes)
     }
 
     pub fn base64(&self) -> String {
-        // take nanos as little-endian bytes, truncate trailing zeroes, and base64-encode
-        let bytes = self.nanos.to_le_bytes();
-        let mut end = bytes.len();
-        for i in (0 .. bytes.len()).rev() {
-            if bytes[i] == 0 {
-                end -= 1;
-            } else {
-                break;
-            }
-        }
-        base64_encode(&bytes[..end])
+        // truncate trailing zeroes, and base64-encode
+        let end = self.bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        base64_encode(&self.bytes[..end])
     }
 
     pub fn parse(s: &str) -> anyhow::Result<Self> {
-        let mut bytes = base64_decode(s)
+        let decoded = base64_decode(s)
             .context("invalid base64 for nonce")?;
-        bytes.resize(16, 0);
-        let nanos = u128::from_le_bytes(bytes[..].try_into().unwrap());
-        Ok(Self { nanos })
+        if decoded.len() > NONCE_LEN {
+            bail!("nonce is too long ({} bytes)", decoded.len());
+        }
+        let mut bytes = [0u8; NONCE_LEN];
+        bytes[..decoded.len()].copy_from_slice(&decoded);
+        Ok(Self { bytes })
     }
 }
 
-fn aead_key(key_bytes: [u8; SECRET_KEY_LEN]) -> aead::LessSafeKey {
+fn aead_key(version: Version, key_bytes: [u8; SECRET_KEY_LEN]) -> aead::LessSafeKey {
     use ring::aead::*;
-    let algorithm = &CHACHA20_POLY1305;
-    LessSafeKey::new(UnboundKey::new(algorithm, &key_bytes)
+    LessSafeKey::new(UnboundKey::new(version.algorithm(), &key_bytes)
         .expect("failed to make key"))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_secret_key() {
+        let key = [7u8; SECRET_KEY_LEN];
+        assert_eq!(key, parse_secret_key(&key).unwrap());
+
+        let mut with_newline = key.to_vec();
+        with_newline.push(b'\n');
+        assert_eq!(key, parse_secret_key(&with_newline).unwrap());
+
+        let mut two_newlines = with_newline.clone();
+        two_newlines.push(b'\n');
+        assert!(parse_secret_key(&two_newlines).is_err());
+
+        assert!(parse_secret_key(&key[..SECRET_KEY_LEN - 1]).is_err());
+        assert!(parse_secret_key(&[key, key].concat()).is_err());
+        assert!(parse_secret_key(&[]).is_err());
+    }
+
+    #[test]
+    fn test_gen_secret_key() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = crate::testutil::TempDir::new("key");
+        let path = dir.path().join("key");
+        let source = SecretKeySource::File(path.clone());
+
+        gen_secret_key(&source, false).expect("failed to generate key");
+        let key = read_secret_key(&source).expect("failed to read key");
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(SECRET_KEY_LEN as u64, meta.len());
+        assert_eq!(0o600, meta.permissions().mode() & 0o777);
+
+        assert!(gen_secret_key(&source, false).is_err());
+        assert_eq!(key, read_secret_key(&source).unwrap());
+
+        gen_secret_key(&source, true).expect("failed to overwrite key");
+        assert_ne!(key, read_secret_key(&source).unwrap());
+
+        assert!(gen_secret_key(&SecretKeySource::Env("DAYLOG_KEY".to_owned()), true).is_err());
+    }
+
+    #[test]
+    fn test_secret_key_from_env() {
+        // Unique to this test, since the environment is shared by all of them.
+        let name = "DAYLOG_TEST_SECRET_KEY_FROM_ENV";
+        let source = SecretKeySource::Env(name.to_owned());
+        assert!(read_secret_key(&source).is_err());
+
+        let key = [7u8; SECRET_KEY_LEN];
+        std::env::set_var(name, format!("{}\n", STANDARD.encode(key)));
+        assert_eq!(key, read_secret_key(&source).unwrap());
+
+        std::env::set_var(name, STANDARD.encode(&key[1..]));
+        assert!(read_secret_key(&source).is_err());
+        std::env::set_var(name, "not base64!");
+        assert!(read_secret_key(&source).is_err());
+        std::env::remove_var(name);
+    }
+
+    #[test]
+    fn test_secret_key_source() {
+        let parse = |s: &str| SecretKeySource::from(s.to_owned());
+        assert_eq!(SecretKeySource::File("/etc/daylog/key".into()), parse("/etc/daylog/key"));
+        assert_eq!(SecretKeySource::Env("DAYLOG_KEY".to_owned()), parse("env:DAYLOG_KEY"));
+        assert_eq!(SecretKeySource::Stdin, parse("-"));
+        for s in ["/etc/daylog/key", "env:DAYLOG_KEY", "-"] {
+            assert_eq!(s, String::from(parse(s)));
+        }
+    }
+
+    #[test]
+    fn test_nonces_unique() {
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0 .. 10_000 {
+            let nonce = TimeNonce::new().unwrap();
+            assert_eq!(nonce.bytes, TimeNonce::parse(&nonce.base64()).unwrap().bytes);
+            assert!(seen.insert(nonce.bytes), "duplicate nonce {:?}", nonce.bytes);
+        }
+    }
+
+    #[test]
+    fn test_message_id_round_trip() {
+        let key = [7u8; SECRET_KEY_LEN];
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let id = gen_message_id("some.user", date, key).unwrap();
+        assert!(is_our_message_id(&id));
+        let (user, date) = verify_message_id(&format!("{}@example.com", id), key).unwrap();
+        assert_eq!(("some.user", "2021-07-15"), (user.as_str(), date.as_str()));
+
+        assert!(verify_message_id(&id, [8u8; SECRET_KEY_LEN]).is_err());
+    }
+
+    #[test]
+    fn test_old_nonce_format() {
+        // From before nonces had random bytes: just the timestamp, with zeroes trimmed.
+        let key = [7u8; SECRET_KEY_LEN];
+        let nanos = 1_626_393_600_123_456_789u64;
+        let mut bytes = [0u8; NONCE_LEN];
+        bytes[..8].copy_from_slice(&nanos.to_le_bytes());
+        let mut encrypted = b"bob.2021-07-15".to_vec();
+        aead_key(Version::V1, key).seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(bytes), aead::Aad::from(b"daylog.1"), &mut encrypted)
+            .unwrap();
+        let id = format!("daylog.1.{}.{}", base64_encode(&nanos.to_le_bytes()),
+            base64_encode(&encrypted));
+
+        let (user, date) = verify_message_id(&id, key).unwrap();
+        assert_eq!(("bob", "2021-07-15"), (user.as_str(), date.as_str()));
+    }
+
+    #[test]
+    fn test_versions() {
+        let key = [7u8; SECRET_KEY_LEN];
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let id = gen_message_id("bob", date, key).unwrap();
+        assert!(id.starts_with(&format!("daylog.{}.", CURRENT_VERSION.as_str())));
+
+        assert_eq!(Some(Version::V1), Version::parse(Version::V1.as_str()));
+
+        // Other versions are recognized as ours, but can't be read.
+        let future = id.replacen("daylog.1.", "daylog.99.", 1);
+        assert!(is_our_message_id(&future));
+        let err = verify_message_id(&future, key).unwrap_err();
+        assert_eq!("unsupported version \"99\"", err.to_string());
+
+        // Other idents aren't ours at all.
+        assert!(!is_our_message_id("daylogger.1.abc.def"));
+        assert_eq!(Err(MessageIdError::WrongPrefix),
+            verify_message_id(&id.replacen("daylog.", "notdaylog.", 1), key));
+    }
+
+    #[test]
+    fn test_surrounding_whitespace_and_brackets() {
+        let key = [7u8; SECRET_KEY_LEN];
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let id = gen_message_id("bob", date, key).unwrap();
+        for wrapped in [
+            format!("<{}@daylog.example.com>", id),
+            format!("  <{}@daylog.example.com>\r\n", id),
+            format!("\r\n\t{}@daylog.example.com", id),
+            format!(" {} ", id),
+        ] {
+            assert!(is_our_message_id(&wrapped), "{:?}", wrapped);
+            assert_eq!(Ok(("bob".to_owned(), "2021-07-15".to_owned())),
+                verify_message_id(&wrapped, key), "{:?}", wrapped);
+        }
+        assert!(!is_our_message_id("<CAF00@mail.example.com>"));
+        assert!(!is_our_message_id("<x.daylog.1.abc@example.com>"));
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let key = [7u8; SECRET_KEY_LEN];
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let id = gen_message_id("bob", date, key).unwrap();
+        let parts = id.split('.').collect::<Vec<_>>();
+
+        assert_eq!(Err(MessageIdError::WrongPrefix), verify_message_id("CAF00@mail.example.com", key));
+        assert_eq!(Err(MessageIdError::MalformedStructure("not enough parts")),
+            verify_message_id("daylog.1.abc@example.com", key));
+        assert_eq!(Err(MessageIdError::MalformedStructure("too many parts")),
+            verify_message_id(&format!("{}.extra", id), key));
+        assert_eq!(Err(MessageIdError::BadNonce),
+            verify_message_id(&format!("daylog.1.!!.{}", parts[3]), key));
+        assert_eq!(Err(MessageIdError::DecryptFailed), verify_message_id(&id, [8u8; SECRET_KEY_LEN]));
+
+        let mut encrypted = vec![0xff, 0xfe];
+        let nonce = TimeNonce::new().unwrap();
+        aead_key(Version::V1, key).seal_in_place_append_tag(
+            nonce.as_aead(), aead::Aad::from(b"daylog.1"), &mut encrypted)
+            .unwrap();
+        let id = format!("daylog.1.{}.{}", nonce.base64(), base64_encode(&encrypted));
+        assert_eq!(Err(MessageIdError::BadUtf8), verify_message_id(&id, key));
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::Context;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often the listener thread checks whether it should exit.
+const POLL_MILLIS: i32 = 500;
+
+/// Counters for the `run` service, served in Prometheus text format when `metrics_addr` is set.
+#[derive(Default)]
+pub struct Metrics {
+    pub emails_sent: AtomicU64,
+    pub send_failures: AtomicU64,
+    pub messages_ingested: AtomicU64,
+    /// Unix timestamp, or 0 if there hasn't been one.
+    pub last_ingest_success: AtomicI64,
+    /// Unix timestamp, or 0 if nobody is scheduled.
+    pub next_send: AtomicI64,
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            write!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n").unwrap();
+        };
+        metric("daylog_emails_sent_total", "counter", "Daily emails sent.",
+            self.emails_sent.load(Ordering::Relaxed).to_string());
+        metric("daylog_send_failures_total", "counter",
+            "Failed attempts to send a daily email, including ones retried later.",
+            self.send_failures.load(Ordering::Relaxed).to_string());
+        metric("daylog_messages_ingested_total", "counter", "Incoming messages processed.",
+            self.messages_ingested.load(Ordering::Relaxed).to_string());
+        metric("daylog_last_ingest_success_timestamp_seconds", "gauge",
+            "When incoming mail was last processed successfully.",
+            self.last_ingest_success.load(Ordering::Relaxed).to_string());
+        metric("daylog_next_send_timestamp_seconds", "gauge",
+            "When the next daily emails are scheduled to be sent.",
+            self.next_send.load(Ordering::Relaxed).to_string());
+        out
+    }
+}
+
+/// Serve the metrics over HTTP at `/metrics` on the given address, on a new thread, until the stop
+/// flag is set.
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>, stop: Arc<AtomicBool>)
+    -> anyhow::Result<JoinHandle<()>>
+{
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("failed to listen for metrics requests on {}", addr))?;
+    info!("serving metrics on http://{}/metrics", addr);
+    let handle = std::thread::Builder::new()
+        .name("metrics".to_owned())
+        .spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                let pollfd = PollFd::new(&listener, PollFlags::POLLIN);
+                match poll(&mut [pollfd], POLL_MILLIS) {
+                    Ok(0) => continue,
+                    Ok(_) => (),
+                    Err(nix::errno::Errno::EINTR) => continue,
+                    Err(e) => {
+                        error!("failed to poll metrics listener: {}", e);
+                        return;
+                    }
+                }
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        if let Err(e) = respond(stream, &metrics) {
+                            debug!("failed to answer metrics request from {}: {}", peer, e);
+                        }
+                    }
+                    Err(e) => warn!("failed to accept metrics connection: {}", e),
+                }
+            }
+            debug!("metrics listener exiting");
+        })
+        .context("failed to start metrics thread")?;
+    Ok(handle)
+}
+
+fn respond(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers; nothing in them matters.
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let (status, body) = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics.render()),
+        ["GET", _] => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_owned()),
+    };
+    let mut stream = &stream;
+    write!(stream, "HTTP/1.0 {}\r\n\
+        Content-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {}", status, body.len(), body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.emails_sent.store(3, Ordering::Relaxed);
+        metrics.next_send.store(1626400800, Ordering::Relaxed);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // Find a free port.
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let handle = serve(addr, Arc::clone(&metrics), Arc::clone(&stop)).unwrap();
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\ndaylog_emails_sent_total 3\n"), "{}", response);
+        assert!(response.contains("\ndaylog_send_failures_total 0\n"), "{}", response);
+        assert!(response.contains("\ndaylog_next_send_timestamp_seconds 1626400800\n"),
+            "{}", response);
+
+        metrics.send_failures.fetch_add(1, Ordering::Relaxed);
+        assert!(get(addr, "/metrics").contains("\ndaylog_send_failures_total 1\n"));
+
+        assert!(get(addr, "/").starts_with("HTTP/1.0 404 Not Found\r\n"));
+
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::{anyhow, bail, Context};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// What an ASCII-armored public key starts with.
+const PUBLIC_KEY_HEADER: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----";
+
+/// What an ASCII-armored PGP message, like each reply in an encrypted entry, starts with.
+pub const MESSAGE_HEADER: &str = "-----BEGIN PGP MESSAGE-----";
+
+/// What an ASCII-armored PGP message ends with.
+const MESSAGE_FOOTER: &str = "-----END PGP MESSAGE-----";
+
+/// Check that the text looks like an ASCII-armored PGP public key. Whether gpg can actually use
+/// it is only found out when encrypting.
+pub fn validate_public_key(key: &str) -> anyhow::Result<()> {
+    if !key.trim_start().starts_with(PUBLIC_KEY_HEADER) {
+        bail!("not an ASCII-armored PGP public key (should start with {:?})", PUBLIC_KEY_HEADER);
+    }
+    Ok(())
+}
+
+/// Encrypt text or file contents to an ASCII-armored public key with gpg, returning an
+/// ASCII-armored PGP message. `gpg_path` is the 'gpg_path' config setting.
+pub fn encrypt(gpg_path: Option<&Path>, public_key: &str, plaintext: impl AsRef<[u8]>)
+    -> anyhow::Result<String>
+{
+    let gpg_path = gpg_path.unwrap_or(Path::new("gpg"));
+
+    // gpg insists on a home directory, but the key doesn't need importing into anyone's keyring,
+    // so give it an empty one of its own.
+    let home = GpgHome::new()?;
+    let key_path = home.path.join("recipient.asc");
+    std::fs::write(&key_path, public_key)
+        .with_context(|| format!("failed to write public key to {:?}", key_path))?;
+
+    let mut child = Command::new(gpg_path)
+        .arg("--homedir")
+        .arg(&home.path)
+        .args(["--batch", "--quiet", "--no-tty", "--trust-model", "always", "--armor",
+            "--encrypt", "--recipient-file"])
+        .arg(&key_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                anyhow!("{:?} command not found. Encrypting entries needs GnuPG; install it, or \
+                    set 'gpg_path' in the config file to its location.", gpg_path)
+            } else {
+                anyhow::Error::new(e).context(format!("failed to run {:?} command", gpg_path))
+            }
+        })?;
+
+    // Write from another thread, so gpg can't get stuck writing output nobody is reading yet.
+    let mut stdin = child.stdin.take().expect("failed to get 'gpg' command stdin");
+    let input = plaintext.as_ref().to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()
+        .context("failed to wait for 'gpg' command")?;
+    let written = writer.join().expect("gpg input thread panicked");
+
+    if !output.status.success() {
+        bail!("gpg failed ({}): {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    written.context("failed to write to 'gpg' command")?;
+    let mut armored = String::from_utf8(output.stdout)
+        .context("gpg output is not UTF-8")?;
+    armored.truncate(armored.trim_end().len());
+    Ok(armored)
+}
+
+/// Replace each ASCII-armored PGP message in the text with "[encrypted]", for showing encrypted
+/// entries somewhere the ciphertext is no use, like in the daily email.
+pub fn redact_messages(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(MESSAGE_HEADER) {
+        out += &rest[.. start];
+        out += "[encrypted]";
+        rest = match rest[start ..].find(MESSAGE_FOOTER) {
+            Some(end) => &rest[start + end + MESSAGE_FOOTER.len() ..],
+            None => "",
+        };
+    }
+    out + rest
+}
+
+/// A temporary, private gpg home directory, removed when dropped.
+struct GpgHome {
+    path: PathBuf,
+}
+
+impl GpgHome {
+    fn new() -> anyhow::Result<Self> {
+        use std::os::unix::fs::DirBuilderExt;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("daylog-gpg-{}-{}",
+            std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .with_context(|| format!("failed to create gpg home directory {:?}", path))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for GpgHome {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TestKey;
+
+    #[test]
+    fn test_encrypt() {
+        let key = TestKey::new();
+        let armored = encrypt(None, &key.public_key, "went for a walk\n").unwrap();
+        assert!(armored.starts_with("-----BEGIN PGP MESSAGE-----\n"), "{}", armored);
+        assert!(armored.ends_with("-----END PGP MESSAGE-----"), "{}", armored);
+        assert!(!armored.contains("walk"));
+        assert_eq!("went for a walk\n", key.decrypt(&armored));
+
+        validate_public_key(&key.public_key).unwrap();
+        validate_public_key("not a key").unwrap_err();
+        encrypt(None, "-----BEGIN PGP PUBLIC KEY BLOCK-----\ngarbage\n", "text").unwrap_err();
+
+        let binary = encrypt(None, &key.public_key, [0xff, 0x00, b'x']).unwrap();
+        assert!(binary.starts_with(MESSAGE_HEADER), "{}", binary);
+    }
+
+    #[test]
+    fn test_redact_messages() {
+        let message = "-----BEGIN PGP MESSAGE-----\n\nhQEMA+abc\n=xyz\n-----END PGP MESSAGE-----";
+        assert_eq!("[encrypted]", redact_messages(message));
+        assert_eq!("before the key\n[encrypted]\n[encrypted]",
+            redact_messages(&format!("before the key\n{}\n{}", message, message)));
+        assert_eq!("plain text", redact_messages("plain text"));
+        assert_eq!("cut off: [encrypted]",
+            redact_messages("cut off: -----BEGIN PGP MESSAGE-----\n\nhQEMA"));
+    }
+}
@@ -0,0 +1,41 @@
+use crate::ReprocessArgs;
+use crate::config::Config;
+use crate::db::Database;
+use crate::ingest::regenerate_entry;
+
+pub fn reprocess(config: &Config, args: ReprocessArgs) -> anyhow::Result<()> {
+    let mut db = Database::open(&config.database_path)?;
+    let user = db.get_user(&args.username, config.default_email_time)?;
+
+    let (from, to) = match (db.first_entry_date(&user.username)?,
+        db.last_entry_date(&user.username)?)
+    {
+        (Some(first), Some(last)) => (args.from.unwrap_or(first), args.to.unwrap_or(last)),
+        _ => {
+            println!("{} has no entries", user.username);
+            return Ok(());
+        }
+    };
+
+    // Only entries with every reply's raw text stored can be made again; older ones are skipped.
+    let entries = db.get_entry_sources(&user.username, from, to)?;
+    let mut changed = 0;
+    for (date, old, sources) in &entries {
+        let date = date.format("%Y-%m-%d").to_string();
+        let new = regenerate_entry(config, &date, sources)?;
+        if new == *old {
+            continue;
+        }
+        changed += 1;
+        if args.dry_run {
+            print!("{}", crate::diff::unified_diff(
+                &format!("{}/{} (stored)", user.username, date), old,
+                &format!("{}/{} (reprocessed)", user.username, date), &new));
+        } else {
+            db.update_entry_body(&user.username, &date, &new)?;
+        }
+    }
+    say!("{} of {} entries {}", changed, entries.len(),
+        if args.dry_run { "would change" } else { "changed" });
+    Ok(())
+}
@@ -1,19 +1,24 @@
-use anyhow::Context;
-use chrono::Duration;
+use anyhow::{anyhow, Context};
+use chrono::{Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use crate::{Config, RunArgs};
 use crate::db::Database;
+use crate::metrics::Metrics;
+use crate::message_id::{read_secret_key, SECRET_KEY_LEN};
 use crate::time::{SleepTime, DaylogTime};
+use crate::send::SendmailFailed;
+use crate::user::{NextUsers, User, Users};
 use nix::errno::Errno;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::socket::{send, MsgFlags};
-use signal_hook::consts::{SIGHUP, SIGTERM};
+use signal_hook::consts::{SIGHUP, SIGTERM, SIGUSR1};
 use std::fmt::Write;
 use std::io::{self, Read};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixStream;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
 
 fn handle_signal(signal: i32, sock: UnixStream, flag: Option<Arc<AtomicBool>>)
     -> anyhow::Result<()>
@@ -33,6 +38,7 @@ fn handle_signal(signal: i32, sock: UnixStream, flag: Option<Arc<AtomicBool>>)
 
 enum SleepResult {
     Completed,
+    LimitReached,
     FdReadable,
 }
 
@@ -47,7 +53,11 @@ fn duration_fmt(mut dur: Duration) -> String {
     out
 }
 
-fn sleep_until(time: SleepTime, control: &UnixStream) -> io::Result<SleepResult> {
+/// Sleep until the given time, or until the control file becomes readable, or until the given
+/// instant (if any), whichever comes first.
+fn sleep_until(time: SleepTime, limit: Option<Instant>, control: &UnixStream)
+    -> io::Result<SleepResult>
+{
     let pollfd = PollFd::new(control, PollFlags::POLLIN);
     loop {
         let now = chrono::Utc::now().time();
@@ -59,9 +69,26 @@ fn sleep_until(time: SleepTime, control: &UnixStream) -> io::Result<SleepResult>
             warn!("sleep duration is negative: {:?}", sleep_duration);
             return Ok(SleepResult::Completed);
         }
-        debug!("sleeping for {}", duration_fmt(sleep_duration));
 
-        return match poll(&mut[pollfd], sleep_duration_millis) {
+        let limit_millis = limit.map(|instant| {
+            instant.saturating_duration_since(Instant::now()).as_millis() as i32
+        });
+        let (timeout_millis, limited) = match limit_millis {
+            Some(millis) if millis < sleep_duration_millis => {
+                debug!("sleeping for {}ms (limited)", millis);
+                (millis, true)
+            }
+            _ => {
+                debug!("sleeping for {}", duration_fmt(sleep_duration));
+                (sleep_duration_millis, false)
+            }
+        };
+
+        return match poll(&mut[pollfd], timeout_millis) {
+            Ok(0) if limited => {
+                debug!("sleep limit reached");
+                Ok(SleepResult::LimitReached)
+            }
             Ok(0) => {
                 debug!("sleep completed");
                 Ok(SleepResult::Completed)
@@ -79,6 +106,31 @@ fn sleep_until(time: SleepTime, control: &UnixStream) -> io::Result<SleepResult>
     }
 }
 
+/// If sending emails scheduled for `scheduled` at `actual` would be more than `max_late` behind,
+/// return how far behind it would be.
+fn too_late(scheduled: NaiveDateTime, actual: NaiveDateTime, max_late: Option<Duration>)
+    -> Option<Duration>
+{
+    let late = actual - scheduled;
+    max_late.filter(|max| late > *max).map(|_| late)
+}
+
+/// Wait for the given duration. Returns false if it was cut short because the control file
+/// became readable.
+fn wait(duration: std::time::Duration, control: &UnixStream) -> io::Result<bool> {
+    let deadline = Instant::now() + duration;
+    let pollfd = PollFd::new(control, PollFlags::POLLIN);
+    loop {
+        let millis = deadline.saturating_duration_since(Instant::now()).as_millis() as i32;
+        return match poll(&mut [pollfd], millis) {
+            Ok(0) => Ok(true),
+            Ok(_) => Ok(false),
+            Err(Errno::EINTR) => continue,
+            Err(errno) => Err(io::Error::from_raw_os_error(errno as i32)),
+        };
+    }
+}
+
 fn read_until_ewouldblock(mut file: impl Read) -> io::Result<()> {
     loop {
         let mut data = [0u8; 1];
@@ -105,11 +157,273 @@ fn set_nonblocking(f: RawFd) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Open the database and read the secret key, for sending emails.
+fn open_for_sending(config: &Config) -> anyhow::Result<(Database, [u8; SECRET_KEY_LEN])> {
+    let key_bytes = read_secret_key(&config.secret_key)
+        .with_context(|| format!("failed to read secret key from {}", config.secret_key))?;
+    let db = Database::open(&config.database_path)?;
+    Ok((db, key_bytes))
+}
+
+/// Re-read the config file, the secret key, and the user list from the database it specifies.
+/// Settings overridden on the command line stay overridden.
+fn reload(config: &Config) -> anyhow::Result<(Config, Database, [u8; SECRET_KEY_LEN], Users)> {
+    let config = Config::try_from_path(config.path.as_os_str())
+        .map_err(|msg| anyhow!(msg))?
+        .with_overrides(config.overrides.clone());
+    let (db, key_bytes) = open_for_sending(&config)?;
+    let users = db.get_all_users(config.default_email_time)?
+        .with_ambiguous_time(config.ambiguous_time);
+    Ok((config, db, key_bytes, users))
+}
+
+/// Move the scheduler's notion of the current time forward to the given actual time, but not
+/// backwards.
+fn catch_up(
+    today: NaiveDate,
+    now: DaylogTime,
+    actual: (NaiveDate, DaylogTime),
+) -> (NaiveDate, DaylogTime) {
+    if actual > (today, now) {
+        actual
+    } else {
+        (today, now)
+    }
+}
+
+/// Log when the given users will be emailed, in their own timezones.
+fn log_local_times(today: NaiveDate, next: SleepTime, users: &[User]) {
+    for line in local_times(today, next, users) {
+        info!("{}", line);
+    }
+}
+
+fn local_times(today: NaiveDate, next: SleepTime, users: &[User]) -> Vec<String> {
+    let utc = Utc.from_utc_datetime(&next.on_date(today));
+    users.iter()
+        .map(|user| format!("    {} at {} local time ({})",
+            user.username,
+            utc.with_timezone(&user.timezone).format("%Y-%m-%d %H:%M %Z"),
+            user.timezone))
+        .collect()
+}
+
+/// The whole schedule from the given time on, for `run --plan`: each time users will be emailed,
+/// followed by those users' local times.
+fn plan(users: &Users, today: NaiveDate, now: DaylogTime) -> Vec<String> {
+    let mut lines = vec![];
+    for (next, users) in users.schedule(today, now) {
+        lines.push(format!("at {} UTC:", next.on_date(today).format("%Y-%m-%d %H:%M")));
+        lines.extend(local_times(today, next, &users));
+    }
+    lines
+}
+
+/// How far into the splay window (`send_splay_seconds`) to send to the given user. This is a hash of
+/// the username, so it's the same every day.
+fn splay_offset(username: &str, window: std::time::Duration) -> std::time::Duration {
+    let window_millis = window.as_millis() as u64;
+    if window_millis == 0 {
+        return std::time::Duration::ZERO;
+    }
+    // FNV-1a, rather than std's hasher, which isn't guaranteed to be stable between releases.
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in username.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    std::time::Duration::from_millis(hash % window_millis)
+}
+
+/// Wait until the given instant, to spread out sends. Unlike `wait`, this isn't cut short by
+/// signals other than SIGTERM.
+fn splay_wait(until: Instant, control: &UnixStream, sigterm_flag: &AtomicBool) -> io::Result<()> {
+    loop {
+        let remaining = until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || sigterm_flag.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        if !wait(remaining, control)? {
+            // Some other signal is waiting for the main loop to handle it, so the control socket
+            // stays readable until then; check for SIGTERM every second instead.
+            std::thread::sleep(remaining.min(std::time::Duration::from_secs(1)));
+        }
+    }
+}
+
+/// Wait out the delay before a round of retries. Like `splay_wait`, this isn't cut short by
+/// signals other than SIGTERM, which would otherwise leave the control socket readable and make
+/// every later round retry right away. Returns false if SIGTERM arrived.
+fn retry_wait(delay: std::time::Duration, control: &UnixStream, sigterm_flag: &AtomicBool)
+    -> io::Result<bool>
+{
+    splay_wait(Instant::now() + delay, control, sigterm_flag)?;
+    Ok(!sigterm_flag.load(Ordering::SeqCst))
+}
+
+/// Send each user their email, retrying ones that failed for reasons that might be temporary, up
+/// to `retries` times. Retries are done in rounds after everyone else has been tried, so one user's
+/// failures don't hold up the rest. Up to `concurrency` users are sent to at once, each from its
+/// own thread, starting in the order given. `wait` is called with the delay before each round,
+/// which doubles each time, and returns false if retrying should be abandoned. `stop` is called
+/// with each user before sending to them (and may wait first, to spread sends out), and if it
+/// returns true (because the service is shutting down), the rest of the batch is abandoned; sends
+/// already in progress are allowed to finish. Returns how many users weren't sent to.
+pub fn send_with_retries(
+    mut users: Vec<User>,
+    retries: u32,
+    mut delay: std::time::Duration,
+    concurrency: usize,
+    send: impl Fn(&User) -> anyhow::Result<()> + Sync,
+    mut wait: impl FnMut(std::time::Duration) -> bool,
+    stop: impl Fn(&User) -> bool + Sync,
+) -> usize {
+    let mut failed = 0;
+    for attempt in 0 ..= retries {
+        if attempt > 0 {
+            if users.is_empty() {
+                break;
+            }
+            info!("retrying sending to {} users in {}s", users.len(), delay.as_secs());
+            if !wait(delay) {
+                warn!("abandoning retries for {} users", users.len());
+                return failed + users.len();
+            }
+            delay *= 2;
+        }
+
+        let round = send_round(users, concurrency, |user| match send(user) {
+            Ok(()) => Sent::Done,
+            Err(e) => {
+                let permanent = e.downcast_ref::<SendmailFailed>()
+                    .map(SendmailFailed::is_permanent)
+                    .unwrap_or(false);
+                if permanent || attempt == retries {
+                    error!("failed to send to {:?}: {}", user, e);
+                    Sent::Failed
+                } else {
+                    warn!("failed to send to {:?}, will retry: {}", user, e);
+                    Sent::Retry
+                }
+            }
+        }, &stop);
+        failed += round.failed;
+        users = round.retry;
+
+        if round.stopped {
+            warn!("shutting down; not sending to {} remaining users", users.len());
+            return failed + users.len();
+        }
+    }
+    failed
+}
+
+enum Sent {
+    Done,
+    Retry,
+    Failed,
+}
+
+struct Round {
+    /// Users to try again, including ones not tried because the round was stopped, in their
+    /// original order.
+    retry: Vec<User>,
+    failed: usize,
+    stopped: bool,
+}
+
+/// Try sending to each user once, from up to `concurrency` threads.
+fn send_round(
+    users: Vec<User>,
+    concurrency: usize,
+    send: impl Fn(&User) -> Sent + Sync,
+    stop: &(impl Fn(&User) -> bool + Sync),
+) -> Round {
+    let threads = concurrency.clamp(1, users.len().max(1));
+    let queue = Mutex::new(users.into_iter().enumerate());
+    let retry = Mutex::new(vec![]);
+    let failed = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for _ in 0 .. threads {
+            scope.spawn(|| loop {
+                let Some((i, user)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                if stopped.load(Ordering::SeqCst) || stop(&user) {
+                    stopped.store(true, Ordering::SeqCst);
+                    retry.lock().unwrap().push((i, user));
+                    continue;
+                }
+                match send(&user) {
+                    Sent::Done => (),
+                    Sent::Retry => retry.lock().unwrap().push((i, user)),
+                    Sent::Failed => {
+                        failed.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+        }
+    });
+    let mut retry = retry.into_inner().unwrap();
+    retry.sort_by_key(|(i, _)| *i);
+    Round {
+        retry: retry.into_iter().map(|(_, user)| user).collect(),
+        failed: failed.into_inner(),
+        stopped: stopped.into_inner(),
+    }
+}
+
+fn ingest(config: &Config, args: &RunArgs, metrics: &Metrics) {
+    info!("processing incoming mail");
+    let ingest_args = crate::IngestArgs {
+        dry_run: args.dry_run,
+        stats_file: None,
+        max_body_bytes: None,
+        stdin: false,
+        limit: None,
+        reprocess: false,
+        since: None,
+        progress: None,
+    };
+    match crate::ingest::ingest(config, ingest_args) {
+        Ok(stats) => {
+            info!("processed {} incoming messages: {} removed, {} kept, {} left unread, {} failed, \
+                {} database errors", stats.num_processed, stats.num_removed, stats.num_kept,
+                stats.num_left_unread, stats.num_failed, stats.num_db_errors);
+            metrics.messages_ingested.fetch_add(stats.num_processed, Ordering::Relaxed);
+            metrics.last_ingest_success.store(Utc::now().timestamp(), Ordering::Relaxed);
+        }
+        Err(e) => error!("failed to process incoming mail: {:?}", e),
+    }
+}
+
 pub fn run(config: &Config, args: RunArgs) -> anyhow::Result<()> {
+    if args.plan {
+        let users = Database::open_read_only(&config.database_path)?
+            .get_all_users(config.default_email_time)?
+            .with_ambiguous_time(config.ambiguous_time);
+        let (today, now) = DaylogTime::now();
+        if let NextUsers::NoUsers = users.next_from_time(today, now) {
+            anyhow::bail!("no users configured");
+        }
+        let lines = plan(&users, today, now);
+        if lines.is_empty() {
+            println!("no users currently scheduled");
+        }
+        for line in lines {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
     info!("starting service");
 
+    let mut config = config.clone();
+
     let (control, control_sigterm) = UnixStream::pair()?;
     let control_sighup = control_sigterm.try_clone()?;
+    let control_sigusr1 = control_sigterm.try_clone()?;
 
     set_nonblocking(control.as_raw_fd())
         .context("failed to set control socket nonblocking")?;
@@ -119,49 +433,156 @@ pub fn run(config: &Config, args: RunArgs) -> anyhow::Result<()> {
     handle_signal(SIGTERM, control_sigterm, Some(Arc::clone(&sigterm_flag)))
         .context("failed to install SIGTERM handler")?;
 
-    handle_signal(SIGHUP, control_sighup, None)
+    // SIGHUP requests that the config file and user list be re-read.
+    let reload_flag = Arc::new(AtomicBool::new(false));
+    handle_signal(SIGHUP, control_sighup, Some(Arc::clone(&reload_flag)))
         .context("failed to install SIGHUP handler")?;
 
-    let db = Database::open(&config.database_path)?;
+    // SIGUSR1 requests that incoming mail be processed right away.
+    let ingest_flag = Arc::new(AtomicBool::new(false));
+    handle_signal(SIGUSR1, control_sigusr1, Some(Arc::clone(&ingest_flag)))
+        .context("failed to install SIGUSR1 handler")?;
+
+    let metrics = Arc::new(Metrics::default());
+    let metrics_thread = match config.metrics_addr {
+        Some(addr) => Some(crate::metrics::serve(addr, Arc::clone(&metrics),
+            Arc::clone(&sigterm_flag))?),
+        None => None,
+    };
+
+    // These are kept open for sending, and only reopened on reload.
+    let (mut db, mut key_bytes) = open_for_sending(&config)?;
 
     info!("process ID: {}", std::process::id());
 
-    let users = db.get_all_users()?;
-    let (mut today, mut now) = DaylogTime::now(); // the only time we check actual clock
+    let mut all_users = db.get_all_users(config.default_email_time)?
+        .with_ambiguous_time(config.ambiguous_time);
+    let (mut today, mut now) = DaylogTime::now();
+
+    let mut ingest_interval = config.ingest_interval();
+    let mut next_ingest = ingest_interval.map(|_| Instant::now());
 
     while !sigterm_flag.load(Ordering::SeqCst) {
 
-        let (next_time, users) = match users.next_from_time(today, now) {
-            Some((next, users)) => {
+        let (next_time, users) = match all_users.next_from_time(today, now) {
+            NextUsers::Scheduled(next, users) => {
                 info!("sleep until {}", next);
+                log_local_times(today, next, &users);
+                metrics.next_send.store(next.on_date(today).and_utc().timestamp(),
+                    Ordering::Relaxed);
                 (next, users)
             }
-            None => {
+            NextUsers::NoneScheduled => {
+                info!("no users currently scheduled; sleep until tomorrow");
+                metrics.next_send.store(0, Ordering::Relaxed);
+                (SleepTime::Tomorrow(DaylogTime::zero()), vec![])
+            }
+            NextUsers::NoUsers => {
                 error!("no users configured");
                 return Ok(());
             }
         };
 
-        let result = sleep_until(next_time, &control)
+        let result = sleep_until(next_time, next_ingest, &control)
             .context("failed to sleep")?;
         match result {
             SleepResult::Completed => (),
+            SleepResult::LimitReached => {
+                ingest(&config, &args, &metrics);
+                next_ingest = ingest_interval.map(|interval| Instant::now() + interval);
+                continue;
+            }
             SleepResult::FdReadable => {
                 read_until_ewouldblock(&control)
                     .context("error draining control file")?;
+                if reload_flag.swap(false, Ordering::SeqCst) {
+                    info!("got SIGHUP; reloading");
+                    match reload(&config) {
+                        Ok((new_config, new_db, new_key_bytes, new_users)) => {
+                            if new_config.ingest_interval() != ingest_interval {
+                                ingest_interval = new_config.ingest_interval();
+                                next_ingest = ingest_interval.map(|_| Instant::now());
+                            }
+                            if new_key_bytes != key_bytes {
+                                warn!("secret key changed; replies to emails sent with the old \
+                                    key won't be recognized");
+                            }
+                            config = new_config;
+                            db = new_db;
+                            key_bytes = new_key_bytes;
+                            all_users = new_users;
+                        }
+                        Err(e) => error!("failed to reload; keeping old configuration: {:?}", e),
+                    }
+                    // Users may have been added with send times between when we last sent mail
+                    // and now; don't send to them until tomorrow.
+                    (today, now) = catch_up(today, now, DaylogTime::now());
+                }
+                if ingest_flag.swap(false, Ordering::SeqCst) {
+                    info!("got SIGUSR1");
+                    ingest(&config, &args, &metrics);
+                    next_ingest = ingest_interval.map(|interval| Instant::now() + interval);
+                }
                 continue;
             }
         }
 
-        for user in users {
-            info!("sending to {:?}", user);
-            if !args.dry_run {
-                let result = crate::send::send(config, crate::send::Mode::User(user.clone()));
-                if let Err(e) = result {
-                    error!("failed to send to {:?}: {}", user, e);
-                }
+        let scheduled = next_time.on_date(today);
+        let mut users = match too_late(scheduled, Utc::now().naive_utc(), config.max_late_send()) {
+            Some(late) if !users.is_empty() => {
+                warn!("{} behind schedule for sending at {} UTC; skipping {}",
+                    duration_fmt(late), scheduled.format("%Y-%m-%d %H:%M"),
+                    users.iter().map(|user| user.username.as_str()).collect::<Vec<_>>().join(", "));
+                vec![]
             }
-        }
+            _ => users,
+        };
+
+        // Spread the sends out over the splay window, in order of each user's offset into it.
+        let splay = std::time::Duration::from_secs(config.send_splay_seconds);
+        users.sort_by_key(|user| splay_offset(&user.username, splay));
+        let batch_start = Instant::now();
+
+        // Only one thread at a time can use the database; it's only needed while writing each
+        // email, not while sendmail takes it.
+        let shared_db = Mutex::new(db);
+        send_with_retries(
+            users,
+            config.send_retries,
+            std::time::Duration::from_secs(config.send_retry_delay_seconds),
+            config.send_concurrency as usize,
+            |user| {
+                info!("sending to {:?}", user);
+                let result = if args.dry_run {
+                    Ok(())
+                } else {
+                    let email = crate::send::prepare(&config, &shared_db.lock().unwrap(),
+                        key_bytes, crate::send::Mode::User(user.clone()));
+                    email.and_then(|email| email.map_or(Ok(()), |email| email.send(&config)))
+                };
+                let counter = match result {
+                    Ok(()) => &metrics.emails_sent,
+                    Err(_) => &metrics.send_failures,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                result
+            },
+            |delay| match retry_wait(delay, &control, &sigterm_flag) {
+                Ok(retry) => retry,
+                Err(e) => {
+                    error!("failed to sleep: {}", e);
+                    false
+                }
+            },
+            |user| {
+                let until = batch_start + splay_offset(&user.username, splay);
+                if let Err(e) = splay_wait(until, &control, &sigterm_flag) {
+                    error!("failed to sleep: {}", e);
+                }
+                sigterm_flag.load(Ordering::SeqCst)
+            },
+        );
+        db = shared_db.into_inner().unwrap();
 
         // Don't actually use the current time; in case sending takes longer than 1 minute, we want
         // to only advance to the next minute for checking the database.
@@ -169,18 +590,290 @@ pub fn run(config: &Config, args: RunArgs) -> anyhow::Result<()> {
             SleepTime::Today(time) => {
                 let time = time.succ();
                 if time == DaylogTime::zero() {
-                    today = today.succ();
+                    today = today.succ_opt().unwrap();
                 }
                 time
             }
             SleepTime::Tomorrow(time) => {
                 // we already slept until tomorrow, so now it's today no matter what
-                today = today.succ();
+                today = today.succ_opt().unwrap();
                 time.succ()
             }
         };
     }
 
     info!("termination requested; exiting");
+    if let Some(thread) = metrics_thread {
+        if thread.join().is_err() {
+            error!("metrics thread panicked");
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{user, TempDir};
+
+    #[test]
+    fn test_reload() {
+        let dir = TempDir::new("reload");
+        let config_path = dir.path().join("config.yaml");
+        std::fs::write(&config_path, "\
+database: daylog.db
+secret_key: key
+return_addr: daylog@example.com
+incoming_mail:
+    maildir:
+        path: maildir
+").unwrap();
+        let config = Config::try_from_path(config_path.as_os_str()).unwrap();
+
+        let mut db = Database::open(&config.database_path).unwrap();
+        db.add_user("alice", "alice@example.com", "America/Chicago", "18:00").unwrap();
+
+        // The secret key has to exist too.
+        assert!(reload(&config).is_err());
+        crate::message_id::gen_secret_key(&config.secret_key, false).unwrap();
+
+        let (_, _, key_bytes, users) = reload(&config).unwrap();
+        assert_eq!(vec!["alice"], users.usernames());
+
+        db.add_user("bob", "bob@example.com", "Asia/Tokyo", "09:00").unwrap();
+        std::fs::write(&config_path, format!("{}ingest_interval_minutes: 5\n",
+            std::fs::read_to_string(&config_path).unwrap())).unwrap();
+        crate::message_id::gen_secret_key(&config.secret_key, true).unwrap();
+
+        let (new_config, _, new_key_bytes, users) = reload(&config).unwrap();
+        assert_eq!(vec!["alice", "bob"], users.usernames());
+        assert_eq!(Some(std::time::Duration::from_secs(300)), new_config.ingest_interval());
+        assert_ne!(key_bytes, new_key_bytes);
+    }
+
+    #[test]
+    fn test_send_with_retries() {
+        use std::collections::HashMap;
+        use std::time::Duration;
+
+        let users = vec![user("ok"), user("flaky"), user("down"), user("nosuchuser")];
+
+        let attempts = Mutex::new(HashMap::<String, u32>::new());
+        let mut waits = vec![];
+        let failed = send_with_retries(users, 3, Duration::from_secs(10), 1,
+            |user| {
+                let mut attempts = attempts.lock().unwrap();
+                let n = attempts.entry(user.username.clone()).or_default();
+                *n += 1;
+                match user.username.as_str() {
+                    "ok" => Ok(()),
+                    "flaky" if *n == 2 => Ok(()),
+                    "nosuchuser" => Err(SendmailFailed { code: Some(67) }.into()),
+                    _ => Err(anyhow::Error::new(SendmailFailed { code: Some(75) })
+                        .context("failed to send")),
+                }
+            },
+            |delay| {
+                waits.push(delay);
+                true
+            },
+            |_| false);
+
+        let attempts = attempts.into_inner().unwrap();
+        assert_eq!(1, attempts["ok"]);
+        assert_eq!(2, attempts["flaky"]);
+        assert_eq!(4, attempts["down"]);
+        assert_eq!(1, attempts["nosuchuser"]);
+        assert_eq!(vec![Duration::from_secs(10), Duration::from_secs(20), Duration::from_secs(40)],
+            waits);
+        assert_eq!(2, failed);
+    }
+
+    #[test]
+    fn test_send_retries_abandoned() {
+        let attempts = AtomicUsize::new(0);
+        send_with_retries(vec![user("down")],
+            3, std::time::Duration::from_secs(1), 1,
+            |_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(anyhow!("nope"))
+            },
+            |_| false,
+            |_| false);
+        assert_eq!(1, attempts.into_inner());
+    }
+
+    #[test]
+    fn test_send_stopped_mid_batch() {
+        // SIGTERM arrives while sending to the second user; the third is never tried, and neither
+        // are retries.
+        let sigterm = AtomicBool::new(false);
+        let sent = Mutex::new(vec![]);
+        let failed = send_with_retries(vec![user("a"), user("b"), user("c")], 3,
+            std::time::Duration::from_secs(1), 1,
+            |user| {
+                sent.lock().unwrap().push(user.username.clone());
+                if user.username == "b" {
+                    sigterm.store(true, Ordering::SeqCst);
+                    return Err(anyhow!("temporary failure"));
+                }
+                Ok(())
+            },
+            |_| panic!("shouldn't wait to retry"),
+            |_| sigterm.load(Ordering::SeqCst));
+        assert_eq!(vec!["a", "b"], sent.into_inner().unwrap());
+        assert_eq!(2, failed);
+    }
+
+    #[test]
+    fn test_send_concurrency() {
+        use std::time::Duration;
+        let users = (0 .. 12).map(|i| user(&format!("user{}", i))).collect::<Vec<_>>();
+
+        // A slow transport that counts how many sends are in progress at once, and fails
+        // everyone's first try once.
+        let sending = AtomicUsize::new(0);
+        let max_sending = AtomicUsize::new(0);
+        let attempts = Mutex::new(vec![]);
+        let failed = send_with_retries(users, 1, Duration::from_secs(1), 4,
+            |user| {
+                let now = sending.fetch_add(1, Ordering::SeqCst) + 1;
+                max_sending.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(50));
+                sending.fetch_sub(1, Ordering::SeqCst);
+                let mut attempts = attempts.lock().unwrap();
+                let first = !attempts.contains(&user.username);
+                attempts.push(user.username.clone());
+                if first && user.username == "user7" {
+                    Err(anyhow::Error::new(SendmailFailed { code: Some(75) }))
+                } else if user.username == "user3" {
+                    Err(anyhow::Error::new(SendmailFailed { code: Some(67) }))
+                } else {
+                    Ok(())
+                }
+            },
+            |_| true,
+            |_| false);
+        assert_eq!(4, max_sending.into_inner());
+        assert_eq!(1, failed);
+        let attempts = attempts.into_inner().unwrap();
+        assert_eq!(13, attempts.len());
+        assert_eq!("user7", attempts[12]);
+
+        // SIGTERM stops the workers from starting any more sends.
+        let users = (0 .. 12).map(|i| user(&format!("user{}", i))).collect::<Vec<_>>();
+        let sigterm = AtomicBool::new(false);
+        let sent = AtomicUsize::new(0);
+        let failed = send_with_retries(users, 3, Duration::from_secs(1), 4,
+            |_| {
+                std::thread::sleep(Duration::from_millis(20));
+                if sent.fetch_add(1, Ordering::SeqCst) == 0 {
+                    sigterm.store(true, Ordering::SeqCst);
+                }
+                Ok(())
+            },
+            |_| panic!("shouldn't wait to retry"),
+            |_| sigterm.load(Ordering::SeqCst));
+        let sent = sent.into_inner();
+        assert!(sent <= 4, "sent {}", sent);
+        assert_eq!(12 - sent, failed);
+    }
+
+    #[test]
+    fn test_retry_wait() {
+        let (control, signal) = UnixStream::pair().unwrap();
+        let sigterm_flag = AtomicBool::new(false);
+        let delay = std::time::Duration::from_millis(300);
+
+        // A signal other than SIGTERM is waiting for the main loop; the delay still applies.
+        std::io::Write::write_all(&mut &signal, &[1]).unwrap();
+        let start = Instant::now();
+        assert!(retry_wait(delay, &control, &sigterm_flag).unwrap());
+        assert!(start.elapsed() >= delay);
+
+        // SIGTERM abandons the retries.
+        sigterm_flag.store(true, Ordering::SeqCst);
+        let start = Instant::now();
+        assert!(!retry_wait(delay, &control, &sigterm_flag).unwrap());
+        assert!(start.elapsed() < delay);
+    }
+
+    #[test]
+    fn test_catch_up() {
+        let today = NaiveDate::from_ymd_opt(2020, 1, 15).unwrap();
+        let now = DaylogTime::new(10, 1);
+
+        // actual time is later
+        assert_eq!((today, DaylogTime::new(14, 0)),
+            catch_up(today, now, (today, DaylogTime::new(14, 0))));
+        assert_eq!((today.succ_opt().unwrap(), DaylogTime::new(0, 0)),
+            catch_up(today, now, (today.succ_opt().unwrap(), DaylogTime::new(0, 0))));
+
+        // already ahead of the actual time (just sent mail for this minute)
+        assert_eq!((today, now), catch_up(today, now, (today, DaylogTime::new(10, 0))));
+    }
+
+    #[test]
+    fn test_plan() {
+        let scheduled = |name: &str, tz: &str, time: &str, enabled: bool| User {
+            timezone: crate::timezone::UserTimezone::parse(tz).unwrap(),
+            email_time_local: DaylogTime::parse(time).unwrap(),
+            enabled,
+            ..user(name)
+        };
+        let users = Users::new(vec![
+            scheduled("alice", "America/Los_Angeles", "18:00", true),
+            scheduled("bob", "Asia/Tokyo", "09:30", true),
+            scheduled("carol", "UTC", "00:30", true),
+            scheduled("dave", "UTC", "12:00", false),
+        ]);
+
+        // 2021-07-15 12:00 UTC: carol and bob's times have passed today
+        let today = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        let lines = plan(&users, today, DaylogTime::new(12, 0));
+        assert_eq!(vec![
+                "at 2021-07-16 00:30 UTC:",
+                "    bob at 2021-07-16 09:30 JST local time (Asia/Tokyo)",
+                "    carol at 2021-07-16 00:30 UTC local time (UTC)",
+                "at 2021-07-16 01:00 UTC:",
+                "    alice at 2021-07-15 18:00 PDT local time (America/Los_Angeles)",
+            ], lines);
+    }
+
+    #[test]
+    fn test_too_late() {
+        let scheduled = chrono::NaiveDate::from_ymd_opt(2021, 7, 15).unwrap()
+            .and_hms_opt(1, 0, 0).unwrap();
+        let max = Some(Duration::minutes(60));
+
+        // A few seconds late is fine.
+        assert_eq!(None, too_late(scheduled, scheduled + Duration::seconds(5), max));
+        assert_eq!(None, too_late(scheduled, scheduled + Duration::minutes(60), max));
+
+        // Woke up from a suspend, or the clock jumped.
+        assert_eq!(Some(Duration::hours(6)),
+            too_late(scheduled, scheduled + Duration::hours(6), max));
+
+        // Without a limit, it's never too late.
+        assert_eq!(None, too_late(scheduled, scheduled + Duration::days(2), None));
+    }
+
+    #[test]
+    fn test_splay_offset() {
+        let window = std::time::Duration::from_secs(300);
+        let usernames = ["alice", "bob", "carol", "dave", "erin", "frank", "grace", "heidi"];
+        let offsets = usernames.iter()
+            .map(|name| splay_offset(name, window))
+            .collect::<Vec<_>>();
+        assert!(offsets.iter().all(|offset| *offset < window));
+        // They're spread out, not all the same.
+        assert!(offsets.iter().any(|offset| *offset != offsets[0]));
+        // The same every time, including across builds.
+        assert_eq!(offsets, usernames.iter()
+            .map(|name| splay_offset(name, window))
+            .collect::<Vec<_>>());
+        assert_eq!(std::time::Duration::from_millis(266_183), splay_offset("alice", window));
+
+        assert_eq!(std::time::Duration::ZERO, splay_offset("alice", std::time::Duration::ZERO));
+    }
+}
@@ -0,0 +1,54 @@
+use crate::SearchArgs;
+use crate::config::Config;
+use crate::db::Database;
+
+/// How many characters of context to show on either side of the first match.
+const SNIPPET_CONTEXT: usize = 40;
+
+pub fn search(config: &Config, args: SearchArgs) -> anyhow::Result<()> {
+    let db = Database::open_read_only(&config.database_path)?;
+    let query = args.query.join(" ");
+    let results = db.search(&args.username, &query)?;
+    for (date, body) in &results {
+        println!("{}: {}", date.format("%Y-%m-%d"), snippet(body, &query));
+    }
+    println!("{} matching entries", results.len());
+    Ok(())
+}
+
+/// Part of the body around the first match of the query's first word, on one line.
+fn snippet(body: &str, query: &str) -> String {
+    let flat = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    let chars = flat.chars().collect::<Vec<_>>();
+    let first_word = query.split_whitespace().next().unwrap_or_default()
+        .to_ascii_lowercase().chars().collect::<Vec<_>>();
+    let pos = chars.windows(first_word.len().max(1))
+        .position(|window| window.iter().map(char::to_ascii_lowercase).eq(first_word.iter().copied()))
+        .unwrap_or(0);
+
+    let start = pos.saturating_sub(SNIPPET_CONTEXT);
+    let end = (pos + first_word.len() + SNIPPET_CONTEXT).min(chars.len());
+    let mut out = String::new();
+    if start > 0 {
+        out.push_str("...");
+    }
+    out.extend(&chars[start .. end]);
+    if end < chars.len() {
+        out.push_str("...");
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!("Flew to Portugal!", snippet("Flew to\nPortugal!", "portugal"));
+        let long = format!("{} went to Portugal {}", "a".repeat(100), "b".repeat(100));
+        assert_eq!(format!("...{} went to Portugal {}...", "a".repeat(31), "b".repeat(39)),
+            snippet(&long, "PORTUGAL trip"));
+        assert_eq!("café ☕", snippet("café ☕", "☕"));
+    }
+}
@@ -1,11 +1,22 @@
 use anyhow::{anyhow, Context};
-use chrono::{Datelike, Duration, NaiveDate};
-use crate::{SendArgs, todays_date};
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Months, NaiveDate, Utc};
+use crate::{SendArgs, SendTestArgs, todays_date};
 use crate::config::Config;
 use crate::db::Database;
-use crate::message_id::{self, read_secret_key};
+use crate::frequency::Frequency;
+use crate::message_id::{self, read_secret_key, SECRET_KEY_LEN};
+use crate::timezone::UserTimezone;
+use crate::user::User;
+use std::fs::File;
 use std::io::{self, Write};
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdin, Command, Stdio};
+use std::sync::Mutex;
+
+const DEFAULT_SUBJECT: &str = "Daylog for {date}";
+const DEFAULT_PROMPT: &str = "What'd you do today, {long_date}?";
+const DEFAULT_SIGNATURE: &str = "sent by daylog";
+const DEFAULT_FROM_NAME: &str = "Daylog";
 
 // This is used in two ways: from the command line, and internally.
 pub enum Mode {
@@ -13,127 +24,510 @@ pub enum Mode {
     Args(SendArgs),
 
     // User already loaded from the database.
-    User(crate::user::User),
+    User(User),
 }
 
 pub fn send(config: &Config, mode: Mode) -> anyhow::Result<()> {
-    let key_bytes = read_secret_key(&config.secret_key_path)
-        .with_context(|| format!("failed to read secret key {:?}", config.secret_key_path))?;
+    let key_bytes = read_secret_key(&config.secret_key)
+        .with_context(|| format!("failed to read secret key from {}", config.secret_key))?;
 
     let db = Database::open(&config.database_path)?;
 
+    match mode {
+        Mode::Args(args) if args.all_users => send_all_users(config, db, key_bytes, args),
+        mode => send_with(config, &db, key_bytes, mode),
+    }
+}
+
+/// Send today's email to every enabled user who gets one today (or on `--date`), in their own
+/// timezone, with the same retries and concurrency as the service.
+fn send_all_users(config: &Config, db: Database, key_bytes: [u8; SECRET_KEY_LEN],
+    args: SendArgs) -> anyhow::Result<()>
+{
+    let date_override = args.date_override.as_deref().map(parse_date_override).transpose()?;
+    let users = db.get_all_users(config.default_email_time)?
+        .iter()
+        .filter(|user| {
+            let date = date_override.unwrap_or_else(|| todays_date(&user.timezone));
+            if !user.enabled {
+                info!("skipping {}: disabled", user.username);
+                false
+            } else if !user.emails_on(date) {
+                info!("skipping {}: no email on {}", user.username, date);
+                false
+            } else {
+                true
+            }
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let total = users.len();
+    let db = Mutex::new(db);
+    let failed = crate::run::send_with_retries(
+        users,
+        config.send_retries,
+        std::time::Duration::from_secs(config.send_retry_delay_seconds),
+        config.send_concurrency as usize,
+        |user| {
+            info!("sending to {:?}", user);
+            let email = prepare(config, &db.lock().unwrap(), key_bytes, Mode::Args(SendArgs {
+                username: Some(user.username.clone()),
+                all_users: false,
+                email_override: None,
+                date_override: args.date_override.clone(),
+                timezone_override: None,
+                dry_run: args.dry_run,
+                output: None,
+            }))?;
+            email.map_or(Ok(()), |email| email.send(config))
+        },
+        |delay| {
+            std::thread::sleep(delay);
+            true
+        },
+        |_user| false,
+    );
+
+    if failed > 0 {
+        anyhow::bail!("failed to send to {} of {} users", failed, total);
+    }
+    if !args.dry_run {
+        say!("sent to {} users", total);
+    }
+    Ok(())
+}
+
+/// Like `send`, but with the database and secret key already opened, for sending to many users.
+pub fn send_with(config: &Config, db: &Database, key_bytes: [u8; SECRET_KEY_LEN], mode: Mode)
+    -> anyhow::Result<()>
+{
+    match prepare(config, db, key_bytes, mode)? {
+        Some(email) => email.send(config),
+        None => Ok(()),
+    }
+}
+
+/// A daily email, written out and ready to hand to sendmail.
+pub struct Email {
+    recipients: Vec<String>,
+    message: Vec<u8>,
+}
+
+impl Email {
+    pub fn send(&self, config: &Config) -> anyhow::Result<()> {
+        let recipients = self.recipients.iter().map(String::as_str).collect::<Vec<_>>();
+        sendmail(config, &recipients, |w| {
+            w.write_all(&self.message).context("failed to write email")
+        })
+    }
+}
+
+/// Write the email `send_with` would send, without sending it, so the database is only needed
+/// while it's written. For a dry run, the email is written to stdout (or `--output`) instead, and
+/// None is returned.
+pub fn prepare(config: &Config, db: &Database, key_bytes: [u8; SECRET_KEY_LEN], mode: Mode)
+    -> anyhow::Result<Option<Email>>
+{
     let username: String;
     let email: String;
+    let cc: Vec<String>;
+    let frequency: Frequency;
+    let timezone: UserTimezone;
     let date: NaiveDate;
     let dry_run: bool;
+    let output: Option<PathBuf>;
 
     match mode {
         Mode::User(user) => {
             username = user.username;
             email = user.email;
-            date = todays_date(&user.timezone);
+            cc = user.cc;
+            frequency = user.frequency;
+            timezone = user.timezone;
+            date = todays_date(&timezone);
             dry_run = false;
+            output = None;
         }
         Mode::Args(args) => {
-            username = args.username;
+            username = args.username.ok_or_else(|| anyhow!("no username given"))?;
 
-            let user = db.get_user(&username)?;
+            let user = db.get_user(&username, config.default_email_time)?;
 
-            email = args.email_override.unwrap_or(user.email);
+            timezone = args.timezone_override.unwrap_or(user.timezone);
             date = match args.date_override {
-                Some(ref date) => {
-                    NaiveDate::parse_from_str(date, "%Y-%m-%d")
-                        .with_context(|| format!("Invalid date specified ({:?})", date))?
-                }
-                None => todays_date(&user.timezone),
+                Some(ref date) => parse_date_override(date)?,
+                None => todays_date(&timezone),
             };
+            email = args.email_override.unwrap_or(user.email);
+            cc = user.cc;
+            frequency = user.frequency;
             dry_run = args.dry_run;
+            output = args.output;
         }
     }
 
     let msgid = message_id::gen_message_id(&username, date, key_bytes)
         .context("failed to generate message ID")?;
 
-    let hostname = hostname::get()
-        .context("failed to get hostname")?
-        .into_string()
-        .map_err(|bad| anyhow!("invalid hostname: {:?}", bad))?;
+    let hostname = message_id_host(config)?;
 
     if dry_run {
-        write_email(io::stdout(), config, &username, &email, &db, date,
+        let w: Box<dyn Write> = match output {
+            Some(path) => Box::new(File::create(&path)
+                .with_context(|| format!("failed to create output file {:?}", path))?),
+            None => Box::new(io::stdout()),
+        };
+        write_email(w, config, &username, &email, &cc, db, date, frequency,
+                    header_time(config, &timezone, Utc::now()),
                     &format!("{}@{}", msgid, hostname))
             .context("failed to write email")?;
-        return Ok(());
+        return Ok(None);
+    }
+
+    let mut message = vec![];
+    write_email(&mut message, config, &username, &email, &cc, db, date, frequency,
+                header_time(config, &timezone, Utc::now()),
+                &format!("{}@{}", msgid, hostname))
+        .context("failed to write email")?;
+    let recipients = std::iter::once(email).chain(cc).collect();
+    Ok(Some(Email { recipients, message }))
+}
+
+/// The time to put in the daily email's 'Date' header: in the user's timezone if `local_date_header`
+/// is set, otherwise in UTC.
+fn header_time(config: &Config, timezone: &UserTimezone, now: DateTime<Utc>) -> DateTime<FixedOffset> {
+    if config.local_date_header {
+        now.with_timezone(timezone).fixed_offset()
+    } else {
+        now.fixed_offset()
+    }
+}
+
+/// Parse the `--date` argument. Only exactly YYYY-MM-DD is accepted: chrono on its own would also
+/// take things like "2021-7-4" or "+2021-07-04".
+pub fn parse_date_override(date: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(anyhow::Error::new)
+        .and_then(|parsed| {
+            if parsed.format("%Y-%m-%d").to_string() == date {
+                Ok(parsed)
+            } else {
+                Err(anyhow!("expected YYYY-MM-DD"))
+            }
+        })
+        .with_context(|| format!("Invalid date specified ({:?})", date))
+}
+
+/// Send a short note to a user confirming that their entry for the given date was saved.
+#[allow(clippy::write_with_newline)]
+pub fn send_ack(config: &Config, user: &User, date: &str) -> anyhow::Result<()> {
+    let msgid = plain_message_id(config, "ack")?;
+
+    sendmail(config, &[&user.email], |w| {
+        write!(w, "Date: {}\r\n", Utc::now().to_rfc2822())?;
+        write!(w, "Subject: Daylog entry saved for {}\r\n", date)?;
+        write!(w, "From: {}\r\n", from_header(config))?;
+        write!(w, "To: <{}>\r\n", user.email)?;
+        write!(w, "Message-ID: <{}>\r\n", msgid)?;
+        write!(w, "Auto-Submitted: auto-replied\r\n")?;
+        write!(w, "\r\n")?;
+        write!(w, "Your daylog entry for {} was saved.\r\n", date)?;
+        write!(w, "\r\n")?;
+        write!(w, "-- \r\n")?;
+        write!(w, "sent by daylog\r\n")?;
+        Ok(())
+    })
+}
+
+/// Send a minimal email to the given address, without looking anything up in the database.
+pub fn send_test(config: &Config, args: SendTestArgs) -> anyhow::Result<()> {
+    crate::address::validate(&args.email)?;
+    let msgid = plain_message_id(config, "test")?;
+    sendmail(config, &[&args.email], |w| write_test_email(w, config, &args.email, Utc::now(), &msgid))?;
+    say!("test email sent to {}", args.email);
+    Ok(())
+}
+
+#[allow(clippy::write_with_newline)]
+fn write_test_email(mut w: impl Write, config: &Config, email: &str, now: DateTime<Utc>, msgid: &str)
+    -> anyhow::Result<()>
+{
+    write!(w, "Date: {}\r\n", now.to_rfc2822())?;
+    write!(w, "Subject: Daylog test email\r\n")?;
+    write!(w, "From: {}\r\n", from_header(config))?;
+    write!(w, "To: <{}>\r\n", email)?;
+    write!(w, "Message-ID: <{}>\r\n", msgid)?;
+    write!(w, "Auto-Submitted: auto-generated\r\n")?;
+    write!(w, "\r\n")?;
+    write!(w, "This is a test email from daylog. If you got it, sending mail works.\r\n")?;
+    write!(w, "\r\n")?;
+    write!(w, "-- \r\n")?;
+    write!(w, "sent by daylog\r\n")?;
+    Ok(())
+}
+
+/// A unique message ID for emails other than the daily one. These are deliberately not our
+/// encrypted message IDs, so any replies to them (including auto-responders) don't get added to an
+/// entry.
+fn plain_message_id(config: &Config, kind: &str) -> anyhow::Result<String> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let unique = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    Ok(format!("daylog-{}.{}@{}", kind, unique, message_id_host(config)?))
+}
+
+fn from_header(config: &Config) -> String {
+    let name = config.from_name.as_deref().unwrap_or(DEFAULT_FROM_NAME);
+    let name = if !name.is_ascii() {
+        encode_header(name)
+    } else if name.contains(|c| "()<>[]:;@\\,.\"".contains(c)) {
+        // Special characters need the name to be a quoted string.
+        format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        name.to_owned()
+    };
+    format!("{} <{}>", name, config.return_addr)
+}
+
+/// Encode a header value as RFC 2047 encoded-words if it has any non-ASCII characters. Each word is
+/// kept within the 75 character limit, and they're separated by folding whitespace.
+fn encode_header(value: &str) -> String {
+    use base64::Engine;
+    if value.is_ascii() {
+        return value.to_owned();
+    }
+
+    // 75, minus the 12 characters of "=?UTF-8?B?" and "?=", leaves room for 15 groups of base64,
+    // which encode 45 bytes. Characters aren't split across words.
+    const MAX_BYTES: usize = 45;
+    let mut words = vec![];
+    let mut rest = value;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(MAX_BYTES);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, remainder) = rest.split_at(end);
+        words.push(format!("=?UTF-8?B?{}?=",
+            base64::engine::general_purpose::STANDARD.encode(chunk)));
+        rest = remainder;
+    }
+    words.join("\r\n ")
+}
+
+/// The domain part of Message-IDs: `message_id_host` from the config, or the system hostname.
+fn message_id_host(config: &Config) -> anyhow::Result<String> {
+    if let Some(ref host) = config.message_id_host {
+        return Ok(host.clone());
     }
+    hostname::get()
+        .context("failed to get hostname")?
+        .into_string()
+        .map_err(|bad| anyhow!("invalid hostname: {:?}", bad))
+}
 
-    let mut child = Command::new("sendmail")
+/// Run the 'sendmail' command to send an email to the given addresses, with the message supplied
+/// by the given function.
+fn sendmail(
+    config: &Config,
+    recipients: &[&str],
+    write: impl FnOnce(&mut ChildStdin) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let sendmail_path = config.sendmail_path.as_deref().unwrap_or(Path::new("sendmail"));
+    let mut child = Command::new(sendmail_path)
         .arg("-i")
         .arg("-f")
         .arg(&config.return_addr)
-        .arg(&email)
+        .args(recipients)
         .stdin(Stdio::piped())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .spawn()
-        .context("failed to run 'sendmail' command")?;
+        .map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                anyhow!("{:?} command not found. Daylog needs a mail transfer agent (such as \
+                    Postfix) which provides a 'sendmail' command; install one, or set \
+                    'sendmail_path' in the config file to its location.", sendmail_path)
+            } else {
+                anyhow::Error::new(e)
+                    .context(format!("failed to run {:?} command", sendmail_path))
+            }
+        })?;
 
     {
-        let sendmail = child.stdin.as_mut().expect("failed to get 'sendmail' command stdin");
-        write_email(sendmail, config, &username, &email, &db, date,
-                    &format!("{}@{}", msgid, hostname))
-            .context("failed to write email")?;
+        let stdin = child.stdin.as_mut().expect("failed to get 'sendmail' command stdin");
+        write(stdin)?;
     }
 
-    child.wait()
+    let status = child.wait()
         .context("failed to wait for 'mail' command")?;
+    if !status.success() {
+        return Err(SendmailFailed { code: status.code() }.into());
+    }
 
     Ok(())
 }
 
-#[allow(clippy::write_with_newline)]
+/// The 'sendmail' command exited unsuccessfully.
+#[derive(Debug)]
+pub struct SendmailFailed {
+    /// Exit code, or None if it was killed by a signal.
+    pub code: Option<i32>,
+}
+
+impl SendmailFailed {
+    /// Whether retrying won't help. Sendmail uses the exit codes from sysexits.h; some of them
+    /// mean the message or recipient is bad, and the rest might be temporary problems.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self.code,
+            Some(64)    // EX_USAGE
+            | Some(65)  // EX_DATAERR
+            | Some(67)  // EX_NOUSER
+            | Some(68)  // EX_NOHOST
+            | Some(77)) // EX_NOPERM
+    }
+}
+
+impl std::fmt::Display for SendmailFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "'sendmail' command failed with exit code {}", code),
+            None => write!(f, "'sendmail' command was killed by a signal"),
+        }
+    }
+}
+
+impl std::error::Error for SendmailFailed {}
+
+#[allow(clippy::write_with_newline, clippy::too_many_arguments)]
 fn write_email(
     mut w: impl Write,
     config: &Config,
     username: &str,
     email: &str,
+    cc: &[String],
     db: &Database,
     date: NaiveDate,
+    frequency: Frequency,
+    now: DateTime<FixedOffset>,
     msgid: &str,
 ) -> anyhow::Result<()> {
-    write!(w, "Date: {}\r\n", chrono::Utc::now().to_rfc2822())?;
-    write!(w, "Subject: Daylog for {}\r\n", date.format("%Y-%m-%d"))?;
-    write!(w, "From: Daylog <{}>\r\n", config.return_addr)?;
+    write!(w, "Date: {}\r\n", now.to_rfc2822())?;
+    let subject = config.subject_template.as_deref().unwrap_or(DEFAULT_SUBJECT);
+    write!(w, "Subject: {}\r\n", encode_header(&fill_template(subject, username, date)))?;
+    write!(w, "From: {}\r\n", from_header(config))?;
+    if let Some(reply_to) = &config.reply_to {
+        write!(w, "Reply-To: <{}>\r\n", reply_to)?;
+    }
     write!(w, "To: <{}>\r\n", email)?;
+    if !cc.is_empty() {
+        let cc = cc.iter().map(|addr| format!("<{}>", addr)).collect::<Vec<_>>();
+        write!(w, "Cc: {}\r\n", cc.join(", "))?;
+    }
     write!(w, "Message-ID: <{}>\r\n", msgid)?;
+    // The message ID authenticates the request; see `ingest::unsubscribe_token`.
+    let unsubscribe_addr = config.reply_to.as_deref().unwrap_or(&config.return_addr);
+    // Base64 padding is the only thing in it that needs escaping in a mailto URL.
+    let token = msgid.split('@').next().unwrap().replace('=', "%3D");
+    write!(w, "List-Unsubscribe: <mailto:{}?subject=unsubscribe%20{}>\r\n",
+        unsubscribe_addr, token)?;
+    // Keeps vacation auto-responders from replying, which would look like an entry.
+    write!(w, "Auto-Submitted: auto-generated\r\n")?;
+    if config.precedence_bulk {
+        write!(w, "Precedence: bulk\r\n")?;
+    }
     write!(w, "\r\n")?;
-    write!(w, "What'd you do today, {}?\r\n", date.format("%A, %B %e, %Y"))?; // Sunday, July 8, 2001
+    write!(w, "{}\r\n", fill_template(pick_prompt(config, date), username, date))?;
     write!(w, "\r\n")?;
 
-    fn months_ago(date: NaiveDate, months: i32) -> Option<NaiveDate> {
-        let mut year = date.year();
-        let mut month = date.month();
-        let day = date.day();
+    if config.show_streak {
+        // Only count up to yesterday: today's entry is what we're asking for.
+        let streak = match date.pred_opt() {
+            Some(yesterday) => db.streak(username, yesterday)?,
+            None => 0,
+        };
+        if streak >= 2 {
+            write!(w, "Current streak: {} days\r\n", streak)?;
+            write!(w, "\r\n")?;
+        }
+    }
 
-        for _ in 0 .. months {
-            month -= 1;
-            if month == 0 {
-                month = 12;
-                year -= 1;
+    // Weekly and monthly emails recap everything since the last one.
+    let recap = frequency.previous(date).zip(date.pred_opt());
+    if let Some((previous, yesterday)) = recap {
+        let entries = db.get_entries(username, previous, yesterday)?;
+        if !entries.is_empty() {
+            write!(w, "Since {}, you wrote\r\n", previous.format("%A, %B %e"))?;
+            let entries = entries.into_iter()
+                .map(|(date, body)| (date.format("%a %b %e").to_string(),
+                    crate::pgp::redact_messages(&body)))
+                .collect::<Vec<_>>();
+            write_entries(&mut w, &entries)?;
+            write!(w, "\r\n")?;
+        }
+    }
+
+    // Encrypted entries can only be labeled as such; the user's mail client wouldn't decrypt
+    // ciphertext pasted into the middle of a message.
+    let mut past_events = vec![];
+    for (label, past_date) in past_dates(date) {
+        // Don't repeat what the recap already showed.
+        if recap.is_some_and(|(from, to)| from <= past_date && past_date <= to) {
+            continue;
+        }
+        // Entries are stored by the local date they were written on, so this is the same day of
+        // the user's life even if they've moved timezones since; say so when they have.
+        let past_date = past_date.format("%Y-%m-%d").to_string();
+        match db.get_entry_with_old_timezone(username, &past_date) {
+            Ok(Some((body, None))) => {
+                past_events.push((label.to_string(), crate::pgp::redact_messages(&body)));
+            },
+            Ok(Some((body, Some(tz)))) => {
+                past_events.push((format!("{} (in {})", label, tz),
+                    crate::pgp::redact_messages(&body)));
+            },
+            Ok(None) => (),
+            Err(e) => {
+                eprintln!("error querying database for {}/{}: {}", username, past_date, e);
             }
         }
+    }
+
+    if !past_events.is_empty() {
+        write!(w, "Here's what you were doing\r\n")?;
+        write_entries(&mut w, &past_events)?;
+        write!(w, "\r\n")?;
+    }
 
-        NaiveDate::from_ymd_opt(year, month, day)
+    write!(w, "-- \r\n")?;
+    let signature = config.signature.as_deref().unwrap_or(DEFAULT_SIGNATURE);
+    for line in fill_template(signature, username, date).lines() {
+        write!(w, "{}\r\n", line)?;
     }
+    Ok(())
+}
 
-    fn years_ago(date: NaiveDate, years: i32) -> Option<NaiveDate> {
-        NaiveDate::from_ymd_opt(
-            date.year() - years,
-            date.month(),
-            date.day()
-        )
+/// Write labeled entries, one per line, or with multi-line entries indented under their label.
+#[allow(clippy::write_with_newline)]
+fn write_entries(mut w: impl Write, entries: &[(String, String)]) -> io::Result<()> {
+    for (label, body) in entries {
+        let lines = body.lines().collect::<Vec<_>>();
+        if lines.len() > 1 {
+            write!(w, "\t{}:\r\n", label)?;
+            for line in &lines {
+                write!(w, "\t\t{}\r\n", line)?;
+            }
+        } else {
+            write!(w, "\t{}:\t{}\r\n", label, body)?;
+        }
     }
+    Ok(())
+}
 
-    let past_times = [
+/// The dates to remind the user of in the email for the given date, labeled with how long ago
+/// they were.
+fn past_dates(date: NaiveDate) -> Vec<(&'static str, NaiveDate)> {
+    dedup_dates([
         ("one week ago", Some(date - Duration::weeks(1))),
         ("two weeks ago", Some(date - Duration::weeks(2))),
         ("three weeks ago", Some(date - Duration::weeks(3))),
@@ -153,45 +547,560 @@ fn write_email(
         ("eight years ago", years_ago(date, 8)),
         ("nine years ago", years_ago(date, 9)),
         ("ten years ago", years_ago(date, 10)),
-    ];
+    ])
+}
 
-    let mut past_events = vec![];
-    for (label, past_date) in &past_times {
-        let past_date = match past_date {
-            Some(d) => d.format("%Y-%m-%d").to_string(),
-            None => continue,
-        };
+/// Drop dates that don't exist, and ones that come up more than once (which clamping to the end of
+/// a month could do), keeping the first label, which is for the shortest interval.
+fn dedup_dates(
+    dates: impl IntoIterator<Item = (&'static str, Option<NaiveDate>)>,
+) -> Vec<(&'static str, NaiveDate)> {
+    let mut seen = std::collections::BTreeSet::new();
+    dates.into_iter()
+        .filter_map(|(label, date)| date.map(|date| (label, date)))
+        .filter(|(_, date)| seen.insert(*date))
+        .collect()
+}
 
-        match db.get_entry(username, &past_date) {
-            Ok(Some(body)) => {
-                past_events.push((label, body));
-            },
-            Ok(None) => (),
-            Err(e) => {
-                eprintln!("error querying database for {}/{}: {}", username, past_date, e);
-            }
+/// The same day of the month, the given number of months earlier. If that month is too short to
+/// have that day, the last day of the month is used instead, so March 31 maps to February 28 (or
+/// 29), and so do March 29 and 30 in non-leap years.
+fn months_ago(date: NaiveDate, months: u32) -> Option<NaiveDate> {
+    date.checked_sub_months(Months::new(months))
+}
+
+/// The same date, the given number of years earlier. There is no clamping here: February 29 has no
+/// anniversary in non-leap years.
+fn years_ago(date: NaiveDate, years: i32) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt(
+        date.year() - years,
+        date.month(),
+        date.day()
+    )
+}
+
+/// Pick the prompt template for the given date. With a list of prompts, the same date always gets
+/// the same one, and consecutive days go through the list in order.
+fn pick_prompt(config: &Config, date: NaiveDate) -> &str {
+    if config.prompts.is_empty() {
+        return config.prompt_template.as_deref().unwrap_or(DEFAULT_PROMPT);
+    }
+    let index = date.num_days_from_ce().rem_euclid(config.prompts.len() as i32);
+    &config.prompts[index as usize]
+}
+
+/// Substitute placeholders in a template from the config file:
+///   {date}       2001-07-08
+///   {long_date}  Sunday, July  8, 2001
+///   {weekday}    Sunday
+///   {username}   the user's username
+/// Anything else in braces is left as-is.
+pub fn fill_template(template: &str, username: &str, date: NaiveDate) -> String {
+    template
+        .replace("{date}", &date.format("%Y-%m-%d").to_string())
+        .replace("{long_date}", &date.format("%A, %B %e, %Y").to_string())
+        .replace("{weekday}", &date.format("%A").to_string())
+        .replace("{username}", username)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{test_config, TempDir};
+
+    #[test]
+    fn test_fill_template() {
+        let date = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap();
+        assert_eq!("Daylog for 2001-07-08", fill_template(DEFAULT_SUBJECT, "bob", date));
+        assert_eq!("What'd you do today, Sunday, July  8, 2001?",
+            fill_template(DEFAULT_PROMPT, "bob", date));
+        assert_eq!("Hey bob, how was your Sunday?",
+            fill_template("Hey {username}, how was your {weekday}?", "bob", date));
+    }
+
+    #[test]
+    fn test_fill_template_missing_placeholder() {
+        let date = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap();
+        assert_eq!("no placeholders here", fill_template("no placeholders here", "bob", date));
+        assert_eq!("{nonsense} on 2001-07-08",
+            fill_template("{nonsense} on {date}", "bob", date));
+    }
+
+    #[test]
+    fn test_parse_date_override() {
+        assert_eq!(NaiveDate::from_ymd_opt(2024, 2, 29).unwrap(),
+            parse_date_override("2024-02-29").unwrap());
+
+        for bad in ["2023-02-29", "2021-13-01", "2021-04-31", "2021-7-4", "+2021-07-04",
+            "2021-07-04 ", "07/04/2021", "yesterday", ""]
+        {
+            let err = parse_date_override(bad).unwrap_err();
+            assert_eq!(format!("Invalid date specified ({:?})", bad), err.to_string());
         }
     }
 
-    if !past_events.is_empty() {
-        write!(w, "Here's what you were doing\r\n")?;
+    #[test]
+    fn test_pick_prompt() {
+        let dir = TempDir::new("pick-prompt");
+        let date = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap();
+
+        let config = test_config(&dir, "");
+        assert_eq!(DEFAULT_PROMPT, pick_prompt(&config, date));
+        let config = test_config(&dir, "prompt_template: How was {weekday}?\n");
+        assert_eq!("How was {weekday}?", pick_prompt(&config, date));
+
+        let config = test_config(&dir, "\
+prompt_template: ignored
+prompts:
+  - one
+  - two
+  - three
+");
+        let week = date.iter_days().take(7).map(|d| pick_prompt(&config, d)).collect::<Vec<_>>();
+        assert_eq!(week[0 .. 3], week[3 .. 6]);
+        assert_eq!(week[0], week[6]);
+        let mut first_three = week[0 .. 3].to_vec();
+        first_three.sort();
+        assert_eq!(vec!["one", "three", "two"], first_three);
+
+        // Always the same for the same date.
+        assert_eq!(pick_prompt(&config, date), pick_prompt(&test_config(&dir, "\
+prompts: [one, two, three]
+"), date));
+
+        // Including across the end of the year.
+        let new_year = NaiveDate::from_ymd_opt(2001, 12, 31).unwrap().iter_days().take(3)
+            .map(|d| pick_prompt(&config, d)).collect::<Vec<_>>();
+        assert_ne!(new_year[0], new_year[1]);
+        assert_ne!(new_year[1], new_year[2]);
     }
-    for (label, body) in &past_events {
-        let lines = body.lines().collect::<Vec<_>>();
-        if lines.len() > 1 {
-            write!(w, "\t{}:\r\n", label)?;
-            for line in &lines {
-                write!(w, "\t\t{}\r\n", line)?;
+
+    #[test]
+    fn test_dedup_dates() {
+        let date = |m, d| NaiveDate::from_ymd_opt(2021, m, d);
+        // The first label for each date wins, and missing dates are dropped.
+        assert_eq!(vec![("one month ago", date(3, 31).unwrap()), ("clamped", date(2, 28).unwrap())],
+            dedup_dates([
+                ("one month ago", date(3, 31)),
+                ("clamped", date(2, 28)),
+                ("also clamped", date(2, 28)),
+                ("nonexistent", None),
+                ("one month ago, again", date(3, 31)),
+            ]));
+    }
+
+    #[test]
+    fn test_months_ago() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        // The last day of each month, one month earlier.
+        for (year, expected) in [
+            (2023, [(2022, 12, 31), (2023, 1, 28), (2023, 2, 28), (2023, 3, 30), (2023, 4, 30),
+                (2023, 5, 30), (2023, 6, 30), (2023, 7, 31), (2023, 8, 30), (2023, 9, 30),
+                (2023, 10, 30), (2023, 11, 30)]),
+            (2024, [(2023, 12, 31), (2024, 1, 29), (2024, 2, 29), (2024, 3, 30), (2024, 4, 30),
+                (2024, 5, 30), (2024, 6, 30), (2024, 7, 31), (2024, 8, 30), (2024, 9, 30),
+                (2024, 10, 30), (2024, 11, 30)]),
+        ] {
+            for (month, (y, m, d)) in (1 ..= 12).zip(expected) {
+                let month_end = match month {
+                    12 => date(year, 12, 31),
+                    _ => date(year, month + 1, 1).pred_opt().unwrap(),
+                };
+                assert_eq!(Some(date(y, m, d)), months_ago(month_end, 1), "{}", month_end);
             }
-        } else {
-            write!(w, "\t{}:\t{}\r\n", label, body)?;
         }
+
+        // Days that don't exist in the earlier month all clamp to its last day.
+        assert_eq!(Some(date(2023, 2, 28)), months_ago(date(2023, 3, 29), 1));
+        assert_eq!(Some(date(2023, 2, 28)), months_ago(date(2023, 3, 30), 1));
+        assert_eq!(Some(date(2024, 2, 29)), months_ago(date(2024, 3, 31), 1));
+        assert_eq!(Some(date(2024, 2, 29)), months_ago(date(2024, 8, 31), 6));
+        assert_eq!(Some(date(2023, 11, 30)), months_ago(date(2024, 1, 31), 2));
+
+        // Days that do exist are left alone.
+        assert_eq!(Some(date(2024, 2, 28)), months_ago(date(2024, 3, 28), 1));
+        assert_eq!(Some(date(2023, 12, 15)), months_ago(date(2024, 3, 15), 3));
+
+        assert_eq!(None, years_ago(date(2024, 2, 29), 1));
+        assert_eq!(Some(date(2020, 2, 29)), years_ago(date(2024, 2, 29), 4));
     }
-    if !past_events.is_empty() {
-        write!(w, "\r\n")?;
+
+    fn render(config: &Config, cc: &[String], db: &Database, date: NaiveDate) -> String {
+        render_with_frequency(config, cc, db, date, Frequency::Daily)
     }
 
-    write!(w, "-- \r\n")?;
-    write!(w, "sent by daylog\r\n")?;
-    Ok(())
+    fn render_with_frequency(config: &Config, cc: &[String], db: &Database, date: NaiveDate,
+        frequency: Frequency) -> String
+    {
+        let now = date.and_hms_opt(18, 0, 0).unwrap().and_utc();
+        let mut out = vec![];
+        write_email(&mut out, config, "bob", "bob@example.com", cc, db, date, frequency,
+            now.fixed_offset(), "msgid@example.com")
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Compare a rendered email against `tests/emails/<name>.eml`. Set DAYLOG_UPDATE_GOLDEN=1 to
+    /// rewrite the files instead.
+    fn check_golden(name: &str, rendered: &str) {
+        let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests").join("emails").join(format!("{}.eml", name));
+        if std::env::var_os("DAYLOG_UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, rendered).unwrap();
+        }
+        let expected = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(expected, rendered, "wrong output for {}", name);
+    }
+
+    #[test]
+    fn test_golden_past_entries() {
+        let dir = TempDir::new("golden-past-entries");
+        let config = test_config(&dir, "");
+        let mut db = Database::open(&config.database_path).unwrap();
+        db.add_entry("bob", "2021-07-08", "went for a walk", None).unwrap();
+        db.add_entry("bob", "2021-06-15", "moved house\nunpacked some boxes", None).unwrap();
+        db.add_entry("bob", "2021-01-15", "six months ago", None).unwrap();
+        db.add_entry("bob", "2020-07-15", "one year ago", None).unwrap();
+        db.add_entry("bob", "2011-07-15", "ten years ago", None).unwrap();
+
+        // These aren't any of the dates that get looked up.
+        db.add_entry("bob", "2021-07-14", "yesterday", None).unwrap();
+        db.add_entry("bob", "2010-07-15", "eleven years ago", None).unwrap();
+        db.add_entry("alice", "2021-07-08", "someone else's entry", None).unwrap();
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        check_golden("past-entries", &render(&config, &[], &db, date));
+    }
+
+    #[test]
+    fn test_moved_timezone() {
+        let dir = TempDir::new("moved-timezone");
+        let config = test_config(&dir, "");
+        let mut db = Database::open(&config.database_path).unwrap();
+        db.add_user("bob", "bob@example.com", "Asia/Tokyo", "09:00").unwrap();
+        db.add_entry("bob", "2020-07-15", "in Tokyo", None).unwrap();
+
+        rusqlite::Connection::open(&config.database_path).unwrap()
+            .execute("UPDATE users SET timezone = 'America/Los_Angeles'", []).unwrap();
+        db.add_entry("bob", "2021-06-15", "in Los Angeles", None).unwrap();
+
+        let email = render(&config, &[], &db, NaiveDate::from_ymd_opt(2021, 7, 15).unwrap());
+        assert!(email.contains("\tone month ago:\tin Los Angeles\r\n"), "{}", email);
+        assert!(email.contains("\tone year ago (in Asia/Tokyo):\tin Tokyo\r\n"), "{}", email);
+    }
+
+    #[test]
+    fn test_golden_leap_day() {
+        let dir = TempDir::new("golden-leap-day");
+        let config = test_config(&dir, "");
+        let mut db = Database::open(&config.database_path).unwrap();
+        // There's no 2023-02-29, so there's no "one year ago", and neither day next to it is used
+        // instead.
+        db.add_entry("bob", "2023-02-28", "not a leap year", None).unwrap();
+        db.add_entry("bob", "2023-03-01", "still not a leap year", None).unwrap();
+        db.add_entry("bob", "2020-02-29", "previous leap day", None).unwrap();
+        db.add_entry("bob", "2024-01-29", "one month ago", None).unwrap();
+
+        let date = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+        check_golden("leap-day", &render(&config, &[], &db, date));
+    }
+
+    #[test]
+    fn test_golden_no_past_entries() {
+        let dir = TempDir::new("golden-no-past-entries");
+        let config = test_config(&dir, "");
+        let mut db = Database::open(&config.database_path).unwrap();
+        db.add_entry("bob", "2021-07-14", "yesterday", None).unwrap();
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 15).unwrap();
+        check_golden("no-past-entries", &render(&config, &[], &db, date));
+    }
+
+    #[test]
+    fn test_golden_weekly() {
+        let dir = TempDir::new("golden-weekly");
+        let config = test_config(&dir, "");
+        let mut db = Database::open(&config.database_path).unwrap();
+        db.add_entry("bob", "2021-07-10", "before the last email", None).unwrap();
+        db.add_entry("bob", "2021-07-11", "the last email", None).unwrap();
+        db.add_entry("bob", "2021-07-13", "went for a walk\nsaw a heron", None).unwrap();
+        db.add_entry("bob", "2021-07-17", "yesterday", None).unwrap();
+        db.add_entry("bob", "2021-06-18", "one month ago", None).unwrap();
+
+        // Sunday
+        let date = NaiveDate::from_ymd_opt(2021, 7, 18).unwrap();
+        check_golden("weekly", &render_with_frequency(&config, &[], &db, date,
+            Frequency::Weekly(chrono::Weekday::Sun)));
+    }
+
+    #[test]
+    fn test_encrypted_entries() {
+        let dir = TempDir::new("encrypted-entries");
+        let config = test_config(&dir, "");
+        let mut db = Database::open(&config.database_path).unwrap();
+        let message = "-----BEGIN PGP MESSAGE-----\n\nhQEMA+abc\n=xyz\n-----END PGP MESSAGE-----";
+        db.add_entry("bob", "2021-07-13", message, None).unwrap();
+        db.add_entry("bob", "2021-06-18", "before the key", None).unwrap();
+        db.add_entry("bob", "2021-06-18", message, None).unwrap();
+
+        let date = NaiveDate::from_ymd_opt(2021, 7, 18).unwrap();
+        let email = render_with_frequency(&config, &[], &db, date,
+            Frequency::Weekly(chrono::Weekday::Sun));
+        assert!(email.contains("\tTue Jul 13:\t[encrypted]\r\n"), "{}", email);
+        assert!(email.contains("\tone month ago:\r\n\t\tbefore the key\r\n\t\t[encrypted]\r\n"),
+            "{}", email);
+        assert!(!email.contains("PGP"), "{}", email);
+    }
+
+    #[test]
+    fn test_streak_line() {
+        let dir = TempDir::new("streak-line");
+        let config = test_config(&dir, "show_streak: true\n");
+        let mut db = Database::open(&config.database_path).unwrap();
+        let date = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap();
+
+        db.add_entry("bob", "2001-07-07", "stuff", None).unwrap();
+        assert!(!render(&config, &[], &db, date).contains("streak"));
+
+        db.add_entry("bob", "2001-07-06", "stuff", None).unwrap();
+        db.add_entry("bob", "2001-07-08", "today's entry doesn't count", None).unwrap();
+        assert!(render(&config, &[], &db, date).contains("\r\nCurrent streak: 2 days\r\n"));
+    }
+
+    #[test]
+    fn test_from_and_reply_to_headers() {
+        let date = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap();
+        let headers = |extra: &str| {
+            let dir = TempDir::new("from-reply-to");
+            let config = test_config(&dir, extra);
+            let db = Database::open(&config.database_path).unwrap();
+            render(&config, &[], &db, date)
+                .split("\r\n\r\n").next().unwrap()
+                .lines()
+                .filter(|line| line.starts_with("From:") || line.starts_with("Reply-To:"))
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["From: Daylog <daylog@example.com>"], headers(""));
+        assert_eq!(vec!["From: My Journal <daylog@example.com>",
+                "Reply-To: <daylog+replies@example.com>"],
+            headers("from_name: My Journal\nreply_to: daylog+replies@example.com\n"));
+    }
+
+    #[test]
+    fn test_encode_header() {
+        assert_eq!("Daylog for 2001-07-08", encode_header("Daylog for 2001-07-08"));
+        assert_eq!("=?UTF-8?B?VGFnZWJ1Y2ggZsO8ciAyMDAxLTA3LTA4?=",
+            encode_header("Tagebuch für 2001-07-08"));
+        assert_eq!("=?UTF-8?B?8J+TlCBEYXlsb2c=?=", encode_header("📔 Daylog"));
+
+        // Long values are split into multiple words, without splitting characters.
+        let long = "é".repeat(30);
+        let encoded = encode_header(&long);
+        let words = encoded.split("\r\n ").collect::<Vec<_>>();
+        assert_eq!(2, words.len());
+        assert!(words.iter().all(|word| word.len() <= 75), "{:?}", words);
+        let decoded = words.iter()
+            .map(|word| word.strip_prefix("=?UTF-8?B?").unwrap().strip_suffix("?=").unwrap())
+            .map(|b64| base64::Engine::decode(&base64::engine::general_purpose::STANDARD, b64)
+                .unwrap())
+            .map(|bytes| String::from_utf8(bytes).unwrap())
+            .collect::<String>();
+        assert_eq!(long, decoded);
+    }
+
+    #[test]
+    fn test_non_ascii_headers() {
+        let dir = TempDir::new("non-ascii-headers");
+        let config = test_config(&dir, "from_name: Journal Über\n\
+            subject_template: \"Qu'as-tu fait le {date} ?\"\n");
+        let db = Database::open(&config.database_path).unwrap();
+        let date = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap();
+        let email = render(&config, &[], &db, date);
+        assert!(email.contains("\r\nSubject: Qu'as-tu fait le 2001-07-08 ?\r\n"));
+        assert!(email.contains("\r\nFrom: =?UTF-8?B?Sm91cm5hbCDDnGJlcg==?= <daylog@example.com>\r\n"));
+
+        let dir = TempDir::new("non-ascii-headers");
+        let config = test_config(&dir, "from_name: \"Daylog, Inc.\"\n\
+            subject_template: \"¿Qué hiciste el {date}?\"\n");
+        let email = render(&config, &[], &db, date);
+        assert!(email.contains("\r\nSubject: =?UTF-8?B?wr9RdcOpIGhpY2lzdGUgZWwgMjAwMS0wNy0wOD8=?=\r\n"));
+        assert!(email.contains("\r\nFrom: \"Daylog, Inc.\" <daylog@example.com>\r\n"));
+    }
+
+    #[test]
+    fn test_list_unsubscribe_header() {
+        let dir = TempDir::new("list-unsubscribe");
+        let config = test_config(&dir, "");
+        let db = Database::open(&config.database_path).unwrap();
+        let date = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap();
+        assert!(render(&config, &[], &db, date).contains("\r\nList-Unsubscribe: \
+            <mailto:daylog@example.com?subject=unsubscribe%20msgid>\r\n"));
+
+        let now = date.and_hms_opt(18, 0, 0).unwrap().and_utc();
+        let mut out = vec![];
+        write_email(&mut out, &config, "bob", "bob@example.com", &[], &db, date, Frequency::Daily,
+            now.fixed_offset(), "daylog.1.abc.def=@example.com")
+            .unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("\r\nList-Unsubscribe: \
+            <mailto:daylog@example.com?subject=unsubscribe%20daylog.1.abc.def%3D>\r\n"));
+
+        let config = test_config(&dir, "reply_to: daylog+replies@example.com\n");
+        assert!(render(&config, &[], &db, date).contains("\r\nList-Unsubscribe: \
+            <mailto:daylog+replies@example.com?subject=unsubscribe%20msgid>\r\n"));
+    }
+
+    #[test]
+    fn test_automated_headers() {
+        let dir = TempDir::new("automated-headers");
+        let config = test_config(&dir, "");
+        let db = Database::open(&config.database_path).unwrap();
+        let date = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap();
+
+        let headers = |config: &Config| {
+            render(config, &[], &db, date)
+                .split("\r\n\r\n").next().unwrap()
+                .lines()
+                .filter(|line| line.starts_with("Auto-Submitted:")
+                    || line.starts_with("Precedence:")
+                    || line.starts_with("Message-ID:"))
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+
+        // The message ID, which replies are matched by, is still there.
+        assert_eq!(vec!["Message-ID: <msgid@example.com>", "Auto-Submitted: auto-generated"],
+            headers(&config));
+        let config = test_config(&dir, "precedence_bulk: true\n");
+        assert_eq!(vec!["Message-ID: <msgid@example.com>", "Auto-Submitted: auto-generated",
+                "Precedence: bulk"],
+            headers(&config));
+    }
+
+    #[test]
+    fn test_cc_header() {
+        let dir = TempDir::new("cc-header");
+        let config = test_config(&dir, "");
+        let db = Database::open(&config.database_path).unwrap();
+        let date = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap();
+
+        let headers = |cc: &[String]| {
+            render(&config, cc, &db, date)
+                .split("\r\n\r\n").next().unwrap()
+                .lines()
+                .filter(|line| line.starts_with("To:") || line.starts_with("Cc:"))
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["To: <bob@example.com>"], headers(&[]));
+        assert_eq!(vec!["To: <bob@example.com>", "Cc: <a@example.com>, <b@example.com>"],
+            headers(&["a@example.com".to_owned(), "b@example.com".to_owned()]));
+    }
+
+    #[test]
+    fn test_test_email() {
+        let dir = TempDir::new("test-email");
+        let config = test_config(&dir, "");
+        let now = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap().and_hms_opt(18, 0, 0).unwrap().and_utc();
+        let mut out = vec![];
+        write_test_email(&mut out, &config, "bob@example.com", now, "daylog-test.1@example.com")
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (headers, body) = out.split_once("\r\n\r\n").unwrap();
+        assert_eq!(vec![
+                "Date: Sun, 8 Jul 2001 18:00:00 +0000",
+                "Subject: Daylog test email",
+                "From: Daylog <daylog@example.com>",
+                "To: <bob@example.com>",
+                "Message-ID: <daylog-test.1@example.com>",
+                "Auto-Submitted: auto-generated",
+            ], headers.split("\r\n").collect::<Vec<_>>());
+        assert!(body.starts_with("This is a test email from daylog."), "{}", body);
+        assert!(!message_id::is_our_message_id("daylog-test.1@example.com"));
+    }
+
+    #[test]
+    fn test_send_all_users() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("send-all-users");
+        // Stands in for sendmail, noting the recipients of each email.
+        let sendmail = dir.path().join("sendmail");
+        std::fs::write(&sendmail, format!("#!/bin/sh\necho \"$@\" >> {:?}\ncat > /dev/null\n",
+            dir.path().join("sent.log"))).unwrap();
+        std::fs::set_permissions(&sendmail, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config = test_config(&dir, &format!("sendmail_path: {:?}\n", sendmail));
+        message_id::gen_secret_key(&config.secret_key, false).unwrap();
+
+        let mut db = Database::open(&config.database_path).unwrap();
+        for name in ["alice", "bob", "carol"] {
+            db.add_user(name, &format!("{}@example.com", name), "UTC", "18:00").unwrap();
+        }
+        db.set_user_enabled("carol", false).unwrap();
+
+        let args = |dry_run: bool| SendArgs {
+            username: None,
+            all_users: true,
+            email_override: None,
+            date_override: None,
+            timezone_override: None,
+            dry_run,
+            output: None,
+        };
+        send(&config, Mode::Args(args(false))).unwrap();
+        let sent = std::fs::read_to_string(dir.path().join("sent.log")).unwrap();
+        assert_eq!(vec!["-i -f daylog@example.com alice@example.com",
+                "-i -f daylog@example.com bob@example.com"],
+            sent.lines().collect::<Vec<_>>());
+
+        std::fs::remove_file(dir.path().join("sent.log")).unwrap();
+        send(&config, Mode::Args(args(true))).unwrap();
+        assert!(!dir.path().join("sent.log").exists());
+    }
+
+    #[test]
+    fn test_message_id_host() {
+        let dir = TempDir::new("message-id-host");
+        let config = test_config(&dir, "message_id_host: mail.example.org\n");
+        message_id::gen_secret_key(&config.secret_key, false).unwrap();
+        Database::open(&config.database_path).unwrap()
+            .add_user("bob", "bob@example.com", "UTC", "18:00").unwrap();
+
+        let output = dir.path().join("out.eml");
+        send(&config, Mode::Args(SendArgs {
+            username: Some("bob".to_owned()),
+            all_users: false,
+            email_override: None,
+            date_override: Some("2001-07-08".to_owned()),
+            timezone_override: None,
+            dry_run: true,
+            output: Some(output.clone()),
+        })).unwrap();
+
+        let email = std::fs::read_to_string(output).unwrap();
+        let msgid = email.lines()
+            .find_map(|line| line.strip_prefix("Message-ID: "))
+            .unwrap();
+        assert!(msgid.starts_with("<daylog.1.") && msgid.ends_with("@mail.example.org>"), "{}", msgid);
+
+        assert!(plain_message_id(&config, "ack").unwrap().ends_with("@mail.example.org"));
+    }
+
+    #[test]
+    fn test_header_time() {
+        let dir = TempDir::new("header-time");
+        let now = NaiveDate::from_ymd_opt(2001, 7, 8).unwrap().and_hms_opt(18, 0, 0).unwrap().and_utc();
+        let tokyo = UserTimezone::parse("Asia/Tokyo").unwrap();
+
+        let config = test_config(&dir, "");
+        assert_eq!("Sun, 8 Jul 2001 18:00:00 +0000", header_time(&config, &tokyo, now).to_rfc2822());
+
+        let config = test_config(&dir, "local_date_header: true\n");
+        assert_eq!("Mon, 9 Jul 2001 03:00:00 +0900", header_time(&config, &tokyo, now).to_rfc2822());
+        let offset = UserTimezone::parse("-05:30").unwrap();
+        assert_eq!("Sun, 8 Jul 2001 12:30:00 -0530", header_time(&config, &offset, now).to_rfc2822());
+    }
 }
@@ -0,0 +1,74 @@
+use chrono::NaiveDate;
+use crate::{StatsArgs, todays_date};
+use crate::config::Config;
+use crate::db::Database;
+use crate::user::User;
+use serde::Serialize;
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct UserStats {
+    username: String,
+    entries: u64,
+    first_entry: Option<NaiveDate>,
+    last_entry: Option<NaiveDate>,
+    current_streak: u64,
+}
+
+pub fn stats(config: &Config, args: StatsArgs) -> anyhow::Result<()> {
+    let db = Database::open_read_only(&config.database_path)?;
+
+    let users = match args.username {
+        Some(username) => vec![db.get_user(&username, config.default_email_time)?],
+        None => db.get_all_users(config.default_email_time)?.iter().cloned().collect(),
+    };
+    let stats = users.iter()
+        .map(|user| user_stats(&db, user))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    if stats.is_empty() {
+        println!("no users configured");
+        return Ok(());
+    }
+
+    let fmt = |date: Option<NaiveDate>| match date {
+        Some(date) => date.format("%Y-%m-%d").to_string(),
+        None => "never".to_owned(),
+    };
+    for stats in stats {
+        println!("{}:", stats.username);
+        println!("\tentries: {}", stats.entries);
+        println!("\tfirst entry: {}", fmt(stats.first_entry));
+        println!("\tlast entry: {}", fmt(stats.last_entry));
+        println!("\tcurrent streak: {} days", stats.current_streak);
+    }
+
+    Ok(())
+}
+
+fn user_stats(db: &Database, user: &User) -> anyhow::Result<UserStats> {
+    Ok(UserStats {
+        username: user.username.clone(),
+        entries: db.count_entries(&user.username)?,
+        first_entry: db.first_entry_date(&user.username)?,
+        last_entry: db.last_entry_date(&user.username)?,
+        current_streak: current_streak(db, &user.username, todays_date(&user.timezone))?,
+    })
+}
+
+/// Number of consecutive days up to today that the user has written entries for. Today's entry
+/// probably hasn't been written yet, so a missing one doesn't break the streak.
+pub fn current_streak(db: &Database, username: &str, today: NaiveDate) -> anyhow::Result<u64> {
+    let streak = db.streak(username, today)?;
+    if streak > 0 {
+        return Ok(streak);
+    }
+    match today.pred_opt() {
+        Some(yesterday) => db.streak(username, yesterday),
+        None => Ok(0),
+    }
+}
@@ -0,0 +1,140 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use crate::{StatusArgs, todays_date};
+use crate::config::Config;
+use crate::db::Database;
+use crate::time::DaylogTime;
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+struct UserStatus {
+    username: String,
+    timezone: String,
+    #[serde(flatten)]
+    next: NextEmail,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum NextEmail {
+    /// Due within the next day.
+    Scheduled {
+        time: DateTime<Utc>,
+        /// The time in the user's timezone, for the human-readable output.
+        #[serde(skip)]
+        local: String,
+    },
+    Disabled,
+    /// Not due within the next day, because of the user's days or frequency.
+    Unscheduled {
+        /// In the user's timezone. None if they'll never get one.
+        next_date: Option<NaiveDate>,
+        days: String,
+        frequency: String,
+    },
+}
+
+pub fn status(config: &Config, args: StatusArgs) -> anyhow::Result<()> {
+    let db = Database::open_read_only(&config.database_path)?;
+    let users = db.get_all_users(config.default_email_time)?
+        .with_ambiguous_time(config.ambiguous_time);
+    let (today, now) = DaylogTime::now();
+
+    let schedule = users.schedule(today, now);
+    let mut statuses = vec![];
+
+    for (sleep_time, users) in &schedule {
+        let utc = Utc.from_utc_datetime(&sleep_time.on_date(today));
+        for user in users {
+            let local = utc.with_timezone(&user.timezone);
+            statuses.push(UserStatus {
+                username: user.username.clone(),
+                timezone: user.timezone.to_string(),
+                next: NextEmail::Scheduled {
+                    time: utc,
+                    local: local.format("%Y-%m-%d %H:%M %Z").to_string(),
+                },
+            });
+        }
+    }
+
+    for user in users.iter() {
+        let next = if !user.enabled {
+            NextEmail::Disabled
+        } else if !schedule.values().flatten().any(|u| u.username == user.username) {
+            NextEmail::Unscheduled {
+                next_date: user.next_email_date(todays_date(&user.timezone)),
+                days: user.days.to_string(),
+                frequency: user.frequency.to_string(),
+            }
+        } else {
+            continue;
+        };
+        statuses.push(UserStatus {
+            username: user.username.clone(),
+            timezone: user.timezone.to_string(),
+            next,
+        });
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+        return Ok(());
+    }
+
+    if statuses.is_empty() {
+        println!("no users configured");
+        return Ok(());
+    }
+
+    for status in statuses {
+        match status.next {
+            NextEmail::Scheduled { time, local } => println!("{} -> {} UTC ({} {})",
+                status.username, time.format("%Y-%m-%d %H:%M"), local, status.timezone),
+            NextEmail::Disabled => println!("{} -> disabled", status.username),
+            NextEmail::Unscheduled { next_date, days, frequency } => {
+                let next = match next_date {
+                    Some(date) => format!("next on {}", date.format("%Y-%m-%d")),
+                    None => "never".to_owned(),
+                };
+                println!("{} -> {} (days: {}, frequency: {})",
+                    status.username, next, days, frequency);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let statuses = vec![
+            UserStatus {
+                username: "alice".to_owned(),
+                timezone: "America/Los_Angeles".to_owned(),
+                next: NextEmail::Scheduled {
+                    time: Utc.with_ymd_and_hms(2021, 7, 16, 1, 0, 0).unwrap(),
+                    local: "2021-07-15 18:00 PDT".to_owned(),
+                },
+            },
+            UserStatus {
+                username: "bob".to_owned(),
+                timezone: "Asia/Tokyo".to_owned(),
+                next: NextEmail::Unscheduled {
+                    next_date: NaiveDate::from_ymd_opt(2021, 7, 18),
+                    days: "-----SS".to_owned(),
+                    frequency: "daily".to_owned(),
+                },
+            },
+        ];
+        assert_eq!(concat!(
+                r#"[{"username":"alice","timezone":"America/Los_Angeles","status":"scheduled","#,
+                r#""time":"2021-07-16T01:00:00Z"},"#,
+                r#"{"username":"bob","timezone":"Asia/Tokyo","status":"unscheduled","#,
+                r#""next_date":"2021-07-18","days":"-----SS","frequency":"daily"}]"#),
+            serde_json::to_string(&statuses).unwrap());
+    }
+}
@@ -0,0 +1,41 @@
+use anyhow::Context;
+use crate::mail::{Mail, MailProcessAction, MailSource, RunStats};
+use std::io::Read;
+
+/// A single raw message, such as one piped in by a mail delivery agent.
+pub struct StdinMail {
+    raw: Vec<u8>,
+}
+
+impl StdinMail {
+    pub fn read_from(mut r: impl Read) -> anyhow::Result<Self> {
+        let mut raw = vec![];
+        r.read_to_end(&mut raw)
+            .context("failed to read message")?;
+        Ok(Self { raw })
+    }
+}
+
+impl MailSource for StdinMail {
+    fn read(&mut self, mut handler: Box<dyn FnMut(Mail) -> MailProcessAction + '_>)
+        -> anyhow::Result<RunStats>
+    {
+        let parsed = mailparse::parse_mail(&self.raw)
+            .context("failed to parse mail message")?;
+        let mail = Mail::parse(parsed)
+            .context("failed to parse mail message (inner)")?;
+
+        let mut stats = RunStats {
+            num_processed: 1,
+            .. RunStats::default()
+        };
+        // There's nowhere to put the message back, so the caller has to look at the stats to know
+        // whether it was handled.
+        match handler(mail) {
+            MailProcessAction::Remove => stats.num_removed += 1,
+            MailProcessAction::Keep => stats.num_kept += 1,
+            MailProcessAction::LeaveUnread => stats.num_left_unread += 1,
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,185 @@
+//! Helpers shared by unit tests.
+
+use crate::config::Config;
+use crate::mail::{Mail, MailProcessAction, MailSource, RunStats};
+use crate::message_id::{gen_message_id, gen_secret_key, read_secret_key, SECRET_KEY_LEN};
+use crate::time::DaylogTime;
+use chrono::NaiveDate;
+use crate::user::User;
+use std::path::{Path, PathBuf};
+
+/// A temporary directory that gets removed when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("daylog-test-{}-{}-{}",
+            name, std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An enabled user who gets a daily email at midnight UTC. Tests change whatever fields they
+/// care about.
+pub fn user(name: &str) -> User {
+    User {
+        id: 0,
+        username: name.to_owned(),
+        email: format!("{}@example.com", name),
+        cc: vec![],
+        timezone: crate::timezone::UserTimezone::parse("UTC").unwrap(),
+        email_time_local: DaylogTime::zero(),
+        enabled: true,
+        days: crate::weekdays::Weekdays::all(),
+        frequency: crate::frequency::Frequency::Daily,
+        pgp_key: None,
+    }
+}
+
+/// Write a minimal config file into the directory, with any extra YAML appended, and load it.
+/// Relative paths in it resolve to files in the directory.
+pub fn test_config(dir: &TempDir, extra: &str) -> Config {
+    let config_path = dir.path().join("config.yaml");
+    std::fs::write(&config_path, format!("\
+database: daylog.db
+secret_key: key
+return_addr: daylog@example.com
+incoming_mail:
+    maildir:
+        path: maildir
+{}", extra)).unwrap();
+    Config::try_from_path(config_path.as_os_str()).unwrap()
+}
+
+/// Generate the secret key file for a config from `test_config`, and return the key.
+pub fn secret_key(config: &Config) -> [u8; SECRET_KEY_LEN] {
+    gen_secret_key(&config.secret_key, false).unwrap();
+    read_secret_key(&config.secret_key).unwrap()
+}
+
+/// A reply to the daily email sent to the user for the given date, as parsed from a message with
+/// the given Message-ID and plain text body.
+pub fn reply(key: [u8; SECRET_KEY_LEN], username: &str, date: NaiveDate, msgid: &str, body: &str)
+    -> Mail
+{
+    Mail {
+        msgid: msgid.to_owned(),
+        reply_to: vec![format!("{}@daylog.example.com",
+            gen_message_id(username, date, key).unwrap())],
+        subject: format!("Re: Daylog for {}", date),
+        body: body.to_owned(),
+        attachments: vec![],
+        from_html: false,
+    }
+}
+
+/// A MailSource that serves messages from memory. Messages left unread are served again on the
+/// next read, like they would be from a maildir.
+#[derive(Default)]
+pub struct VecMailSource {
+    pub mails: Vec<Mail>,
+
+    /// Message ID and the action taken for each message handled, in order.
+    pub actions: Vec<(String, MailProcessAction)>,
+
+    /// How many messages that couldn't be parsed to report on the next read, as if they were there.
+    pub unparseable: u64,
+}
+
+impl VecMailSource {
+    pub fn new(mails: Vec<Mail>) -> Self {
+        Self { mails, actions: vec![], unparseable: 0 }
+    }
+}
+
+impl MailSource for VecMailSource {
+    fn read(&mut self, mut handler: Box<dyn FnMut(Mail) -> MailProcessAction + '_>)
+        -> anyhow::Result<RunStats>
+    {
+        let mut stats = RunStats {
+            num_failed: std::mem::take(&mut self.unparseable),
+            .. RunStats::default()
+        };
+        let mut unread = vec![];
+        for mail in std::mem::take(&mut self.mails) {
+            stats.num_processed += 1;
+            let action = handler(mail.clone());
+            match action {
+                MailProcessAction::Remove => stats.num_removed += 1,
+                MailProcessAction::Keep => stats.num_kept += 1,
+                MailProcessAction::LeaveUnread => stats.num_left_unread += 1,
+            }
+            self.actions.push((mail.msgid.clone(), action));
+            if action == MailProcessAction::LeaveUnread {
+                unread.push(mail);
+            }
+        }
+        self.mails = unread;
+        Ok(stats)
+    }
+}
+
+/// A gpg home directory with a new key pair in it, for encrypting to and decrypting with. Tests
+/// using it need gpg installed, and fail without it.
+pub struct TestKey {
+    dir: TempDir,
+    pub public_key: String,
+}
+
+impl TestKey {
+    pub fn new() -> Self {
+        if let Err(e) = std::process::Command::new("gpg").arg("--version").output() {
+            panic!("failed to run gpg, which the PGP tests need: {}", e);
+        }
+        let dir = TempDir::new("gpg-key");
+        let gpg = |args: &[&str]| {
+            let output = std::process::Command::new("gpg").arg("--homedir").arg(dir.path())
+                .args(["--batch", "--quiet", "--no-tty"])
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            String::from_utf8(output.stdout).unwrap()
+        };
+        gpg(&["--passphrase", "", "--quick-gen-key", "Test <test@example.com>", "default",
+            "default", "never"]);
+        let public_key = gpg(&["--armor", "--export"]);
+        Self { dir, public_key }
+    }
+
+    pub fn decrypt(&self, armored: &str) -> String {
+        let path = self.dir.path().join("message.asc");
+        std::fs::write(&path, armored).unwrap();
+        let output = std::process::Command::new("gpg").arg("--homedir").arg(self.dir.path())
+            .args(["--batch", "--quiet", "--no-tty", "--decrypt"])
+            .arg(&path)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    }
+}
+
+impl Drop for TestKey {
+    fn drop(&mut self) {
+        // Decrypting starts an agent for the directory.
+        let _ = std::process::Command::new("gpgconf").arg("--homedir").arg(self.dir.path())
+            .args(["--kill", "gpg-agent"])
+            .output();
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::{anyhow, bail};
 use chrono::prelude::*;
-use chrono::Duration;
+use chrono::{Duration, LocalResult};
+use crate::config::AmbiguousTime;
 use std::cmp::Ordering;
 
 /// Daylog operates in UTC, with minute resolution.
@@ -11,9 +12,10 @@ pub struct DaylogTime {
 }
 
 impl DaylogTime {
-    pub fn now() -> (Date<Utc>, Self) {
+    /// The current UTC date and time.
+    pub fn now() -> (NaiveDate, Self) {
         let now = Utc::now();
-        (now.date(), Self::from(now.time()))
+        (now.date_naive(), Self::from(now.time()))
     }
 
     #[cfg(test)]
@@ -43,11 +45,11 @@ impl DaylogTime {
     }
 
     pub fn as_naivetime(self) -> NaiveTime {
-        NaiveTime::from_hms(
+        NaiveTime::from_hms_opt(
             u32::from(self.hour),
             u32::from(self.minute),
             0,
-        )
+        ).unwrap() // can't panic; hour and minute are always in range
     }
 
     fn from_naivetime(t: NaiveTime) -> Self {
@@ -69,13 +71,13 @@ impl DaylogTime {
     pub fn parse(s: &str) -> anyhow::Result<Self> {
         let mut parts = s.splitn(2, ':');
         let hour: u8 = parts.next().ok_or_else(|| anyhow!("couldn't find hour"))?
-            .parse().map_err(|e| anyhow!(format!("bad hour: {}", e)))?;
+            .parse().map_err(|e| anyhow!("bad hour: {}", e))?;
         if hour > 23 {
             bail!("hour is out of range");
         }
 
         let minute: u8 = parts.next().ok_or_else(|| anyhow!("couldn't find minute"))?
-            .parse().map_err(|e| anyhow!(format!("bad minute: {}", e)))?;
+            .parse().map_err(|e| anyhow!("bad minute: {}", e))?;
         if minute > 59 {
             bail!("minute is out of range");
         }
@@ -83,27 +85,40 @@ impl DaylogTime {
         Ok(Self { hour, minute })
     }
 
-    pub fn apply_timezone<Tz: TimeZone>(self, utc_now: DateTime<Utc>, tz: &Tz) -> SleepTime {
+    /// Work out when this local time in the given timezone next comes around, in UTC. If the
+    /// local time happens twice that day, `ambiguous` picks which one.
+    pub fn apply_timezone<Tz: TimeZone>(
+        self,
+        utc_now: DateTime<Utc>,
+        tz: &Tz,
+        ambiguous: AmbiguousTime,
+    ) -> SleepTime {
+        let resolve = |result: LocalResult<DateTime<Tz>>| match ambiguous {
+            AmbiguousTime::Earliest => result.earliest(),
+            AmbiguousTime::Latest => result.latest(),
+        };
         let adj = |date: NaiveDate| -> DateTime<Tz> {
             let local = date.and_time(self.as_naivetime());
-            match tz.from_local_datetime(&local) {
-                chrono::LocalResult::None => {
+            resolve(tz.from_local_datetime(&local))
+                .or_else(|| {
                     // caller asked for something like 2:01am during a DST transition
                     // pick 1 hour later and assume it will work...
-                    let later = local + Duration::hours(1);
-                    tz.from_local_datetime(&later).unwrap()
-                }
-                other => other.latest().unwrap(),
-            }
+                    resolve(tz.from_local_datetime(&(local + Duration::hours(1))))
+                })
+                .unwrap_or_else(|| {
+                    // ...unless the gap is longer than that, which is vanishingly rare. Rather
+                    // than panic, treat the time as UTC.
+                    tz.from_utc_datetime(&local)
+                })
         };
 
-        let local_today = adj(utc_now.naive_utc().date());
+        let local_today = adj(utc_now.date_naive());
 
         if local_today.naive_utc().time() >= utc_now.time() {
             SleepTime::Today(Self::from_naivetime(
                 local_today.naive_utc().time()))
         } else {
-            let tomorrow = utc_now.naive_utc().date().succ();
+            let tomorrow = utc_now.date_naive().succ_opt().unwrap();
             let local_tomorrow = adj(tomorrow);
             SleepTime::Tomorrow(Self::from_naivetime(
                 local_tomorrow.naive_utc().time()))
@@ -125,6 +140,20 @@ impl std::fmt::Display for DaylogTime {
     }
 }
 
+/// Serialized as "HH:MM", like in the database.
+impl serde::Serialize for DaylogTime {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for DaylogTime {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::parse(&s).map_err(|e| serde::de::Error::custom(format!("invalid time {:?}: {}", s, e)))
+    }
+}
+
 impl Ord for DaylogTime {
     fn cmp(&self, other: &DaylogTime) -> Ordering {
         match self.hour.cmp(&other.hour) {
@@ -149,11 +178,19 @@ pub enum SleepTime {
 }
 
 impl SleepTime {
+    /// Get the UTC date and time this refers to, given the current UTC date.
+    pub fn on_date(self, today: NaiveDate) -> NaiveDateTime {
+        match self {
+            SleepTime::Today(time) => today.and_time(time.as_naivetime()),
+            SleepTime::Tomorrow(time) => today.succ_opt().unwrap().and_time(time.as_naivetime()),
+        }
+    }
+
     pub fn duration_from(self, earlier_time: NaiveTime) -> Duration {
         match self {
             SleepTime::Tomorrow(time) => {
                 Duration::days(1)
-                    - (earlier_time - NaiveTime::from_hms(0, 0, 0))
+                    - (earlier_time - NaiveTime::MIN)
                     + time.duration_since_start_of_day()
             }
             SleepTime::Today(time) => {
@@ -201,15 +238,26 @@ mod test {
         assert_eq!(
             Duration::hours(47) + Duration::minutes(59),
             SleepTime::Tomorrow(DaylogTime::new(23, 59))
-                .duration_from(NaiveTime::from_hms(0,0,0)));
+                .duration_from(NaiveTime::from_hms_opt(0,0,0).unwrap()));
         assert_eq!(
             Duration::seconds(-56),
             SleepTime::Today(DaylogTime::new(12, 34))
-                .duration_from(NaiveTime::from_hms(12, 34, 56)));
+                .duration_from(NaiveTime::from_hms_opt(12, 34, 56).unwrap()));
         assert_eq!(
             Duration::seconds(1),
             SleepTime::Tomorrow(DaylogTime::zero())
-                .duration_from(NaiveTime::from_hms(23, 59, 59)));
+                .duration_from(NaiveTime::from_hms_opt(23, 59, 59).unwrap()));
+    }
+
+    #[test]
+    fn test_on_date() {
+        let today = NaiveDate::from_ymd_opt(2020, 12, 31).unwrap();
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2020, 12, 31).unwrap().and_hms_opt(12, 34, 0).unwrap(),
+            SleepTime::Today(DaylogTime::new(12, 34)).on_date(today));
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().and_hms_opt(0, 5, 0).unwrap(),
+            SleepTime::Tomorrow(DaylogTime::new(0, 5)).on_date(today));
     }
 
     #[test]
@@ -233,26 +281,44 @@ mod test {
 
         // Let's say it's 2020-03-07, before the time change.
         // Assert that 6PM email will be sent at 2AM UTC.
-        let mut utc_now = Utc.ymd(2020, 3, 7).and_hms(0, 0, 0);
-        let x1 = email_time.apply_timezone(utc_now, &tz);
+        let mut utc_now = Utc.with_ymd_and_hms(2020, 3, 7, 0, 0, 0).unwrap();
+        let x1 = email_time.apply_timezone(utc_now, &tz, AmbiguousTime::Latest);
         assert_eq!(x1, SleepTime::Today(DaylogTime { hour: 2, minute: 0 }));
 
         // Let's advance just past that time.
         // Assert that the email gets sent tomorrow, since it's too late today, and that the time
         // changes because then it'll be after the time change to PDT.
-        utc_now = Utc.ymd(2020, 3, 7).and_hms(2, 1, 0);
-        let x2 = email_time.apply_timezone(utc_now, &tz);
+        utc_now = Utc.with_ymd_and_hms(2020, 3, 7, 2, 1, 0).unwrap();
+        let x2 = email_time.apply_timezone(utc_now, &tz, AmbiguousTime::Latest);
         assert_eq!(x2, SleepTime::Tomorrow(DaylogTime { hour: 1, minute: 0 }));
 
         // Now it's 10:01 AM UTC, right after PST turns to PDT.
         // Assert that the PDT time tomorrow is still picked.
-        utc_now = Utc.ymd(2020, 3, 7).and_hms(10, 1, 0);
-        let x3 = email_time.apply_timezone(utc_now, &tz);
+        utc_now = Utc.with_ymd_and_hms(2020, 3, 7, 10, 1, 0).unwrap();
+        let x3 = email_time.apply_timezone(utc_now, &tz, AmbiguousTime::Latest);
         assert_eq!(x3, SleepTime::Tomorrow(DaylogTime { hour: 1, minute: 0 }));
 
         // Now it's the next day. Assert that it's sent today, at the right time.
-        utc_now = Utc.ymd(2020, 3, 8).and_hms(0, 0, 0);
-        let x4 = email_time.apply_timezone(utc_now, &tz);
+        utc_now = Utc.with_ymd_and_hms(2020, 3, 8, 0, 0, 0).unwrap();
+        let x4 = email_time.apply_timezone(utc_now, &tz, AmbiguousTime::Latest);
         assert_eq!(x4, SleepTime::Today(DaylogTime { hour: 1, minute: 0 }));
     }
+
+    #[test]
+    fn test_fall_back() {
+        // 2020-11-01 is the day that PDT becomes PST at 2:00 AM local time, so 1:30 AM happens
+        // twice: at 08:30 UTC in PDT, and at 09:30 UTC in PST.
+        let tz = chrono_tz::America::Los_Angeles;
+        let email_time = DaylogTime::new(1, 30);
+        let utc_now = Utc.with_ymd_and_hms(2020, 11, 1, 0, 0, 0).unwrap();
+        assert_eq!(SleepTime::Today(DaylogTime::new(8, 30)),
+            email_time.apply_timezone(utc_now, &tz, AmbiguousTime::Earliest));
+        assert_eq!(SleepTime::Today(DaylogTime::new(9, 30)),
+            email_time.apply_timezone(utc_now, &tz, AmbiguousTime::Latest));
+
+        // Just after the first occurrence, the second one is still to come today with Latest.
+        let utc_now = Utc.with_ymd_and_hms(2020, 11, 1, 8, 31, 0).unwrap();
+        assert_eq!(SleepTime::Today(DaylogTime::new(9, 30)),
+            email_time.apply_timezone(utc_now, &tz, AmbiguousTime::Latest));
+    }
 }
@@ -0,0 +1,156 @@
+use anyhow::{anyhow, bail};
+use chrono::{FixedOffset, LocalResult, NaiveDate, NaiveDateTime, Offset, TimeZone};
+use std::fmt;
+
+/// A user's timezone: either a named IANA zone like "America/Los_Angeles", or a fixed offset from
+/// UTC like "+05:30". Named zones are preferred, because fixed offsets don't follow daylight
+/// savings time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserTimezone {
+    Named(chrono_tz::Tz),
+    Fixed(FixedOffset),
+}
+
+impl UserTimezone {
+    /// Parse an IANA timezone name, or failing that, a fixed offset in one of the forms "+05:30",
+    /// "-0800", "+2", or any of those prefixed by "UTC" or "GMT".
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match s.parse::<chrono_tz::Tz>() {
+            Ok(tz) => Ok(UserTimezone::Named(tz)),
+            Err(named_err) => parse_fixed_offset(s)
+                .map(UserTimezone::Fixed)
+                .map_err(|e| anyhow!("{}; and not a valid UTC offset: {}", named_err, e)),
+        }
+    }
+}
+
+fn parse_fixed_offset(s: &str) -> anyhow::Result<FixedOffset> {
+    let s = s.trim();
+    let s = s.strip_prefix("UTC")
+        .or_else(|| s.strip_prefix("GMT"))
+        .unwrap_or(s);
+
+    let (sign, rest) = match s.as_bytes().first() {
+        Some(b'+') => (1, &s[1..]),
+        Some(b'-') => (-1, &s[1..]),
+        _ => bail!("offset must start with '+' or '-'"),
+    };
+    if !rest.is_ascii() {
+        bail!("offset must only have digits and ':'");
+    }
+
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((h, m)) => (h, m),
+        None if rest.len() > 2 => rest.split_at(rest.len() - 2),
+        None => (rest, "0"),
+    };
+    let hours: i32 = hours.parse().map_err(|e| anyhow!("bad hours: {}", e))?;
+    let minutes: i32 = minutes.parse().map_err(|e| anyhow!("bad minutes: {}", e))?;
+    if hours > 23 || minutes > 59 {
+        bail!("offset is out of range");
+    }
+
+    Ok(FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).unwrap())
+}
+
+impl fmt::Display for UserTimezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserTimezone::Named(tz) => write!(f, "{}", tz.name()),
+            UserTimezone::Fixed(offset) => write!(f, "UTC{}", offset),
+        }
+    }
+}
+
+/// The offset type for `UserTimezone`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserOffset {
+    Named(<chrono_tz::Tz as TimeZone>::Offset),
+    Fixed(FixedOffset),
+}
+
+impl Offset for UserOffset {
+    fn fix(&self) -> FixedOffset {
+        match self {
+            UserOffset::Named(offset) => offset.fix(),
+            UserOffset::Fixed(offset) => *offset,
+        }
+    }
+}
+
+impl fmt::Display for UserOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserOffset::Named(offset) => offset.fmt(f),
+            UserOffset::Fixed(offset) => offset.fmt(f),
+        }
+    }
+}
+
+impl TimeZone for UserTimezone {
+    type Offset = UserOffset;
+
+    fn from_offset(offset: &UserOffset) -> Self {
+        match offset {
+            UserOffset::Named(offset) => UserTimezone::Named(chrono_tz::Tz::from_offset(offset)),
+            UserOffset::Fixed(offset) => UserTimezone::Fixed(*offset),
+        }
+    }
+
+    fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<UserOffset> {
+        match self {
+            UserTimezone::Named(tz) => tz.offset_from_local_date(local).map(UserOffset::Named),
+            UserTimezone::Fixed(tz) => tz.offset_from_local_date(local).map(UserOffset::Fixed),
+        }
+    }
+
+    fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<UserOffset> {
+        match self {
+            UserTimezone::Named(tz) => tz.offset_from_local_datetime(local).map(UserOffset::Named),
+            UserTimezone::Fixed(tz) => tz.offset_from_local_datetime(local).map(UserOffset::Fixed),
+        }
+    }
+
+    fn offset_from_utc_date(&self, utc: &NaiveDate) -> UserOffset {
+        match self {
+            UserTimezone::Named(tz) => UserOffset::Named(tz.offset_from_utc_date(utc)),
+            UserTimezone::Fixed(tz) => UserOffset::Fixed(tz.offset_from_utc_date(utc)),
+        }
+    }
+
+    fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> UserOffset {
+        match self {
+            UserTimezone::Named(tz) => UserOffset::Named(tz.offset_from_utc_datetime(utc)),
+            UserTimezone::Fixed(tz) => UserOffset::Fixed(tz.offset_from_utc_datetime(utc)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(UserTimezone::Named(chrono_tz::America::Chicago),
+            UserTimezone::parse("America/Chicago").unwrap());
+        assert_eq!(UserTimezone::Named(chrono_tz::UTC), UserTimezone::parse("UTC").unwrap());
+
+        let fixed = |secs| UserTimezone::Fixed(FixedOffset::east_opt(secs).unwrap());
+        assert_eq!(fixed(5 * 3600 + 30 * 60), UserTimezone::parse("+05:30").unwrap());
+        assert_eq!(fixed(-8 * 3600), UserTimezone::parse("-0800").unwrap());
+        assert_eq!(fixed(2 * 3600), UserTimezone::parse("UTC+2").unwrap());
+        assert_eq!(fixed(-(9 * 3600 + 30 * 60)), UserTimezone::parse("GMT-9:30").unwrap());
+
+        assert!(UserTimezone::parse("Mars/Olympus_Mons").is_err());
+        assert!(UserTimezone::parse("+25:00").is_err());
+        assert!(UserTimezone::parse("UTC+").is_err());
+        assert!(UserTimezone::parse("+1€").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!("America/Chicago", UserTimezone::parse("America/Chicago").unwrap().to_string());
+        assert_eq!("UTC+05:30", UserTimezone::parse("+0530").unwrap().to_string());
+    }
+}
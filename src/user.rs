@@ -1,65 +1,384 @@
 use anyhow::{anyhow, Context};
-use chrono::{Date, Utc};
+use chrono::{Datelike, NaiveDate, TimeZone, Utc};
+use crate::config::AmbiguousTime;
 use crate::db::UserRaw;
+use crate::frequency::Frequency;
 use crate::time::{DaylogTime, SleepTime};
+use crate::timezone::UserTimezone;
+use crate::weekdays::Weekdays;
 use std::collections::BTreeMap;
-use std::convert::TryFrom;
 
 #[derive(Debug, Clone)]
 pub struct User {
+    #[allow(dead_code)]
     pub id: i64,
     pub username: String,
     pub email: String,
-    pub timezone: chrono_tz::Tz,
+    /// Other addresses the daily email is copied to.
+    pub cc: Vec<String>,
+    pub timezone: UserTimezone,
     pub email_time_local: DaylogTime,
+    /// Whether to send this user their daily email.
+    pub enabled: bool,
+    /// Days of the week, in the user's timezone, to send their daily email on.
+    pub days: Weekdays,
+    /// How often to send the email, on top of `days`.
+    pub frequency: Frequency,
+    /// ASCII-armored PGP public key to encrypt the user's entries to before storing them.
+    pub pgp_key: Option<String>,
 }
 
-impl TryFrom<UserRaw> for User {
-    type Error = anyhow::Error;
-    fn try_from(raw: UserRaw) -> Result<Self, Self::Error> {
+impl User {
+    /// Convert a row from the database. Users without their own email time get the given default
+    /// (`default_email_time` from the config), if any.
+    pub fn from_raw(raw: UserRaw, default_email_time: Option<DaylogTime>) -> anyhow::Result<Self> {
+        crate::address::validate(&raw.email)
+            .with_context(|| format!("invalid email for user {:?}", raw.username))?;
+        let cc = if raw.cc.trim().is_empty() {
+            vec![]
+        } else {
+            raw.cc.split(',')
+                .map(|addr| {
+                    let addr = addr.trim();
+                    crate::address::validate(addr).with_context(||
+                        format!("invalid cc address for user {:?}", raw.username))?;
+                    Ok(addr.to_owned())
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+        };
         Ok(User {
             id: raw.id.ok_or_else(|| anyhow!("missing ID for user {:?}",raw.username))?,
-            timezone: raw.timezone.as_str().parse::<chrono_tz::Tz>()
-                .map_err(|e| {
-                    // can't use with_context because of type bounds
-                    anyhow!("failed to parse timezone for user {:?}: {}", raw.username, e)
-                })?,
-            email_time_local: DaylogTime::parse(&raw.email_time_local)
-                .with_context(|| format!("failed to parse time for user {:?}", raw.username))?,
+            cc,
+            timezone: UserTimezone::parse(&raw.timezone)
+                .with_context(|| format!("failed to parse timezone for user {:?}", raw.username))?,
+            email_time_local: match raw.email_time_local.as_deref().map(str::trim) {
+                Some(time) if !time.is_empty() => DaylogTime::parse(time)
+                    .with_context(|| format!("failed to parse time for user {:?}", raw.username))?,
+                _ => default_email_time.ok_or_else(|| anyhow!(
+                    "user {:?} has no email time set, and there's no default_email_time configured",
+                    raw.username))?,
+            },
+            days: Weekdays::parse(&raw.days)
+                .with_context(|| format!("failed to parse days for user {:?}", raw.username))?,
+            frequency: Frequency::parse(&raw.frequency)
+                .with_context(|| format!("failed to parse frequency for user {:?}", raw.username))?,
+            pgp_key: if raw.pgp_key.trim().is_empty() {
+                None
+            } else {
+                crate::pgp::validate_public_key(&raw.pgp_key)
+                    .with_context(|| format!("invalid PGP key for user {:?}", raw.username))?;
+                Some(raw.pgp_key)
+            },
             email: raw.email,
             username: raw.username,
+            enabled: raw.enabled,
         })
     }
+
+    /// Whether the user gets an email on the given date, in their timezone.
+    pub fn emails_on(&self, local_date: NaiveDate) -> bool {
+        self.days.contains(local_date.weekday()) && self.frequency.includes(local_date)
+    }
+
+    /// The first date, in their timezone, from the given one on, that the user gets an email. None
+    /// if there isn't one within a year, which can happen with some combinations of `days` and
+    /// `frequency`.
+    pub fn next_email_date(&self, local_date: NaiveDate) -> Option<NaiveDate> {
+        local_date.iter_days()
+            .take(366)
+            .find(|&date| self.emails_on(date))
+    }
+}
+
+/// Result of `Users::next_from_time`.
+#[derive(Debug)]
+pub enum NextUsers {
+    /// These users should be emailed at the given time.
+    Scheduled(SleepTime, Vec<User>),
+
+    /// There are users, but none of them are currently scheduled to be emailed.
+    NoneScheduled,
+
+    /// There aren't any users at all.
+    NoUsers,
 }
 
 pub struct Users {
     vec: Vec<User>,
+    ambiguous_time: AmbiguousTime,
 }
 
 impl Users {
     pub fn new(users: Vec<User>) -> Self {
         Self {
             vec: users,
+            ambiguous_time: AmbiguousTime::default(),
         }
     }
 
+    /// Set how users' email times are resolved when they happen twice in a day.
+    pub fn with_ambiguous_time(mut self, ambiguous_time: AmbiguousTime) -> Self {
+        self.ambiguous_time = ambiguous_time;
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &User> {
+        self.vec.iter()
+    }
+
+    #[cfg(test)]
+    pub fn usernames(&self) -> Vec<&str> {
+        self.vec.iter().map(|u| u.username.as_str()).collect()
+    }
+
     /// Given a date and time, return the set of users who should be emailed next, and the time to
     /// sleep to until then. This needs a date because users' times are specified in local timezone,
     /// and local times depend what day it is, because daylight savings time exists.
-    pub fn next_from_time(&self, date: Date<Utc>, time: DaylogTime) -> Option<(SleepTime, Vec<User>)> {
+    pub fn next_from_time(&self, date: NaiveDate, time: DaylogTime) -> NextUsers {
+        info!("getting users from DaylogTime {} on {}", time, date);
+        if self.vec.is_empty() {
+            return NextUsers::NoUsers;
+        }
+        match self.schedule(date, time).into_iter().next() {
+            Some((time, users)) => NextUsers::Scheduled(time, users),
+            None => NextUsers::NoneScheduled,
+        }
+    }
+
+    /// Like `next_from_time`, but returns every enabled user, grouped by the time they should next be
+    /// emailed, in order. Users whose next email time falls on a day (in their timezone) they don't
+    /// get emails on, because of their `days` or `frequency`, are left out; this gets called at
+    /// least once a day, so they're scheduled once the day comes that they do.
+    pub fn schedule(&self, date: NaiveDate, time: DaylogTime) -> BTreeMap<SleepTime, Vec<User>> {
         // Simple brute-force method: recalculate everyone's local time on every call.
         // This can probably be improved, because nobody's time can change more than once per day,
         // but this is fast enough for now.
 
-        info!("getting users from DaylogTime {} on {}", time, date);
         let mut by_time = BTreeMap::<SleepTime, Vec<User>>::new();
-        let now = date.and_time(time.as_naivetime()).unwrap(); // can't panic, it's UTC
+        let now = date.and_time(time.as_naivetime()).and_utc();
 
-        for user in &self.vec {
-            let sleep_time = user.email_time_local.apply_timezone(now, &user.timezone);
+        for user in self.vec.iter().filter(|user| user.enabled) {
+            let sleep_time = user.email_time_local.apply_timezone(now, &user.timezone,
+                self.ambiguous_time);
+            let local_date = Utc.from_utc_datetime(&sleep_time.on_date(date))
+                .with_timezone(&user.timezone)
+                .date_naive();
+            if !user.emails_on(local_date) {
+                debug!("skipping {} on {} (days: {}, frequency: {})",
+                    user.username, local_date, user.days, user.frequency);
+                continue;
+            }
             by_time.entry(sleep_time).or_default().push(user.to_owned());
         }
 
-        by_time.into_iter().next()
+        by_time
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn user(username: &str, timezone: &str, hour: u8, minute: u8) -> User {
+        User {
+            id: 0,
+            username: username.to_owned(),
+            email: format!("{}@example.com", username),
+            cc: vec![],
+            timezone: UserTimezone::parse(timezone).unwrap(),
+            email_time_local: DaylogTime::new(hour, minute),
+            enabled: true,
+            days: Weekdays::all(),
+            frequency: Frequency::Daily,
+            pgp_key: None,
+        }
+    }
+
+    fn scheduled(next: NextUsers) -> (SleepTime, Vec<User>) {
+        match next {
+            NextUsers::Scheduled(time, users) => (time, users),
+            other => panic!("expected scheduled users, not {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cc_validation() {
+        let raw = |email: &str, cc: &str| UserRaw {
+            id: Some(1),
+            username: "bob".to_owned(),
+            email: email.to_owned(),
+            timezone: "UTC".to_owned(),
+            email_time_local: Some("18:00".to_owned()),
+            cc: cc.to_owned(),
+            enabled: true,
+            days: String::new(),
+            frequency: String::new(),
+            pgp_key: String::new(),
+        };
+        assert!(User::from_raw(raw("bob@example.com", ""), None).unwrap().cc.is_empty());
+        assert_eq!(vec!["a@example.com", "b@example.com"],
+            User::from_raw(raw("bob@example.com", "a@example.com,b@example.com"), None).unwrap().cc);
+        assert!(User::from_raw(raw("", ""), None).is_err());
+        let err = User::from_raw(raw("bob.example.com", ""), None).unwrap_err();
+        assert_eq!("invalid email for user \"bob\": \
+            \"bob.example.com\" is not an email address: it has no '@'", format!("{:#}", err));
+        assert!(User::from_raw(raw("bob@example.com", "a@example.com b@example.com"), None).is_err());
+        assert!(User::from_raw(raw("bob@example.com", "a@example.com,,b@example.com"), None).is_err());
+        assert!(User::from_raw(raw("bob@example.com", "a@example.com, "), None).is_err());
+    }
+
+    #[test]
+    fn test_default_email_time() {
+        let raw = |time: Option<&str>| UserRaw {
+            id: Some(1),
+            username: "bob".to_owned(),
+            email: "bob@example.com".to_owned(),
+            timezone: "UTC".to_owned(),
+            email_time_local: time.map(str::to_owned),
+            cc: String::new(),
+            enabled: true,
+            days: String::new(),
+            frequency: String::new(),
+            pgp_key: String::new(),
+        };
+        let default = Some(DaylogTime::new(19, 30));
+        assert_eq!(DaylogTime::new(18, 0),
+            User::from_raw(raw(Some("18:00")), default).unwrap().email_time_local);
+        assert_eq!(DaylogTime::new(19, 30),
+            User::from_raw(raw(None), default).unwrap().email_time_local);
+        assert_eq!(DaylogTime::new(19, 30),
+            User::from_raw(raw(Some("")), default).unwrap().email_time_local);
+        let err = User::from_raw(raw(None), None).unwrap_err();
+        assert_eq!("user \"bob\" has no email time set, and there's no default_email_time configured",
+            err.to_string());
+        assert!(User::from_raw(raw(Some("25:00")), default).is_err());
+    }
+
+    #[test]
+    fn test_no_users() {
+        let users = Users::new(vec![]);
+        assert!(matches!(users.next_from_time(date(2020, 1, 15), DaylogTime::zero()),
+            NextUsers::NoUsers));
+    }
+
+    #[test]
+    fn test_disabled_users() {
+        let mut disabled = user("disabled", "UTC", 12, 0);
+        disabled.enabled = false;
+        let users = Users::new(vec![disabled.clone(), user("enabled", "UTC", 18, 0)]);
+        let (time, next) = scheduled(users.next_from_time(date(2020, 1, 15), DaylogTime::zero()));
+        assert_eq!(SleepTime::Today(DaylogTime::new(18, 0)), time);
+        assert_eq!(vec!["enabled"], next.iter().map(|u| u.username.as_str()).collect::<Vec<_>>());
+
+        let users = Users::new(vec![disabled]);
+        assert!(matches!(users.next_from_time(date(2020, 1, 15), DaylogTime::zero()),
+            NextUsers::NoneScheduled));
+    }
+
+    #[test]
+    fn test_fixed_offset() {
+        let users = Users::new(vec![
+            user("fixed", "+05:30", 18, 0),
+            user("named", "America/Chicago", 18, 0),
+        ]);
+        let date = date(2020, 1, 15);
+
+        // 18:00 at +05:30 is 12:30 UTC, which comes before 18:00 CST (00:00 UTC tomorrow).
+        let (time, next) = scheduled(users.next_from_time(date, DaylogTime::new(0, 1)));
+        assert_eq!(SleepTime::Today(DaylogTime::new(12, 30)), time);
+        assert_eq!(vec!["fixed"], next.iter().map(|u| u.username.as_str()).collect::<Vec<_>>());
+
+        // Once that's passed, the Chicago user is next, and then the fixed one again tomorrow.
+        let (time, next) = scheduled(users.next_from_time(date, DaylogTime::new(12, 31)));
+        assert_eq!(SleepTime::Tomorrow(DaylogTime::new(0, 0)), time);
+        assert_eq!(vec!["named"], next.iter().map(|u| u.username.as_str()).collect::<Vec<_>>());
+
+        let (time, next) = scheduled(
+            users.next_from_time(date.succ_opt().unwrap(), DaylogTime::new(0, 1)));
+        assert_eq!(SleepTime::Today(DaylogTime::new(12, 30)), time);
+        assert_eq!(vec!["fixed"], next.iter().map(|u| u.username.as_str()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_weekdays_only() {
+        // 18:00 in Los Angeles is 01:00 UTC the next day (in July), so the user's local weekday is behind
+        // the UTC one when they're emailed.
+        let mut weekdays = user("weekdays", "America/Los_Angeles", 18, 0);
+        weekdays.days = Weekdays::parse("Mon-Fri").unwrap();
+        let users = Users::new(vec![weekdays]);
+
+        // Friday 2021-07-16 12:00 UTC: the next email is 18:00 Friday local, 01:00 Saturday UTC.
+        let (time, next) = scheduled(users.next_from_time(date(2021, 7, 16), DaylogTime::new(12, 0)));
+        assert_eq!(SleepTime::Tomorrow(DaylogTime::new(1, 0)), time);
+        assert_eq!(vec!["weekdays"], next.iter().map(|u| u.username.as_str()).collect::<Vec<_>>());
+
+        // Once that's sent, the next would be Saturday local (01:00 Sunday UTC), so there's none.
+        assert!(matches!(users.next_from_time(date(2021, 7, 17), DaylogTime::new(1, 1)),
+            NextUsers::NoneScheduled));
+
+        // Nor on Sunday local (01:00 Monday UTC), even though it's a Monday in UTC.
+        assert!(matches!(users.next_from_time(date(2021, 7, 18), DaylogTime::new(1, 1)),
+            NextUsers::NoneScheduled));
+
+        // Monday local is back on.
+        let (time, _) = scheduled(users.next_from_time(date(2021, 7, 19), DaylogTime::new(1, 1)));
+        assert_eq!(SleepTime::Tomorrow(DaylogTime::new(1, 0)), time);
+
+        // Other users are still scheduled in the meantime.
+        let mut weekdays = user("weekdays", "America/Los_Angeles", 18, 0);
+        weekdays.days = Weekdays::parse("Mon-Fri").unwrap();
+        let users = Users::new(vec![weekdays, user("daily", "UTC", 12, 0)]);
+        let (time, next) = scheduled(users.next_from_time(date(2021, 7, 17), DaylogTime::new(1, 1)));
+        assert_eq!(SleepTime::Today(DaylogTime::new(12, 0)), time);
+        assert_eq!(vec!["daily"], next.iter().map(|u| u.username.as_str()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_weekly() {
+        let mut weekly = user("weekly", "Asia/Tokyo", 9, 0);
+        weekly.frequency = Frequency::Weekly(chrono::Weekday::Sun);
+        let users = Users::new(vec![weekly.clone()]);
+
+        // 09:00 in Tokyo is 00:00 UTC the same day. Saturday 2021-07-17 isn't the day...
+        assert!(matches!(users.next_from_time(date(2021, 7, 16), DaylogTime::new(0, 1)),
+            NextUsers::NoneScheduled));
+        // ...but Sunday is.
+        let (time, _) = scheduled(users.next_from_time(date(2021, 7, 17), DaylogTime::new(0, 1)));
+        assert_eq!(SleepTime::Tomorrow(DaylogTime::new(0, 0)), time);
+        // And then not again for a week.
+        assert!(matches!(users.next_from_time(date(2021, 7, 18), DaylogTime::new(0, 1)),
+            NextUsers::NoneScheduled));
+
+        let date = |d| NaiveDate::from_ymd_opt(2021, 7, d).unwrap();
+        assert_eq!(Some(date(18)), weekly.next_email_date(date(18)));
+        assert_eq!(Some(date(25)), weekly.next_email_date(date(19)));
+
+        // Combined with days that never include Sunday, it never happens.
+        weekly.days = Weekdays::parse("Mon-Fri").unwrap();
+        assert_eq!(None, weekly.next_email_date(date(19)));
+    }
+
+    #[test]
+    fn test_monthly() {
+        let mut monthly = user("monthly", "UTC", 18, 0);
+        monthly.frequency = Frequency::Monthly(31);
+        let users = Users::new(vec![monthly.clone()]);
+
+        let (time, _) = scheduled(users.next_from_time(date(2021, 1, 31), DaylogTime::zero()));
+        assert_eq!(SleepTime::Today(DaylogTime::new(18, 0)), time);
+        assert!(matches!(users.next_from_time(date(2021, 2, 1), DaylogTime::zero()),
+            NextUsers::NoneScheduled));
+        // February is short, so it's the 28th.
+        let (time, _) = scheduled(users.next_from_time(date(2021, 2, 28), DaylogTime::zero()));
+        assert_eq!(SleepTime::Today(DaylogTime::new(18, 0)), time);
+
+        let date = |m, d| NaiveDate::from_ymd_opt(2021, m, d).unwrap();
+        assert_eq!(Some(date(2, 28)), monthly.next_email_date(date(2, 1)));
+        assert_eq!(Some(date(3, 31)), monthly.next_email_date(date(3, 1)));
+        assert_eq!(Some(date(4, 30)), monthly.next_email_date(date(4, 1)));
     }
 }
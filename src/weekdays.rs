@@ -0,0 +1,126 @@
+use anyhow::{anyhow, bail};
+use chrono::Weekday;
+
+/// Letters for each day in the positional form, Monday first.
+const LETTERS: &[u8; 7] = b"MTWTFSS";
+
+/// The set of days of the week a user gets their daily email on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Weekdays {
+    bits: u8,
+}
+
+impl Weekdays {
+    pub fn all() -> Self {
+        Self { bits: 0x7f }
+    }
+
+    pub fn contains(self, day: Weekday) -> bool {
+        self.bits & bit(day) != 0
+    }
+
+    /// Parse a set of days, either as comma-separated day names and ranges of them, like
+    /// "Mon-Fri" or "mon,wed,sat-sun", or positionally like "MTWTF--", where each day is either its
+    /// letter or '-' (trailing days can be left off, so "MTWTF" is also weekdays only). An empty
+    /// string means every day.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(Self::all());
+        }
+
+        let days = if let Some(days) = parse_positional(s) {
+            days
+        } else {
+            let mut days = Self { bits: 0 };
+            for part in s.split(',') {
+                let part = part.trim();
+                match part.split_once('-') {
+                    Some((first, last)) => {
+                        let mut day = parse_day(first)?;
+                        let last = parse_day(last)?;
+                        days.bits |= bit(day);
+                        while day != last {
+                            day = day.succ();
+                            days.bits |= bit(day);
+                        }
+                    }
+                    None => days.bits |= bit(parse_day(part)?),
+                }
+            }
+            days
+        };
+
+        if days.bits == 0 {
+            bail!("no days given");
+        }
+        Ok(days)
+    }
+}
+
+impl std::fmt::Display for Weekdays {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, &letter) in LETTERS.iter().enumerate() {
+            let c = if self.bits & (1 << i) != 0 { letter as char } else { '-' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+fn bit(day: Weekday) -> u8 {
+    1 << day.num_days_from_monday()
+}
+
+fn parse_positional(s: &str) -> Option<Weekdays> {
+    if s.len() > LETTERS.len() {
+        return None;
+    }
+    let mut bits = 0;
+    for (i, c) in s.bytes().enumerate() {
+        if c.eq_ignore_ascii_case(&LETTERS[i]) {
+            bits |= 1 << i;
+        } else if c != b'-' {
+            return None;
+        }
+    }
+    Some(Weekdays { bits })
+}
+
+fn parse_day(s: &str) -> anyhow::Result<Weekday> {
+    s.trim().parse::<Weekday>().map_err(|_| anyhow!("{:?} is not a day of the week", s.trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let weekdays = "MTWTF--";
+        assert_eq!(weekdays, Weekdays::parse("Mon-Fri").unwrap().to_string());
+        assert_eq!(weekdays, Weekdays::parse("MTWTF").unwrap().to_string());
+        assert_eq!(weekdays, Weekdays::parse("mtwtf--").unwrap().to_string());
+        assert_eq!(weekdays, Weekdays::parse("monday - friday").unwrap().to_string());
+        assert_eq!("M-W-FS-", Weekdays::parse("Mon, Wed, Fri-Sat").unwrap().to_string());
+        assert_eq!("M---FSS", Weekdays::parse("Fri-Mon").unwrap().to_string());
+        assert_eq!("-----SS", Weekdays::parse("-----SS").unwrap().to_string());
+        assert_eq!(Weekdays::all(), Weekdays::parse("").unwrap());
+        assert_eq!(Weekdays::all(), Weekdays::parse("Mon-Sun").unwrap());
+
+        assert!(Weekdays::parse("-------").is_err());
+        assert!(Weekdays::parse("Mon-Funday").is_err());
+        assert!(Weekdays::parse("Mon,,Tue").is_err());
+        assert!(Weekdays::parse("SSMTWTF").is_err());
+        assert!(Weekdays::parse("MTWTFSSM").is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let days = Weekdays::parse("Mon-Fri").unwrap();
+        assert!(days.contains(Weekday::Mon));
+        assert!(days.contains(Weekday::Fri));
+        assert!(!days.contains(Weekday::Sat));
+        assert!(!days.contains(Weekday::Sun));
+    }
+}
@@ -0,0 +1,121 @@
+//! Run the `mail-transform` subcommand on sample replies from various mail clients, and check that
+//! the user's text is extracted from each one correctly.
+//!
+//! Each sample is a pair of files in `tests/replies/`: `<name>.eml` is the raw message, and
+//! `<name>.txt` is the expected output. Some also have `<name>-<mode>.txt`, the expected output
+//! with `--quote-handling <mode>`.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+fn check(name: &str) {
+    check_with(name, name, &[]);
+}
+
+fn check_quote_handling(name: &str, mode: &str) {
+    check_with(name, &format!("{}-{}", name, mode), &["--quote-handling", mode]);
+}
+
+fn check_with(name: &str, expected_name: &str, args: &[&str]) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("replies");
+    let expected = std::fs::read_to_string(dir.join(format!("{}.txt", expected_name))).unwrap();
+    assert_eq!(expected, mail_transform(name, args), "wrong output for {}", expected_name);
+}
+
+/// Run `mail-transform` with the given arguments on a sample reply, and return its output.
+fn mail_transform(name: &str, args: &[&str]) -> String {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("replies");
+    let raw = std::fs::read(dir.join(format!("{}.eml", name))).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_daylog-email"))
+        .arg(dir.join("config.yaml"))
+        .arg("mail-transform")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run daylog-email");
+    child.stdin.take().unwrap().write_all(&raw).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "mail-transform failed on {}", name);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn gmail() {
+    check("gmail");
+}
+
+#[test]
+fn gmail_wrapped() {
+    check("gmail-wrapped");
+}
+
+#[test]
+fn gmail_german() {
+    check("gmail-german");
+}
+
+#[test]
+fn gmail_french() {
+    check("gmail-french");
+}
+
+#[test]
+fn outlook() {
+    check("outlook");
+}
+
+#[test]
+fn outlook_web() {
+    check("outlook-web");
+}
+
+#[test]
+fn apple_mail() {
+    check("apple-mail");
+}
+
+#[test]
+fn apple_mail_unquoted() {
+    check("apple-mail-unquoted");
+}
+
+#[test]
+fn thunderbird() {
+    check("thunderbird");
+}
+
+#[test]
+fn thunderbird_flowed() {
+    check("thunderbird-flowed");
+}
+
+#[test]
+fn gmail_inline() {
+    check("gmail-inline");
+}
+
+#[test]
+fn gmail_inline_smart() {
+    check_quote_handling("gmail-inline", "smart");
+}
+
+#[test]
+fn html_only() {
+    check("html-only");
+}
+
+#[test]
+fn json() {
+    let json = mail_transform("gmail", &["--json"]);
+    let mail: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!("gmail-reply@client.example.com", mail["msgid"]);
+    assert_eq!(serde_json::json!(["daylog.1.AAAA.BBBB@daylog.example.com"]), mail["reply_to"]);
+    assert_eq!(serde_json::json!([]), mail["attachments"]);
+    assert_eq!(false, mail["from_html"]);
+    // Plus the newline println! adds.
+    assert_eq!(mail_transform("gmail", &["--pre-transform"]),
+        format!("{}\n", mail["body"].as_str().unwrap()));
+}